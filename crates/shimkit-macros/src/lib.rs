@@ -17,8 +17,14 @@ struct MainArgs {
     flavor: Option<TokioFlavor>,
     worker_threads: Option<u32>,
     start_paused: Option<bool>,
+    hardening: Option<syn::Path>,
 }
 
+/// Makes `main` the entry point of a shim, see `shimkit::run::run`.
+///
+/// The daemon is hardened with the `shimkit::run::Hardening` returned by the function
+/// given as `hardening`, e.g. `#[shimkit::main(hardening = my_hardening)]`,
+/// or with the default one.
 #[proc_macro_attribute]
 pub fn main(args: TokenStream, input: TokenStream) -> TokenStream {
     main_impl(args, input).unwrap_or_else(|err| err.into_compile_error().into())
@@ -50,14 +56,21 @@ fn main_impl(args: TokenStream, input: TokenStream) -> syn::Result<TokenStream>
         _ => quote! {},
     };
 
+    let run = |main: &syn::Ident| match &args.hardening {
+        Some(hardening) => quote! { #shimkit_path::run::run_with(#hardening(), #main) },
+        None => quote! { #shimkit_path::run::run(#main) },
+    };
+
     let tokens = if input.sig.asyncness.is_none() {
+        let run = run(&ident);
         quote! {
             fn main() -> impl ::std::process::Termination {
                 #input
-                #shimkit_path::run::run(#ident)
+                #run
             }
         }
     } else {
+        let run = run(&syn::Ident::from_string("inner_main")?);
         quote! {
             fn main() -> impl ::std::process::Termination {
                 fn inner_main(cmd: #shimkit_path::args::Arguments) -> impl ::std::process::Termination {
@@ -70,7 +83,7 @@ fn main_impl(args: TokenStream, input: TokenStream) -> syn::Result<TokenStream>
                         .unwrap()
                         .block_on(#ident(cmd))
                 }
-                #shimkit_path::run::run(inner_main)
+                #run
             }
         }
     };
@@ -1,13 +1,50 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::{env, fs};
+
+use heck::{ToKebabCase as _, ToUpperCamelCase as _};
 use trapeze_codegen::Config;
 
+const EVENTS_DIR: &str = "protos/github.com/containerd/containerd/api/events";
+
+// The event protos, the prefix of their message names, and the root of their topics.
+// See containerd's `pkg/events` for the topic of each event.
+const EVENTS: &[(&str, &str, &str)] = &[
+    ("container.proto", "Container", "/containers"),
+    ("content.proto", "Content", "/content"),
+    ("image.proto", "Image", "/images"),
+    ("namespace.proto", "Namespace", "/namespaces"),
+    ("sandbox.proto", "Sandbox", "/sandboxes"),
+    ("snapshot.proto", "Snapshot", "/snapshot"),
+    ("task.proto", "Task", "/tasks"),
+];
+
+// The services also served over gRPC, with the `grpc` feature, and the package they are
+// served as. containerd only talks gRPC to the v3 task API, which has the same messages as v2.
+#[cfg(feature = "grpc")]
+const GRPC_PACKAGES: &[(&str, &str)] = &[
+    ("containerd.task.v2", "containerd.task.v3"),
+    (
+        "containerd.runtime.sandbox.v1",
+        "containerd.runtime.sandbox.v1",
+    ),
+];
+
 fn main() {
-    Config::new()
+    let mut config = Config::new();
+
+    #[cfg(feature = "grpc")]
+    config.service_generator(Box::new(grpc::ServiceGenerator::new()));
+
+    config
         .enable_type_names()
         .include_file("mod.rs")
         .compile_protos(
             &[
                 "protos/gogoproto/gogo.proto",
                 "protos/github.com/containerd/containerd/protobuf/plugin/fieldpath.proto",
+                "protos/github.com/containerd/containerd/api/types/metrics.proto",
                 "protos/github.com/containerd/containerd/api/types/mount.proto",
                 "protos/github.com/containerd/containerd/api/types/task/task.proto",
                 "protos/github.com/containerd/cgroups/stats/v1/metrics.proto",
@@ -20,6 +57,7 @@ fn main() {
                 "protos/github.com/containerd/containerd/api/events/snapshot.proto",
                 "protos/github.com/containerd/containerd/api/events/task.proto",
                 "protos/github.com/containerd/containerd/runtime/v2/runc/options/oci.proto",
+                "protos/github.com/containerd/containerd/api/types/runtimeoptions/v1/api.proto",
                 "protos/github.com/containerd/containerd/api/runtime/task/v2/shim.proto",
                 "protos/github.com/containerd/containerd/api/services/ttrpc/events/v1/events.proto",
                 "protos/github.com/containerd/containerd/api/types/platform.proto",
@@ -29,8 +67,101 @@ fn main() {
             &["protos/"],
         )
         .expect("Failed to generate protos");
+
+    generate_events();
+}
+
+// Generates the `for_each_event!` macro, listing every top level message of the event
+// protos with its topic. Messages used as fields of other messages (e.g., `TaskIO`) are
+// not events, and are skipped.
+fn generate_events() {
+    let mut events = String::new();
+    for (file, prefix, root) in EVENTS {
+        let path = PathBuf::from(EVENTS_DIR).join(file);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let proto = fs::read_to_string(&path).expect("Failed to read event proto");
+
+        let mut messages = vec![];
+        let mut fields = HashSet::new();
+        for line in proto.lines() {
+            if let Some(name) = line.strip_prefix("message ") {
+                messages.push(name.trim_end_matches(['{', ' ']).to_string());
+            } else if let Some((ty, _)) = line.trim().split_once(' ') {
+                fields.insert(ty.to_string());
+            }
+        }
+
+        for message in messages {
+            if fields.contains(&message) {
+                continue;
+            }
+            let action = message
+                .strip_prefix(prefix)
+                .expect("Event name doesn't start with its prefix");
+            let ty = message.to_upper_camel_case();
+            let topic = format!("{root}/{}", action.to_kebab_case());
+            writeln!(
+                events,
+                "        $callback!($crate::events::{ty}, {topic:?});"
+            )
+            .unwrap();
+        }
+    }
+
+    let code = format!(
+        r#"/// Invokes `$callback!(Type, "/topic")` for every containerd event type.
+#[macro_export]
+macro_rules! for_each_event {{
+    ($callback:ident) => {{
+{events}    }};
+}}
+"#
+    );
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("events.rs"), code).expect("Failed to write events");
 }
 
-/*
+// Generates the tonic servers and clients of the `GRPC_PACKAGES` services, e.g.,
+// `task_server::Task` for `containerd.task.v3.Task`, next to the TTRPC services,
+// so that both use the same messages.
+#[cfg(feature = "grpc")]
+mod grpc {
+    use prost_build::Service;
+    use trapeze_codegen::TtrpcServiceGenerator;
+
+    pub struct ServiceGenerator {
+        tonic: Box<dyn prost_build::ServiceGenerator>,
+    }
 
-*/
+    impl ServiceGenerator {
+        pub fn new() -> Self {
+            let tonic = tonic_build::configure()
+                .build_transport(false)
+                .service_generator();
+            Self { tonic }
+        }
+    }
+
+    impl prost_build::ServiceGenerator for ServiceGenerator {
+        fn generate(&mut self, service: Service, buf: &mut String) {
+            let package = super::GRPC_PACKAGES
+                .iter()
+                .find(|(package, _)| *package == service.package);
+            if let Some((_, served)) = package {
+                let mut service = service.clone();
+                service.package = served.to_string();
+                self.tonic.generate(service, buf);
+            }
+            TtrpcServiceGenerator.generate(service, buf);
+        }
+
+        fn finalize(&mut self, buf: &mut String) {
+            self.tonic.finalize(buf);
+        }
+
+        fn finalize_package(&mut self, package: &str, buf: &mut String) {
+            self.tonic.finalize_package(package, buf);
+        }
+    }
+}
@@ -7,8 +7,11 @@ pub mod protos {
 pub mod events {
     pub use super::protos::containerd::events::*;
     pub use super::protos::containerd::services::events::ttrpc::v1::*;
+    pub use super::protos::containerd::services::images::v1::*;
 }
 
+include!(concat!(env!("OUT_DIR"), "/events.rs"));
+
 pub mod task {
     pub use super::protos::containerd::task::v2::*;
     pub use super::protos::containerd::types::*;
@@ -20,10 +23,22 @@ pub mod sandbox {
     pub use super::protos::containerd::types::*;
 }
 
+pub mod cgroups {
+    pub use super::protos::io::containerd::cgroups::v1::*;
+}
+
 pub mod cri {
     pub use super::protos::runtime::v1::*;
 }
 
+pub mod runc {
+    pub use super::protos::containerd::runc::v1::*;
+}
+
+pub mod runtimeoptions {
+    pub use super::protos::runtimeoptions::v1::*;
+}
+
 pub use prost_types as prost;
 pub use trapeze::{Code, Result, Status};
 
@@ -1,26 +1,31 @@
 use anyhow::Result;
 use shimkit::args::Arguments;
-use shimkit::utils::cri_sandbox_id;
-use tokio::signal::ctrl_c;
+use shimkit::bundle::Bundle;
+use shimkit::run::Hardening;
 
 mod server;
 use server::Server;
 
-#[shimkit::main(flavor = "current_thread")]
+// when the node runs out of memory, kill the logger before the other shims
+fn hardening() -> Hardening {
+    Hardening::default().with_oom_score_adj(Some(2))
+}
+
+#[shimkit::main(flavor = "current_thread", hardening = hardening)]
 async fn main(args: Arguments) -> Result<()> {
+    #[cfg(unix)]
+    shimkit::log::Logger::new(&args).init()?;
+    #[cfg(windows)]
     env_logger::init();
 
     let address = if args.is_interactive() {
         log::info!("Running logger interactively, a debug address will be used");
         args.socket_address_debug("debug")
     } else {
-        let id = cri_sandbox_id().unwrap_or_else(|| args.id.clone());
-        args.socket_address(id)
+        let bundle = Bundle::from_args(&args)?;
+        args.socket_address(bundle.sandbox_id().unwrap_or(&args.id))
     };
 
-    #[cfg(unix)]
-    let _ = tokio::fs::remove_file(&address).await;
-
     let _publisher = args.event_publisher().await?;
     let server = Server { _publisher };
     let server = args.serve(&address, server).await?;
@@ -28,14 +33,6 @@ async fn main(args: Arguments) -> Result<()> {
     log::info!("Listening on {}", address.display());
     log::info!("Press Ctrl+C to exit.");
 
-    let controller = server.controller();
-    tokio::spawn(async move {
-        ctrl_c().await.expect("Failed to wait for Ctrl+C.");
-        log::info!("");
-        log::info!("Shutting down server");
-        controller.shutdown();
-    });
-
     server.await.expect("Error shutting down server");
     log::info!("Server shutdown");
 
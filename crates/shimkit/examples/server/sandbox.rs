@@ -1,3 +1,4 @@
+use shimkit::options::unpack;
 use shimkit::types::cri::*;
 use shimkit::types::sandbox::*;
 use shimkit::types::{Result, Status};
@@ -6,15 +7,10 @@ use super::Server;
 
 impl Sandbox for Server {
     async fn create_sandbox(&self, mut r: CreateSandboxRequest) -> Result<CreateSandboxResponse> {
-        let options = r.options.take().and_then(|mut options| {
-            if !options.type_url.ends_with("runtime.v1.PodSandboxConfig") {
-                return None;
-            }
-            // Someone somewhere is not adding the required slash to the type_url
-            // Workaround it by setting it manually
-            options.type_url = "/runtime.v1.PodSandboxConfig".into();
-            options.to_msg::<PodSandboxConfig>().ok()
-        });
+        let options = r
+            .options
+            .take()
+            .and_then(|options| unpack::<PodSandboxConfig>(&options).ok().flatten());
         log::info!("{r:#?}");
         options.inspect(|opts| log::info!("{opts:#?}"));
         Err(Status::not_found(
@@ -34,6 +30,13 @@ impl Sandbox for Server {
         ))
     }
 
+    async fn sandbox_metrics(&self, r: SandboxMetricsRequest) -> Result<SandboxMetricsResponse> {
+        log::info!("{r:#?}");
+        Err(Status::not_found(
+            "/containerd.runtime.sandbox.v1.Sandbox/SandboxMetrics is not supported",
+        ))
+    }
+
     async fn sandbox_status(&self, r: SandboxStatusRequest) -> Result<SandboxStatusResponse> {
         log::info!("{r:#?}");
         Err(Status::not_found(
@@ -62,6 +65,13 @@ impl Sandbox for Server {
         ))
     }
 
+    async fn update_sandbox(&self, r: UpdateSandboxRequest) -> Result<UpdateSandboxResponse> {
+        log::info!("{r:#?}");
+        Err(Status::not_found(
+            "/containerd.runtime.sandbox.v1.Sandbox/UpdateSandbox is not supported",
+        ))
+    }
+
     async fn wait_sandbox(&self, r: WaitSandboxRequest) -> Result<WaitSandboxResponse> {
         log::info!("{r:#?}");
         Err(Status::not_found(
@@ -1,4 +1,5 @@
 use std::env::current_exe;
+#[cfg(not(target_os = "linux"))]
 use std::time::SystemTime;
 
 use anyhow::Context;
@@ -111,6 +112,9 @@ impl Task for Server {
 
     async fn cleanup(&self, r: CleanupRequest) -> trapeze::Result<DeleteResponse> {
         log::info!("{r:#?}");
+        #[cfg(target_os = "linux")]
+        return Ok(shimkit::cleanup::cleanup(&r.bundle).await?);
+        #[cfg(not(target_os = "linux"))]
         Ok(DeleteResponse {
             exit_status: 137,
             exited_at: Some(SystemTime::now().into()),
@@ -2,20 +2,29 @@ use std::collections::HashMap;
 use std::env::current_exe;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::hash::{DefaultHasher, Hash, Hasher as _};
 use std::io::{stdout, IsTerminal, Result as IoResult, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{bail, ensure, Context as _, Result};
 use os_str_bytes::OsStrBytesExt as _;
 use prost::Message;
+use sha2::{Digest as _, Sha256};
 use shimkit_types::task::KeyValue;
-use trapeze::{service, Client, Server, ServerHandle};
+#[cfg(windows)]
+use trapeze::Client;
+use trapeze::{service, Server};
 
-use crate::event::EventPublisher;
+use crate::bootstrap::{BootstrapFormat, BootstrapParams, Protocol};
+use crate::daemon::{Daemon, DaemonHandle, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::event::{EventPublisher, PublishBinary};
 use crate::fs::dev_null;
+use crate::run::LAUNCHER_ENV;
 use crate::stdio::Duplicate as _;
 use crate::sys::CONTAINERD_DEFAULT_ADDRESS;
+use crate::types::prost::Any;
 use crate::types::sandbox::Sandbox;
 use crate::types::task::{CleanupRequest, Task};
 use crate::utils::ToLossyString;
@@ -44,6 +53,15 @@ pub struct Arguments {
     pub(crate) bundle: PathBuf,
     pub(crate) shim_name: OsString,
     pub(crate) stdout: File,
+    pub(crate) bootstrap: BootstrapFormat,
+    pub(crate) protocol: Protocol,
+    pub(crate) shutdown_timeout: Duration,
+    // whether the daemon was spawned by the `start` launcher, which reads its stderr
+    pub(crate) launched: bool,
+    // the runtime options containerd passed to the `start` action on stdin
+    pub(crate) runtime_options: Option<Any>,
+    // the publishers created for the daemon, flushed on shutdown
+    pub(crate) publishers: Mutex<Vec<EventPublisher>>,
 }
 
 impl std::fmt::Debug for Arguments {
@@ -55,6 +73,9 @@ impl std::fmt::Debug for Arguments {
             .field("grpc_address", &self.grpc_address)
             .field("ttrpc_address", &self.ttrpc_address)
             .field("debug", &self.debug)
+            .field("bootstrap", &self.bootstrap)
+            .field("protocol", &self.protocol)
+            .field("shutdown_timeout", &self.shutdown_timeout)
             .finish()
     }
 }
@@ -73,39 +94,81 @@ impl Default for Arguments {
             bundle: Default::default(),
             shim_name: Default::default(),
             stdout: dev_null().unwrap(),
+            bootstrap: Default::default(),
+            protocol: Default::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            launched: false,
+            runtime_options: None,
+            publishers: Default::default(),
         }
     }
 }
 
 impl Arguments {
-    pub(crate) fn to_args_vec(&self, action: &'static OsStr) -> Vec<&OsStr> {
-        let mut args: Vec<&OsStr> = vec![
-            "-id".as_ref(),
-            self.id.as_ref(),
-            "-namespace".as_ref(),
-            self.namespace.as_ref(),
-            "-address".as_ref(),
-            self.grpc_address.as_ref(),
-            "-publish-binary".as_ref(),
-            self.publish_binary.as_ref(),
-        ];
+    /// Builds a command running `program` with the same arguments, and `action`,
+    /// such that `parse_from` in the new process yields the same `Arguments`.
+    pub(crate) fn to_command(&self, program: impl AsRef<OsStr>, action: &str) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.arg("-id").arg(&self.id);
+        cmd.arg("-namespace").arg(&self.namespace);
+        cmd.arg("-address").arg(&self.grpc_address);
+        cmd.arg("-publish-binary").arg(&self.publish_binary);
+        if !self.bundle.as_os_str().is_empty() {
+            cmd.arg("-bundle").arg(&self.bundle);
+        }
         if self.debug {
-            args.push("-debug".as_ref());
+            cmd.arg("-debug");
         }
-        args.push(action.as_ref());
-        args.extend(self.rest.iter().map(AsRef::<OsStr>::as_ref));
-        args
+        cmd.arg(action).args(&self.rest);
+        // the ttrpc address has no flag, and isn't always derived from the grpc one
+        cmd.env("TTRPC_ADDRESS", &self.ttrpc_address);
+        cmd
     }
 
     pub fn is_interactive(&self) -> bool {
         self.stdout.is_terminal()
     }
 
+    /// Returns the runtime options of the shim, which containerd 1.7 and newer passes
+    /// to the `start` action, e.g., `containerd.runc.v1.Options`.
+    /// These are only available to the daemon, see `options::RuntimeOptions::from_any`.
+    pub fn runtime_options(&self) -> Option<&Any> {
+        self.runtime_options.as_ref()
+    }
+
+    /// Sets the format used to report the shim address to containerd.
+    /// Defaults to `BootstrapFormat::Address`, which all containerd versions understand.
+    pub fn with_bootstrap_format(mut self, format: BootstrapFormat) -> Self {
+        self.bootstrap = format;
+        self
+    }
+
+    /// Sets the protocol the shim API is served over.
+    /// Defaults to `Protocol::Ttrpc`. Serving over `Protocol::Grpc` requires the `grpc` feature,
+    /// and serves the task API as `containerd.task.v3.Task`, which containerd 2.0 and newer
+    /// accepts over gRPC. The address is then always reported in the `BootstrapFormat::Json`
+    /// format with version 3, as the plain address implies TTRPC and the v2 task API.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the time given to the daemon to finish the in-flight requests
+    /// and flush its events when shutting down.
+    /// Defaults to `DEFAULT_SHUTDOWN_TIMEOUT`.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Runs the shim action with `server`.
+    /// For the `daemon` action, `server` is served on `address` until the daemon shuts down,
+    /// see `DaemonHandle`.
     pub async fn serve(
         self,
         address: impl AsRef<Path>,
         server: impl Sandbox + Task,
-    ) -> Result<ServerHandle> {
+    ) -> Result<DaemonHandle> {
         match self.action.as_str() {
             "version" => {
                 let mut stdout = self.stdout;
@@ -114,7 +177,7 @@ impl Arguments {
                 for KeyValue { key, value } in result.info {
                     writeln!(stdout, "  {key}: {value}")?;
                 }
-                Ok(ServerHandle::new())
+                Ok(DaemonHandle::done())
             }
             "delete" => {
                 let mut stdout = self.stdout;
@@ -123,29 +186,100 @@ impl Arguments {
                 };
                 let result = server.cleanup(req).await?.encode_to_vec();
                 stdout.write_all(&result)?;
-                Ok(ServerHandle::new())
+                Ok(DaemonHandle::done())
             }
             "daemon" => {
-                let address = address.as_ref().display().to_string();
+                let address_path = address.as_ref();
+                let address = address_path.display().to_string();
 
                 #[cfg(unix)]
                 let address = format!("unix://{address}");
 
+                let protocol = self.protocol;
+                ensure!(
+                    protocol == Protocol::Ttrpc || cfg!(all(unix, feature = "grpc")),
+                    "Serving over gRPC requires the `grpc` feature on unix"
+                );
+                let format = match protocol {
+                    Protocol::Ttrpc => self.bootstrap,
+                    Protocol::Grpc => BootstrapFormat::Json,
+                };
+
+                // report the address once the daemon is ready to serve on it,
+                // or if a live server is already serving on it
+                let params = BootstrapParams::new(&address, protocol);
                 let mut stdout = self.stdout;
-                writeln!(stdout, "{}", address)?;
+                let launched = self.launched;
+                let mut bootstrap = move || -> Result<()> {
+                    // hand stderr over from the launcher to the log, as the launcher exits
+                    // once it has read the address
+                    if launched {
+                        crate::run::open_log()?.duplicate_to_stderr()?;
+                    }
+                    writeln!(stdout, "{}", params.encode(format))?;
+                    Ok(())
+                };
 
-                if Client::connect(&address).await.is_ok() {
-                    // a server is already running on that address
-                    return Ok(ServerHandle::new());
-                }
+                let request = Arc::default();
+                let server = Daemon::new(server, Arc::clone(&request));
+                let publishers = self
+                    .publishers
+                    .into_inner()
+                    .unwrap_or_else(|err| err.into_inner());
 
-                let handle = Server::new()
-                    .register(service!(server : Sandbox + Task))
-                    .bind(&address)
-                    .await
-                    .context("Error binding listener")?;
+                #[cfg(unix)]
+                {
+                    let Some(listener) = crate::socket::ShimListener::bind(address_path)
+                        .context("Error binding listener")?
+                    else {
+                        // a live server is already running on that address
+                        bootstrap()?;
+                        return Ok(DaemonHandle::done());
+                    };
+                    bootstrap()?;
+
+                    #[cfg(feature = "grpc")]
+                    if protocol == Protocol::Grpc {
+                        let handle = crate::grpc::serve(listener, server);
+                        return Ok(DaemonHandle::spawn(
+                            handle,
+                            request,
+                            publishers,
+                            self.shutdown_timeout,
+                        ));
+                    }
+
+                    let handle = Server::new()
+                        .register(service!(server : Sandbox + Task))
+                        .start(listener);
+                    Ok(DaemonHandle::spawn(
+                        handle,
+                        request,
+                        publishers,
+                        self.shutdown_timeout,
+                    ))
+                }
 
-                Ok(handle)
+                #[cfg(windows)]
+                {
+                    if Client::connect(&address).await.is_ok() {
+                        // a server is already running on that address
+                        bootstrap()?;
+                        return Ok(DaemonHandle::done());
+                    }
+                    let handle = Server::new()
+                        .register(service!(server : Sandbox + Task))
+                        .bind(&address)
+                        .await
+                        .context("Error binding listener")?;
+                    bootstrap()?;
+                    Ok(DaemonHandle::spawn(
+                        handle,
+                        request,
+                        publishers,
+                        self.shutdown_timeout,
+                    ))
+                }
             }
             action => bail!("Unsupported action `{action}`"),
         }
@@ -176,15 +310,33 @@ fn socket_address(containerd_socket: impl AsRef<Path>, id: impl AsRef<OsStr>) ->
         .with_extension(extension)
 }
 
+// the shortest digest used in socket names, 128 bits
+#[cfg(unix)]
+const MIN_DIGEST_LEN: usize = 32;
+
 impl Arguments {
-    pub fn socket_address(&self, id: impl Hash) -> PathBuf {
-        let id = {
-            let mut hasher = DefaultHasher::new();
-            (&self.namespace, id).hash(&mut hasher);
-            hasher.finish()
-        };
+    /// Returns the socket address for the shim serving `id` in the current namespace.
+    /// The name is derived from the SHA-256 of the namespace and `id`, so that every build
+    /// of the shim computes the same address, shortening the digest if the path
+    /// would not fit in a unix socket address.
+    pub fn socket_address(&self, id: impl AsRef<str>) -> PathBuf {
+        let digest = Sha256::new()
+            .chain_update(&self.namespace)
+            .chain_update("/")
+            .chain_update(id.as_ref())
+            .finalize();
+        let digest = format!("{digest:x}");
+
+        #[cfg(unix)]
+        {
+            let len = self.socket_address_debug(&digest).as_os_str().len();
+            let excess = len.saturating_sub(crate::socket::SUN_PATH_MAX);
+            let keep = digest.len().saturating_sub(excess).max(MIN_DIGEST_LEN);
+            self.socket_address_debug(&digest[..keep])
+        }
 
-        self.socket_address_debug(format!("{id:02x}"))
+        #[cfg(not(unix))]
+        self.socket_address_debug(digest)
     }
 
     pub fn socket_address_debug(&self, stem: impl AsRef<OsStr>) -> PathBuf {
@@ -200,11 +352,19 @@ impl Arguments {
                 let address = &self.ttrpc_address;
                 #[cfg(unix)]
                 let address = format!("unix://{address}");
-                EventPublisher::connect(address).await?
+                let fallback = PublishBinary {
+                    binary: self.publish_binary.clone(),
+                    address: self.grpc_address.clone(),
+                };
+                EventPublisher::connect(address, Some(fallback))
             }
             _ => EventPublisher::null(),
         };
         let publisher = publisher.with_namespace(&self.namespace);
+        self.publishers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(publisher.clone());
         Ok(publisher)
     }
 }
@@ -288,6 +448,12 @@ impl Arguments {
             bundle,
             shim_name,
             stdout,
+            bootstrap: Default::default(),
+            protocol: Default::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            launched: vars.contains_key(LAUNCHER_ENV),
+            runtime_options: None,
+            publishers: Default::default(),
         };
 
         match args.action.as_str() {
@@ -299,6 +465,7 @@ impl Arguments {
 
 #[cfg(test)]
 mod tests {
+    use std::io::{Read as _, Seek as _, SeekFrom};
     use std::path::Path;
 
     use super::*;
@@ -346,6 +513,46 @@ mod tests {
         assert_eq!(args.namespace, "default");
     }
 
+    #[test]
+    fn round_trip_to_daemon() {
+        let args = [
+            "-debug",
+            "-id",
+            "123",
+            "-namespace",
+            "k8s.io",
+            "-publish-binary",
+            "/path/to/binary",
+            "-bundle",
+            "/path/to/bundle",
+            "-address",
+            "/path/to/c8d.sock",
+            "start",
+            "abc",
+        ];
+        let envs = [("TTRPC_ADDRESS", "/other/c8d.sock.ttrpc")];
+        let args = Arguments::parse_from(args, envs).unwrap();
+
+        let cmd = args.to_command("shim", "daemon");
+        let lossy = |s: &OsStr| s.to_string_lossy().into_owned();
+        let daemon = Arguments::parse_from(
+            cmd.get_args().map(lossy),
+            cmd.get_envs().map(|(k, v)| (lossy(k), lossy(v.unwrap()))),
+        )
+        .unwrap();
+
+        assert_eq!(daemon.action, "daemon");
+        assert_eq!(daemon.id, args.id);
+        assert_eq!(daemon.namespace, args.namespace);
+        assert_eq!(daemon.publish_binary, args.publish_binary);
+        assert_eq!(daemon.grpc_address, args.grpc_address);
+        assert_eq!(daemon.ttrpc_address, "/other/c8d.sock.ttrpc");
+        assert_eq!(daemon.bundle, args.bundle);
+        assert_eq!(daemon.debug, args.debug);
+        assert_eq!(daemon.rest, args.rest);
+        assert!(!daemon.launched);
+    }
+
     #[test]
     fn parse_version() {
         let args = ["-v"];
@@ -384,4 +591,95 @@ mod tests {
 
         assert_eq!(socket, PathBuf::from("/path/to/containerd-shim-logger-123"));
     }
+
+    #[test]
+    fn socket_address_stable() {
+        let args = Arguments {
+            namespace: "default".into(),
+            ttrpc_address: "/run/c8d.sock".into(),
+            shim_name: "logger".into(),
+            ..Default::default()
+        };
+
+        let socket = args.socket_address("123");
+
+        assert_eq!(
+            socket,
+            PathBuf::from(
+                "/run/containerd-shim-logger-\
+                 3275b39666d6109282265074df997f160afca6aa66495d2e77e9345f63e020c1.sock"
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_address_too_long() {
+        let args = Arguments {
+            namespace: "default".into(),
+            ttrpc_address: "/run/containerd/containerd.sock.ttrpc".into(),
+            shim_name: "logger".into(),
+            ..Default::default()
+        };
+
+        let socket = args.socket_address("123");
+
+        assert_eq!(socket.as_os_str().len(), crate::socket::SUN_PATH_MAX);
+        assert_eq!(
+            socket,
+            PathBuf::from(
+                "/run/containerd/containerd-shim-logger-\
+                 3275b39666d6109282265074df997f160afca6aa66495d2e77e9345f6.sock.ttrpc"
+            )
+        );
+    }
+
+    struct NullServer;
+    impl Task for NullServer {}
+    impl Sandbox for NullServer {}
+
+    #[cfg(unix)]
+    async fn serve_daemon(format: BootstrapFormat) -> (String, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+        let mut stdout = tempfile::tempfile().unwrap();
+
+        let args = Arguments {
+            action: "daemon".into(),
+            stdout: stdout.try_clone().unwrap(),
+            ..Default::default()
+        };
+        let handle = args
+            .with_bootstrap_format(format)
+            .serve(&socket, NullServer)
+            .await
+            .unwrap();
+        assert!(socket.exists());
+        handle.shutdown();
+        handle.await.unwrap();
+        assert!(!socket.exists());
+
+        let mut output = String::new();
+        stdout.seek(SeekFrom::Start(0)).unwrap();
+        stdout.read_to_string(&mut output).unwrap();
+        (output, socket)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_bootstrap_address() {
+        let (output, socket) = serve_daemon(BootstrapFormat::Address).await;
+        assert_eq!(output, format!("unix://{}\n", socket.display()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_bootstrap_json() {
+        let (output, socket) = serve_daemon(BootstrapFormat::Json).await;
+        let expected = format!(
+            r#"{{"version":2,"address":"unix://{}","protocol":"ttrpc"}}"#,
+            socket.display()
+        );
+        assert_eq!(output, format!("{expected}\n"));
+    }
 }
@@ -0,0 +1,100 @@
+use serde::Serialize;
+
+/// Transport protocol the shim API is served over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Ttrpc,
+    Grpc,
+}
+
+/// Format used to report the shim address back to containerd from the `start` action.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BootstrapFormat {
+    /// Plain address, e.g., `unix:///run/containerd/s/abc`.
+    /// This is the legacy v2 handshake, understood by every containerd version.
+    #[default]
+    Address,
+
+    /// JSON encoded `BootstrapParams` document.
+    /// Requires containerd 2.0 or newer.
+    Json,
+}
+
+/// Bootstrap parameters printed by the shim on stdout.
+/// See containerd's `core/runtime/v2/bootstrap.go`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BootstrapParams {
+    // the version of the shim API
+    pub version: u32,
+
+    // the address containerd should use to connect to the shim
+    pub address: String,
+
+    // the protocol the shim API is served over
+    pub protocol: Protocol,
+}
+
+/// Version of the task API served by shimkit shims over TTRPC.
+pub const TASK_API_VERSION: u32 = 2;
+
+/// Version of the task API served by shimkit shims over gRPC, as containerd only
+/// accepts that protocol for `containerd.task.v3.Task`.
+pub const GRPC_TASK_API_VERSION: u32 = 3;
+
+impl BootstrapParams {
+    pub fn new(address: impl Into<String>, protocol: Protocol) -> Self {
+        let version = match protocol {
+            Protocol::Ttrpc => TASK_API_VERSION,
+            Protocol::Grpc => GRPC_TASK_API_VERSION,
+        };
+        Self {
+            version,
+            address: address.into(),
+            protocol,
+        }
+    }
+
+    /// Encodes the parameters in the requested format.
+    /// The protocol is ignored in the `Address` format, as it implies TTRPC.
+    pub fn encode(&self, format: BootstrapFormat) -> String {
+        match format {
+            BootstrapFormat::Address => self.address.clone(),
+            // serializing a struct of strings and integers can't fail
+            BootstrapFormat::Json => serde_json::to_string(self).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_address() {
+        let params = BootstrapParams::new("unix:///path/to/shim.sock", Protocol::Ttrpc);
+        let encoded = params.encode(BootstrapFormat::Address);
+        assert_eq!(encoded, "unix:///path/to/shim.sock");
+    }
+
+    #[test]
+    fn encode_json() {
+        let params = BootstrapParams::new("unix:///path/to/shim.sock", Protocol::Ttrpc);
+        let encoded = params.encode(BootstrapFormat::Json);
+        assert_eq!(
+            encoded,
+            r#"{"version":2,"address":"unix:///path/to/shim.sock","protocol":"ttrpc"}"#
+        );
+    }
+
+    #[test]
+    fn encode_json_grpc() {
+        let params = BootstrapParams::new(r"\\.\pipe\shim", Protocol::Grpc);
+        let encoded = params.encode(BootstrapFormat::Json);
+        assert_eq!(
+            encoded,
+            r#"{"version":3,"address":"\\\\.\\pipe\\shim","protocol":"grpc"}"#
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use oci_spec::runtime::Spec;
+use oci_spec::OciSpecError;
+
+use crate::args::Arguments;
+use crate::types::task::CreateTaskRequest;
+
+/// The OCI runtime spec of the container, in the bundle.
+pub const CONFIG_FILE: &str = "config.json";
+
+/// The fifo in the bundle where containerd reads the logs of the shim.
+pub const LOG_FILE: &str = "log";
+
+// the annotations used to group the containers of a pod in the same shim
+const GROUP_LABELS: [&str; 2] = [
+    "io.kubernetes.cri.sandbox-id",
+    "io.containerd.runc.v2.group",
+];
+
+/// An OCI bundle, as prepared by containerd for a container.
+///
+/// The bundle is the directory containing the `config.json` runtime spec, and is also
+/// the working directory of the shim, where it keeps its runtime state.
+#[derive(Clone, Debug)]
+pub struct Bundle {
+    path: PathBuf,
+    spec: Spec,
+    annotations: HashMap<String, String>,
+}
+
+impl Bundle {
+    /// Opens the bundle in `path`, loading its runtime spec.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if path.as_os_str().is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty bundle path"));
+        }
+
+        let config = path.join(CONFIG_FILE);
+        let spec = Spec::load(&config).map_err(|err| {
+            let kind = match &err {
+                OciSpecError::Io(err) => err.kind(),
+                _ => ErrorKind::InvalidData,
+            };
+            Error::new(kind, format!("failed to load {config:?}: {err}"))
+        })?;
+        let annotations = spec.annotations().clone().unwrap_or_default();
+
+        Ok(Self {
+            path,
+            spec,
+            annotations,
+        })
+    }
+
+    /// Opens the bundle of the shim started with `args`, i.e., the `-bundle` path
+    /// for the `delete` action, or the working directory otherwise.
+    pub fn from_args(args: &Arguments) -> Result<Self> {
+        match args.bundle.as_os_str().is_empty() {
+            true => Self::open(std::env::current_dir()?),
+            false => Self::open(&args.bundle),
+        }
+    }
+
+    /// Opens the bundle of the container created with `req`.
+    pub fn from_request(req: &CreateTaskRequest) -> Result<Self> {
+        Self::open(&req.bundle)
+    }
+
+    /// Returns the path of the bundle.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the runtime spec of the container.
+    pub fn spec(&self) -> &Spec {
+        &self.spec
+    }
+
+    /// Returns the root filesystem of the container, from `root.path` in the spec
+    /// relative to the bundle, or `rootfs` if the spec doesn't set it.
+    pub fn rootfs(&self) -> PathBuf {
+        match self.spec.root() {
+            Some(root) => self.path.join(root.path()),
+            None => self.path.join("rootfs"),
+        }
+    }
+
+    /// Returns the annotations of the spec.
+    pub fn annotations(&self) -> &HashMap<String, String> {
+        &self.annotations
+    }
+
+    /// Returns the id of the sandbox the container belongs to, e.g., its pod,
+    /// which can be used to serve all the containers of a sandbox from the same shim.
+    pub fn sandbox_id(&self) -> Option<&str> {
+        GROUP_LABELS
+            .iter()
+            .find_map(|label| self.annotations.get(*label))
+            .map(String::as_str)
+    }
+
+    /// Returns the path of the `log` fifo, see `log::Logger`.
+    pub fn log_path(&self) -> PathBuf {
+        self.path.join(LOG_FILE)
+    }
+
+    /// Returns the directory where the shim keeps the runtime state of the container,
+    /// e.g., the files of `cleanup`, `mount` and `state`.
+    /// For containerd this is the bundle itself.
+    pub fn state_dir(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::RootBuilder;
+
+    use super::*;
+
+    fn bundle(spec: &Spec) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        spec.save(dir.path().join(CONFIG_FILE)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn open_bundle() {
+        let mut spec = Spec::default();
+        let root = RootBuilder::default().path("rootfs").build().unwrap();
+        spec.set_root(Some(root));
+        spec.set_annotations(Some(HashMap::from([(
+            "io.kubernetes.cri.sandbox-id".to_string(),
+            "pod".to_string(),
+        )])));
+        let dir = bundle(&spec);
+
+        let req = CreateTaskRequest {
+            bundle: dir.path().to_str().unwrap().into(),
+            ..Default::default()
+        };
+        let bundle = Bundle::from_request(&req).unwrap();
+        assert_eq!(bundle.path(), dir.path());
+        assert_eq!(bundle.rootfs(), dir.path().join("rootfs"));
+        assert_eq!(bundle.log_path(), dir.path().join("log"));
+        assert_eq!(bundle.state_dir(), dir.path());
+        assert_eq!(bundle.sandbox_id(), Some("pod"));
+        assert_eq!(bundle.annotations().len(), 1);
+    }
+
+    #[test]
+    fn open_from_args() {
+        let mut spec = Spec::default();
+        let root = RootBuilder::default().path("/abs/rootfs").build().unwrap();
+        spec.set_root(Some(root));
+        spec.set_annotations(None);
+        let dir = bundle(&spec);
+
+        let args = Arguments {
+            bundle: dir.path().into(),
+            ..Default::default()
+        };
+        let bundle = Bundle::from_args(&args).unwrap();
+        assert_eq!(bundle.rootfs(), Path::new("/abs/rootfs"));
+        assert_eq!(bundle.sandbox_id(), None);
+        assert!(bundle.annotations().is_empty());
+    }
+
+    #[test]
+    fn open_errors() {
+        let err = Bundle::open("").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let dir = tempfile::tempdir().unwrap();
+        let err = Bundle::open(dir.path()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        std::fs::write(dir.path().join(CONFIG_FILE), "{").unwrap();
+        let err = Bundle::open(dir.path()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,805 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use oci_spec::runtime::Spec;
+
+use crate::types::cgroups::{
+    BlkIoEntry, BlkIoStat, CpuStat, CpuUsage, HugetlbStat, MemoryEntry, MemoryOomControl,
+    MemoryStat, Metrics, PidsStat, Throttle,
+};
+use crate::types::prost::Any;
+
+/// Default mount point of the cgroup filesystem.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// cgroup v1 reports cpu times in USER_HZ, which is 100 on every architecture linux supports
+const NANOS_PER_TICK: u64 = 1_000_000_000 / 100;
+
+/// Version of the cgroup hierarchy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hierarchy {
+    /// Legacy hierarchy, with one mount per controller.
+    V1,
+
+    /// Unified hierarchy, with all the controllers in a single mount.
+    V2,
+}
+
+impl Hierarchy {
+    /// Detects the hierarchy mounted at `root`.
+    pub fn detect(root: impl AsRef<Path>) -> Self {
+        if root.as_ref().join("cgroup.controllers").exists() {
+            Hierarchy::V2
+        } else {
+            Hierarchy::V1
+        }
+    }
+}
+
+/// A cgroup of a container, used to collect its resource usage.
+#[derive(Clone, Debug)]
+pub struct Cgroup {
+    root: PathBuf,
+    path: PathBuf,
+    hierarchy: Hierarchy,
+}
+
+impl Cgroup {
+    /// Opens the cgroup at `path`, relative to the cgroup filesystem mounted at `/sys/fs/cgroup`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::with_root(CGROUP_ROOT, path)
+    }
+
+    /// Opens the cgroup at `path`, relative to the cgroup filesystem mounted at `root`.
+    pub fn with_root(root: impl Into<PathBuf>, path: impl AsRef<Path>) -> Self {
+        let root = root.into();
+        let path = path.as_ref();
+        let path = path.strip_prefix("/").unwrap_or(path).to_owned();
+        let hierarchy = Hierarchy::detect(&root);
+        Self {
+            root,
+            path,
+            hierarchy,
+        }
+    }
+
+    /// Opens the cgroup in the `linux.cgroupsPath` of an OCI spec.
+    /// Systemd style paths (e.g., `system.slice:docker:abc`) are converted to their cgroupfs path.
+    pub fn from_spec(spec: &Spec) -> Option<Self> {
+        let path = spec.linux().as_ref()?.cgroups_path().as_ref()?;
+        Some(Self::new(cgroupfs_path(&path.to_string_lossy())))
+    }
+
+    pub fn hierarchy(&self) -> Hierarchy {
+        self.hierarchy
+    }
+
+    /// The directory of a controller for this cgroup.
+    /// In the unified hierarchy, every controller shares the same directory.
+    pub fn dir(&self, controller: &str) -> PathBuf {
+        match self.hierarchy {
+            Hierarchy::V1 => self.root.join(controller).join(&self.path),
+            Hierarchy::V2 => self.root.join(&self.path),
+        }
+    }
+
+    /// Collects the resource usage of the cgroup.
+    pub fn metrics(&self) -> Result<Metrics> {
+        match self.hierarchy {
+            Hierarchy::V1 => self.metrics_v1(),
+            Hierarchy::V2 => self.metrics_v2(),
+        }
+    }
+
+    /// Collects the resource usage of the cgroup, packed as expected in a `StatsResponse`.
+    pub fn stats(&self) -> Result<Any> {
+        Ok(Any::from_msg(&self.metrics()?)?)
+    }
+
+    /// Moves the process `pid` into the cgroup.
+    /// In the legacy hierarchy, the process is moved in every controller the cgroup exists in.
+    pub fn add_process(&self, pid: u32) -> Result<()> {
+        let pid = pid.to_string();
+        match self.hierarchy {
+            Hierarchy::V2 => {
+                let dir = self.dir("");
+                if !dir.is_dir() {
+                    return Err(not_found(&self.path));
+                }
+                fs::write(dir.join("cgroup.procs"), pid)
+            }
+            Hierarchy::V1 => {
+                let mut found = false;
+                for entry in fs::read_dir(&self.root)? {
+                    let entry = entry?;
+                    // skip the links to co-mounted controllers, e.g., `cpu` to `cpu,cpuacct`
+                    if !entry.file_type()?.is_dir() {
+                        continue;
+                    }
+                    let dir = entry.path().join(&self.path);
+                    if dir.is_dir() {
+                        fs::write(dir.join("cgroup.procs"), &pid)?;
+                        found = true;
+                    }
+                }
+                match found {
+                    true => Ok(()),
+                    false => Err(not_found(&self.path)),
+                }
+            }
+        }
+    }
+
+    /// Lists the processes in the cgroup.
+    /// In the legacy hierarchy, these are the processes in any controller the cgroup exists in.
+    pub fn procs(&self) -> Result<Vec<u32>> {
+        let dirs = match self.hierarchy {
+            Hierarchy::V2 => vec![self.dir("")],
+            Hierarchy::V1 => {
+                let mut dirs = vec![];
+                for entry in fs::read_dir(&self.root)? {
+                    let entry = entry?;
+                    // skip the links to co-mounted controllers, e.g., `cpu` to `cpu,cpuacct`
+                    if entry.file_type()?.is_dir() {
+                        dirs.push(entry.path().join(&self.path));
+                    }
+                }
+                dirs
+            }
+        };
+
+        let mut found = false;
+        let mut procs = vec![];
+        for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+            found = true;
+            let content = read(dir.join("cgroup.procs"))?.unwrap_or_default();
+            procs.extend(
+                content
+                    .lines()
+                    .filter_map(|pid| pid.trim().parse::<u32>().ok()),
+            );
+        }
+        if !found {
+            return Err(not_found(&self.path));
+        }
+        procs.sort_unstable();
+        procs.dedup();
+        Ok(procs)
+    }
+
+    fn metrics_v1(&self) -> Result<Metrics> {
+        let controllers = ["cpuacct", "memory", "pids", "blkio"];
+        if !controllers.iter().any(|c| self.dir(c).is_dir()) {
+            return Err(not_found(&self.path));
+        }
+
+        let cpuacct = self.dir("cpuacct");
+        let stat = read_keyed(cpuacct.join("cpuacct.stat"))?;
+        let usage = CpuUsage {
+            total: read_u64(cpuacct.join("cpuacct.usage"))?,
+            kernel: stat.get("system").copied().unwrap_or_default() * NANOS_PER_TICK,
+            user: stat.get("user").copied().unwrap_or_default() * NANOS_PER_TICK,
+            per_cpu: read(cpuacct.join("cpuacct.usage_percpu"))?
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(parse_u64)
+                .collect(),
+        };
+        let stat = read_keyed(self.dir("cpu").join("cpu.stat"))?;
+        let throttling = Throttle {
+            periods: stat.get("nr_periods").copied().unwrap_or_default(),
+            throttled_periods: stat.get("nr_throttled").copied().unwrap_or_default(),
+            throttled_time: stat.get("throttled_time").copied().unwrap_or_default(),
+        };
+
+        let memory = self.dir("memory");
+        let stat = read_keyed(memory.join("memory.stat"))?;
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        let entry = |prefix: &str| -> Result<Option<MemoryEntry>> {
+            let usage = memory.join(format!("{prefix}.usage_in_bytes"));
+            if !usage.exists() {
+                return Ok(None);
+            }
+            Ok(Some(MemoryEntry {
+                limit: read_u64(memory.join(format!("{prefix}.limit_in_bytes")))?,
+                usage: read_u64(usage)?,
+                max: read_u64(memory.join(format!("{prefix}.max_usage_in_bytes")))?,
+                failcnt: read_u64(memory.join(format!("{prefix}.failcnt")))?,
+            }))
+        };
+        let memory_stat = MemoryStat {
+            cache: get("cache"),
+            rss: get("rss"),
+            rss_huge: get("rss_huge"),
+            mapped_file: get("mapped_file"),
+            dirty: get("dirty"),
+            writeback: get("writeback"),
+            pg_pg_in: get("pgpgin"),
+            pg_pg_out: get("pgpgout"),
+            pg_fault: get("pgfault"),
+            pg_maj_fault: get("pgmajfault"),
+            inactive_anon: get("inactive_anon"),
+            active_anon: get("active_anon"),
+            inactive_file: get("inactive_file"),
+            active_file: get("active_file"),
+            unevictable: get("unevictable"),
+            hierarchical_memory_limit: get("hierarchical_memory_limit"),
+            hierarchical_swap_limit: get("hierarchical_memsw_limit"),
+            total_cache: get("total_cache"),
+            total_rss: get("total_rss"),
+            total_rss_huge: get("total_rss_huge"),
+            total_mapped_file: get("total_mapped_file"),
+            total_dirty: get("total_dirty"),
+            total_writeback: get("total_writeback"),
+            total_pg_pg_in: get("total_pgpgin"),
+            total_pg_pg_out: get("total_pgpgout"),
+            total_pg_fault: get("total_pgfault"),
+            total_pg_maj_fault: get("total_pgmajfault"),
+            total_inactive_anon: get("total_inactive_anon"),
+            total_active_anon: get("total_active_anon"),
+            total_inactive_file: get("total_inactive_file"),
+            total_active_file: get("total_active_file"),
+            total_unevictable: get("total_unevictable"),
+            usage: entry("memory")?,
+            swap: entry("memory.memsw")?,
+            kernel: entry("memory.kmem")?,
+            kernel_tcp: entry("memory.kmem.tcp")?,
+        };
+        let oom_control = read_keyed(memory.join("memory.oom_control"))?;
+        let memory_oom_control = MemoryOomControl {
+            oom_kill_disable: oom_control
+                .get("oom_kill_disable")
+                .copied()
+                .unwrap_or_default(),
+            under_oom: oom_control.get("under_oom").copied().unwrap_or_default(),
+            oom_kill: oom_control.get("oom_kill").copied().unwrap_or_default(),
+        };
+
+        let pids = self.dir("pids");
+        let pids = PidsStat {
+            current: read_u64(pids.join("pids.current"))?,
+            limit: read_u64(pids.join("pids.max"))?,
+        };
+
+        let blkio = self.dir("blkio");
+        let entries = |name: &str| read_blkio(blkio.join(format!("blkio.{name}")));
+        let mut blkio_stat = BlkIoStat {
+            io_service_bytes_recursive: entries("io_service_bytes_recursive")?,
+            io_serviced_recursive: entries("io_serviced_recursive")?,
+            io_queued_recursive: entries("io_queued_recursive")?,
+            io_service_time_recursive: entries("io_service_time_recursive")?,
+            io_wait_time_recursive: entries("io_wait_time_recursive")?,
+            io_merged_recursive: entries("io_merged_recursive")?,
+            io_time_recursive: entries("time_recursive")?,
+            sectors_recursive: entries("sectors_recursive")?,
+        };
+        if blkio_stat.io_service_bytes_recursive.is_empty() {
+            // the CFQ scheduler stats are not available, use the throttling policy stats
+            blkio_stat.io_service_bytes_recursive = entries("throttle.io_service_bytes")?;
+            blkio_stat.io_serviced_recursive = entries("throttle.io_serviced")?;
+        }
+
+        let hugetlb = self.dir("hugetlb");
+        let hugetlb = page_sizes(&hugetlb, ".usage_in_bytes")?
+            .into_iter()
+            .map(|pagesize| {
+                let file = |name: &str| hugetlb.join(format!("hugetlb.{pagesize}.{name}"));
+                Ok(HugetlbStat {
+                    usage: read_u64(file("usage_in_bytes"))?,
+                    max: read_u64(file("max_usage_in_bytes"))?,
+                    failcnt: read_u64(file("failcnt"))?,
+                    pagesize,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Metrics {
+            hugetlb,
+            pids: Some(pids),
+            cpu: Some(CpuStat {
+                usage: Some(usage),
+                throttling: Some(throttling),
+            }),
+            memory: Some(memory_stat),
+            blkio: Some(blkio_stat),
+            memory_oom_control: Some(memory_oom_control),
+            ..Default::default()
+        })
+    }
+
+    fn metrics_v2(&self) -> Result<Metrics> {
+        let dir = self.dir("");
+        if !dir.is_dir() {
+            return Err(not_found(&self.path));
+        }
+
+        let stat = read_keyed(dir.join("cpu.stat"))?;
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        let cpu = CpuStat {
+            usage: Some(CpuUsage {
+                total: get("usage_usec") * 1000,
+                kernel: get("system_usec") * 1000,
+                user: get("user_usec") * 1000,
+                per_cpu: vec![],
+            }),
+            throttling: Some(Throttle {
+                periods: get("nr_periods"),
+                throttled_periods: get("nr_throttled"),
+                throttled_time: get("throttled_usec") * 1000,
+            }),
+        };
+
+        let stat = read_keyed(dir.join("memory.stat"))?;
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        let events = read_keyed(dir.join("memory.events"))?;
+        let memory = MemoryStat {
+            cache: get("file"),
+            rss: get("anon"),
+            rss_huge: get("anon_thp"),
+            mapped_file: get("file_mapped"),
+            dirty: get("file_dirty"),
+            writeback: get("file_writeback"),
+            pg_fault: get("pgfault"),
+            pg_maj_fault: get("pgmajfault"),
+            inactive_anon: get("inactive_anon"),
+            active_anon: get("active_anon"),
+            inactive_file: get("inactive_file"),
+            active_file: get("active_file"),
+            unevictable: get("unevictable"),
+            usage: Some(MemoryEntry {
+                limit: read_u64(dir.join("memory.max"))?,
+                usage: read_u64(dir.join("memory.current"))?,
+                max: read_u64(dir.join("memory.peak"))?,
+                failcnt: events.get("max").copied().unwrap_or_default(),
+            }),
+            swap: Some(MemoryEntry {
+                limit: read_u64(dir.join("memory.swap.max"))?,
+                usage: read_u64(dir.join("memory.swap.current"))?,
+                max: read_u64(dir.join("memory.swap.peak"))?,
+                failcnt: 0,
+            }),
+            ..Default::default()
+        };
+        let memory_oom_control = MemoryOomControl {
+            oom_kill: events.get("oom_kill").copied().unwrap_or_default(),
+            ..Default::default()
+        };
+
+        let pids = PidsStat {
+            current: read_u64(dir.join("pids.current"))?,
+            limit: read_u64(dir.join("pids.max"))?,
+        };
+
+        let mut blkio = BlkIoStat::default();
+        for line in read(dir.join("io.stat"))?.unwrap_or_default().lines() {
+            let mut fields = line.split_whitespace();
+            let Some((major, minor)) = fields.next().and_then(parse_device) else {
+                continue;
+            };
+            let entry = |op: &str, value: u64| BlkIoEntry {
+                op: op.into(),
+                major,
+                minor,
+                value,
+                ..Default::default()
+            };
+            for field in fields {
+                let Some((key, value)) = field.split_once('=') else {
+                    continue;
+                };
+                let value = parse_u64(value);
+                match key {
+                    "rbytes" => blkio.io_service_bytes_recursive.push(entry("Read", value)),
+                    "wbytes" => blkio.io_service_bytes_recursive.push(entry("Write", value)),
+                    "rios" => blkio.io_serviced_recursive.push(entry("Read", value)),
+                    "wios" => blkio.io_serviced_recursive.push(entry("Write", value)),
+                    _ => {}
+                }
+            }
+        }
+
+        let hugetlb = page_sizes(&dir, ".current")?
+            .into_iter()
+            .map(|pagesize| {
+                let file = |name: &str| dir.join(format!("hugetlb.{pagesize}.{name}"));
+                let events = read_keyed(file("events"))?;
+                Ok(HugetlbStat {
+                    usage: read_u64(file("current"))?,
+                    max: read_u64(file("max"))?,
+                    failcnt: events.get("max").copied().unwrap_or_default(),
+                    pagesize,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Metrics {
+            hugetlb,
+            pids: Some(pids),
+            cpu: Some(cpu),
+            memory: Some(memory),
+            blkio: Some(blkio),
+            memory_oom_control: Some(memory_oom_control),
+            ..Default::default()
+        })
+    }
+}
+
+/// Converts a `linux.cgroupsPath` into a path relative to the cgroup filesystem.
+/// Systemd style paths `slice:prefix:name` are expanded as systemd would,
+/// e.g., `system-foo.slice:docker:abc` becomes `system.slice/system-foo.slice/docker-abc.scope`.
+pub fn cgroupfs_path(path: &str) -> PathBuf {
+    let parts: Vec<_> = path.split(':').collect();
+    let [slice, prefix, name] = parts[..] else {
+        return path.into();
+    };
+    if path.starts_with('/') {
+        return path.into();
+    }
+
+    let mut result = PathBuf::new();
+    let slice = slice.strip_suffix(".slice").unwrap_or(slice);
+    if !slice.is_empty() && slice != "-" {
+        let mut parent = String::new();
+        for component in slice.split('-') {
+            if !parent.is_empty() {
+                parent.push('-');
+            }
+            parent.push_str(component);
+            result.push(format!("{parent}.slice"));
+        }
+    }
+
+    if name.ends_with(".slice") {
+        result.push(name);
+    } else if prefix.is_empty() {
+        result.push(format!("{name}.scope"));
+    } else {
+        result.push(format!("{prefix}-{name}.scope"));
+    }
+    result
+}
+
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("cgroup {path:?} not found"))
+}
+
+// reads a file, returning `None` if it doesn't exist, e.g., if the controller isn't enabled
+fn read(path: impl AsRef<Path>) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// parses a cgroup value, where "max" means unlimited
+fn parse_u64(value: &str) -> u64 {
+    match value.trim() {
+        "max" => u64::MAX,
+        value => value.parse().unwrap_or_default(),
+    }
+}
+
+fn read_u64(path: impl AsRef<Path>) -> Result<u64> {
+    Ok(read(path)?.as_deref().map(parse_u64).unwrap_or_default())
+}
+
+// reads a file of `key value` lines
+fn read_keyed(path: impl AsRef<Path>) -> Result<HashMap<String, u64>> {
+    let content = read(path)?.unwrap_or_default();
+    let values = content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(key, value)| (key.to_string(), parse_u64(value)))
+        .collect();
+    Ok(values)
+}
+
+fn parse_device(device: &str) -> Option<(u64, u64)> {
+    let (major, minor) = device.split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+// reads a v1 blkio file of `major:minor [op] value` lines
+fn read_blkio(path: impl AsRef<Path>) -> Result<Vec<BlkIoEntry>> {
+    let content = read(path)?.unwrap_or_default();
+    let entries = content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (device, op, value) = match fields[..] {
+                [device, op, value] => (device, op, value),
+                [device, value] => (device, "", value),
+                _ => return None,
+            };
+            // skips the "Total" summary line
+            let (major, minor) = parse_device(device)?;
+            Some(BlkIoEntry {
+                op: op.into(),
+                major,
+                minor,
+                value: parse_u64(value),
+                ..Default::default()
+            })
+        })
+        .collect();
+    Ok(entries)
+}
+
+// lists the huge page sizes with a `hugetlb.<size><suffix>` file in `dir`
+fn page_sizes(dir: &Path, suffix: &str) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut sizes = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(size) = name
+            .strip_prefix("hugetlb.")
+            .and_then(|name| name.strip_suffix(suffix))
+        {
+            if !size.contains('.') {
+                sizes.push(size.to_string());
+            }
+        }
+    }
+    sizes.sort();
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn metrics_v1() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            &[
+                ("cpuacct/ctr/cpuacct.usage", "5000\n"),
+                ("cpuacct/ctr/cpuacct.usage_percpu", "2000 3000 \n"),
+                ("cpuacct/ctr/cpuacct.stat", "user 3\nsystem 2\n"),
+                (
+                    "cpu/ctr/cpu.stat",
+                    "nr_periods 10\nnr_throttled 2\nthrottled_time 300\n",
+                ),
+                (
+                    "memory/ctr/memory.stat",
+                    "cache 100\nrss 200\ntotal_rss 250\n",
+                ),
+                ("memory/ctr/memory.usage_in_bytes", "4096\n"),
+                ("memory/ctr/memory.limit_in_bytes", "8192\n"),
+                ("memory/ctr/memory.max_usage_in_bytes", "6000\n"),
+                ("memory/ctr/memory.failcnt", "1\n"),
+                (
+                    "memory/ctr/memory.oom_control",
+                    "oom_kill_disable 0\nunder_oom 0\noom_kill 2\n",
+                ),
+                ("pids/ctr/pids.current", "3\n"),
+                ("pids/ctr/pids.max", "max\n"),
+                (
+                    "blkio/ctr/blkio.io_service_bytes_recursive",
+                    "8:0 Read 1024\n8:0 Write 2048\nTotal 3072\n",
+                ),
+                ("blkio/ctr/blkio.sectors_recursive", "8:0 6\n"),
+                ("hugetlb/ctr/hugetlb.2MB.usage_in_bytes", "2097152\n"),
+                ("hugetlb/ctr/hugetlb.2MB.max_usage_in_bytes", "4194304\n"),
+                ("hugetlb/ctr/hugetlb.2MB.failcnt", "0\n"),
+                (
+                    "hugetlb/ctr/hugetlb.2MB.limit_in_bytes",
+                    "9223372036854771712\n",
+                ),
+            ],
+        );
+
+        let cgroup = Cgroup::with_root(root.path(), "/ctr");
+        assert_eq!(cgroup.hierarchy(), Hierarchy::V1);
+        let metrics = cgroup.metrics().unwrap();
+
+        let usage = metrics.cpu.as_ref().unwrap().usage.as_ref().unwrap();
+        assert_eq!(usage.total, 5000);
+        assert_eq!(usage.per_cpu, [2000, 3000]);
+        assert_eq!(usage.user, 30_000_000);
+        assert_eq!(usage.kernel, 20_000_000);
+        let throttling = metrics.cpu.as_ref().unwrap().throttling.unwrap();
+        assert_eq!(throttling.throttled_periods, 2);
+        assert_eq!(throttling.throttled_time, 300);
+
+        let memory = metrics.memory.as_ref().unwrap();
+        assert_eq!(memory.cache, 100);
+        assert_eq!(memory.rss, 200);
+        assert_eq!(memory.total_rss, 250);
+        let usage = memory.usage.unwrap();
+        assert_eq!((usage.usage, usage.limit, usage.max), (4096, 8192, 6000));
+        assert_eq!(usage.failcnt, 1);
+        assert_eq!(memory.swap, None);
+        assert_eq!(metrics.memory_oom_control.unwrap().oom_kill, 2);
+
+        let pids = metrics.pids.unwrap();
+        assert_eq!((pids.current, pids.limit), (3, u64::MAX));
+
+        let blkio = metrics.blkio.as_ref().unwrap();
+        let bytes: Vec<_> = blkio
+            .io_service_bytes_recursive
+            .iter()
+            .map(|e| (e.op.as_str(), e.major, e.minor, e.value))
+            .collect();
+        assert_eq!(bytes, [("Read", 8, 0, 1024), ("Write", 8, 0, 2048)]);
+        assert_eq!(blkio.sectors_recursive[0].value, 6);
+
+        assert_eq!(metrics.hugetlb.len(), 1);
+        assert_eq!(metrics.hugetlb[0].pagesize, "2MB");
+        assert_eq!(metrics.hugetlb[0].usage, 2097152);
+        assert_eq!(metrics.hugetlb[0].max, 4194304);
+    }
+
+    #[test]
+    fn metrics_v2() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            &[
+                ("cgroup.controllers", "cpu io memory pids hugetlb\n"),
+                (
+                    "ctr/cpu.stat",
+                    "usage_usec 5\nuser_usec 3\nsystem_usec 2\nnr_periods 10\nnr_throttled 1\nthrottled_usec 7\n",
+                ),
+                ("ctr/memory.stat", "anon 200\nfile 100\nfile_dirty 4\npgfault 9\n"),
+                ("ctr/memory.current", "4096\n"),
+                ("ctr/memory.max", "max\n"),
+                ("ctr/memory.swap.current", "0\n"),
+                ("ctr/memory.swap.max", "1024\n"),
+                ("ctr/memory.events", "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\n"),
+                ("ctr/pids.current", "3\n"),
+                ("ctr/pids.max", "100\n"),
+                (
+                    "ctr/io.stat",
+                    "8:0 rbytes=1024 wbytes=2048 rios=1 wios=2 dbytes=0 dios=0\n",
+                ),
+                ("ctr/hugetlb.2MB.current", "0\n"),
+                ("ctr/hugetlb.2MB.max", "max\n"),
+                ("ctr/hugetlb.2MB.events", "max 4\n"),
+            ],
+        );
+
+        let cgroup = Cgroup::with_root(root.path(), "ctr");
+        assert_eq!(cgroup.hierarchy(), Hierarchy::V2);
+        let metrics = cgroup.metrics().unwrap();
+
+        let usage = metrics.cpu.as_ref().unwrap().usage.as_ref().unwrap();
+        assert_eq!((usage.total, usage.user, usage.kernel), (5000, 3000, 2000));
+        let throttling = metrics.cpu.as_ref().unwrap().throttling.unwrap();
+        assert_eq!(throttling.throttled_time, 7000);
+
+        let memory = metrics.memory.as_ref().unwrap();
+        assert_eq!((memory.rss, memory.cache, memory.dirty), (200, 100, 4));
+        let usage = memory.usage.unwrap();
+        assert_eq!(
+            (usage.usage, usage.limit, usage.failcnt),
+            (4096, u64::MAX, 3)
+        );
+        assert_eq!(memory.swap.unwrap().limit, 1024);
+        assert_eq!(metrics.memory_oom_control.unwrap().oom_kill, 1);
+
+        let pids = metrics.pids.unwrap();
+        assert_eq!((pids.current, pids.limit), (3, 100));
+
+        let blkio = metrics.blkio.as_ref().unwrap();
+        let serviced: Vec<_> = blkio
+            .io_serviced_recursive
+            .iter()
+            .map(|e| (e.op.as_str(), e.value))
+            .collect();
+        assert_eq!(serviced, [("Read", 1), ("Write", 2)]);
+
+        assert_eq!(metrics.hugetlb.len(), 1);
+        assert_eq!(metrics.hugetlb[0].max, u64::MAX);
+        assert_eq!(metrics.hugetlb[0].failcnt, 4);
+
+        let stats = cgroup.stats().unwrap();
+        assert_eq!(stats.to_msg::<Metrics>().unwrap(), metrics);
+    }
+
+    #[test]
+    fn missing_cgroup() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path(), &[("cgroup.controllers", "")]);
+        let err = Cgroup::with_root(root.path(), "ctr").metrics().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn add_process() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            &[
+                ("cpu,cpuacct/shim/tasks", ""),
+                ("memory/shim/tasks", ""),
+                ("pids/other/tasks", ""),
+            ],
+        );
+        std::os::unix::fs::symlink("cpu,cpuacct", root.path().join("cpu")).unwrap();
+        Cgroup::with_root(root.path(), "/shim")
+            .add_process(42)
+            .unwrap();
+        for controller in ["cpu,cpuacct", "memory"] {
+            let procs = root.path().join(controller).join("shim/cgroup.procs");
+            assert_eq!(fs::read_to_string(procs).unwrap(), "42");
+        }
+        assert!(!root.path().join("pids/shim").exists());
+        let err = Cgroup::with_root(root.path(), "missing").add_process(42);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::NotFound);
+
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            &[("cgroup.controllers", ""), ("shim/cgroup.procs", "")],
+        );
+        Cgroup::with_root(root.path(), "shim")
+            .add_process(42)
+            .unwrap();
+        let procs = fs::read_to_string(root.path().join("shim/cgroup.procs")).unwrap();
+        assert_eq!(procs, "42");
+        let err = Cgroup::with_root(root.path(), "missing").add_process(42);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn procs() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            &[
+                ("cpu,cpuacct/ctr/cgroup.procs", "42\n43\n"),
+                ("memory/ctr/cgroup.procs", "43\n44\n"),
+                ("pids/other/cgroup.procs", "45\n"),
+            ],
+        );
+        std::os::unix::fs::symlink("cpu,cpuacct", root.path().join("cpu")).unwrap();
+        let procs = Cgroup::with_root(root.path(), "ctr").procs().unwrap();
+        assert_eq!(procs, [42, 43, 44]);
+        let err = Cgroup::with_root(root.path(), "missing").procs();
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::NotFound);
+
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            &[("cgroup.controllers", ""), ("ctr/cgroup.procs", "42\n")],
+        );
+        let procs = Cgroup::with_root(root.path(), "ctr").procs().unwrap();
+        assert_eq!(procs, [42]);
+    }
+
+    #[test]
+    fn systemd_paths() {
+        assert_eq!(
+            cgroupfs_path("/kubepods/pod1/ctr"),
+            Path::new("/kubepods/pod1/ctr")
+        );
+        assert_eq!(
+            cgroupfs_path("system.slice:docker:abc"),
+            Path::new("system.slice/docker-abc.scope")
+        );
+        assert_eq!(
+            cgroupfs_path("kubepods-besteffort-pod1.slice:cri-containerd:abc"),
+            Path::new(
+                "kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1.slice/cri-containerd-abc.scope"
+            )
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use tokio::time::Instant;
+
+use crate::bundle::Bundle;
+use crate::cgroups::Cgroup;
+use crate::mount;
+use crate::process::{Exit, Pidfd};
+use crate::state::{self, StateStore, STATE_FILE};
+use crate::types::task::DeleteResponse;
+
+/// The file in the bundle where the pid of the init process is recorded, like runc does.
+pub const INIT_PID_FILE: &str = "init.pid";
+
+/// The file in the bundle where the exit of the init process is recorded.
+pub const INIT_EXIT_FILE: &str = "init.exit";
+
+// the status reported when the init process had to be killed, 128 + SIGKILL
+const KILLED_STATUS: u32 = 137;
+
+// how long to wait for a killed process to go away
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Records the pid of the init process of the container in `bundle`.
+pub fn record_pid(bundle: impl AsRef<Path>, pid: u32) -> Result<()> {
+    std::fs::write(bundle.as_ref().join(INIT_PID_FILE), pid.to_string())
+}
+
+/// Records the exit of the init process of the container in `bundle`.
+pub fn record_exit(bundle: impl AsRef<Path>, exit: &Exit) -> Result<()> {
+    let content = serde_json::to_vec(exit)?;
+    std::fs::write(bundle.as_ref().join(INIT_EXIT_FILE), content)
+}
+
+/// Removes the runtime state recorded in `bundle`, including the one of `state::StateStore`.
+pub fn remove_state(bundle: impl AsRef<Path>) -> Result<()> {
+    for file in [INIT_PID_FILE, INIT_EXIT_FILE, STATE_FILE] {
+        match std::fs::remove_file(bundle.as_ref().join(file)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Cleans up after a container whose shim went away, as the `delete` action does.
+///
+/// It kills the processes of the container recorded in `bundle` by `state::StateStore`
+/// that are still running, or the init process in the pid file if there's no such state
+/// or it can't be read,
+/// and then any process left in the cgroup of the container.
+/// Then it unmounts the container rootfs as recorded by `mount::mount_rootfs`, and removes
+/// the recorded state.
+/// The response carries the recorded exit of the init process if there is one,
+/// or a `SIGKILL` exit status otherwise.
+///
+/// A recorded process is only killed if it's still the one recorded: the state records
+/// its start time, and the pid file must have been written after it started, so that
+/// another process that reused its pid is left alone.
+pub async fn cleanup(bundle: impl AsRef<Path>) -> Result<DeleteResponse> {
+    let bundle = bundle.as_ref();
+
+    let mut killed = vec![];
+    let store = StateStore::new(bundle);
+    let state = store.load().unwrap_or_else(|err| {
+        log::warn!(
+            "failed to read {:?}, falling back to the pid file: {err}",
+            store.path()
+        );
+        None
+    });
+    let (pid, recorded) = match state {
+        Some(state) => {
+            // the exec processes can outlive the init process, e.g., without a pid namespace
+            for process in state.execs.values().chain([&state.init]) {
+                if process.pid != 0 {
+                    killed.extend(kill(process.pid, || process.is_running())?);
+                }
+            }
+            let pid = Some(state.init.pid).filter(|pid| *pid != 0);
+            (pid, state.init.exit)
+        }
+        None => {
+            let recorded = read_exit(bundle)?;
+            let pid = match recorded {
+                Some(exit) => Some(exit.pid),
+                None => read_pid(bundle)?,
+            };
+            if let (Some(pid), None) = (pid, recorded) {
+                let written = std::fs::metadata(bundle.join(INIT_PID_FILE))?.modified()?;
+                killed.extend(kill(pid, || started_before(pid, written))?);
+            }
+            (pid, recorded)
+        }
+    };
+    for pid in cgroup_procs(bundle)? {
+        killed.extend(kill(pid, || true)?);
+    }
+    wait_killed(killed).await;
+
+    mount::unmount_rootfs(bundle)?;
+    remove_state(bundle)?;
+
+    let (status, timestamp) = match recorded {
+        Some(exit) => (exit.status, exit.timestamp),
+        None => (KILLED_STATUS, SystemTime::now()),
+    };
+    Ok(DeleteResponse {
+        pid: pid.unwrap_or_default(),
+        exit_status: status,
+        exited_at: Some(timestamp.into()),
+    })
+}
+
+fn read_pid(bundle: &Path) -> Result<Option<u32>> {
+    let content = match std::fs::read_to_string(bundle.join(INIT_PID_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    // only positive pids designate a single process, others a process group or every process
+    let pid = content.trim().parse::<i32>().ok().filter(|pid| *pid > 0);
+    match pid {
+        Some(pid) => Ok(Some(pid as u32)),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid pid file: {:?}", content.trim()),
+        )),
+    }
+}
+
+fn read_exit(bundle: &Path) -> Result<Option<Exit>> {
+    let content = match std::fs::read(bundle.join(INIT_EXIT_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+// lists the processes in the cgroup of the container, if the bundle has one
+fn cgroup_procs(bundle: &Path) -> Result<Vec<u32>> {
+    let cgroup = match Bundle::open(bundle) {
+        Ok(bundle) => Cgroup::from_spec(bundle.spec()),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => {
+            log::warn!("failed to open the bundle, not looking for leftover processes: {err}");
+            None
+        }
+    };
+    let Some(cgroup) = cgroup else {
+        return Ok(vec![]);
+    };
+    match cgroup.procs() {
+        Ok(procs) => Ok(procs
+            .into_iter()
+            .filter(|pid| *pid != std::process::id())
+            .collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err),
+    }
+}
+
+// kills the process if `verify` confirms it's the expected one, returning its pidfd
+// to wait for it to go away; verifying it once its pidfd is opened ensures that
+// the signal can't reach another process that reused its pid in the meantime
+fn kill(pid: u32, verify: impl FnOnce() -> bool) -> Result<Option<(u32, Pidfd)>> {
+    let Some(pidfd) = Pidfd::open(pid)? else {
+        return Ok(None);
+    };
+    if !verify() {
+        log::info!("not killing process {pid}, which is not the recorded one anymore");
+        return Ok(None);
+    }
+    match pidfd.signal(libc::SIGKILL)? {
+        true => Ok(Some((pid, pidfd))),
+        false => Ok(None),
+    }
+}
+
+// waits for the killed processes to go away
+async fn wait_killed(killed: Vec<(u32, Pidfd)>) {
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    for (pid, pidfd) in killed {
+        match tokio::time::timeout_at(deadline, pidfd.exited()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::warn!("failed to wait for process {pid} to be killed: {err}"),
+            Err(_) => log::warn!("process {pid} is still running after being killed"),
+        }
+    }
+}
+
+// whether the process started before `time`, which a process reusing its pid after that can't
+fn started_before(pid: u32, time: SystemTime) -> bool {
+    let (Some(ticks), Some(boot)) = (state::start_time(pid), boot_time()) else {
+        return false;
+    };
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return false;
+    }
+    boot + Duration::from_millis(ticks.saturating_mul(1000) / hz as u64) <= time
+}
+
+// the boot time of the system, rounded down to the second
+fn boot_time() -> Option<SystemTime> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let secs = stat.lines().find_map(|line| line.strip_prefix("btime "))?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs.trim().parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::process::monitor;
+    use crate::state::{start_time, ContainerState, ProcessState};
+    use crate::types::task::Status as TaskStatus;
+
+    fn bundle() -> tempfile::TempDir {
+        let bundle = tempfile::tempdir().unwrap();
+        std::fs::create_dir(bundle.path().join("rootfs")).unwrap();
+        bundle
+    }
+
+    #[tokio::test]
+    async fn cleanup_leftover_process() {
+        let bundle = bundle();
+        let child = monitor().spawn(Command::new("sleep").arg("100")).unwrap();
+        let pid = child.id();
+        record_pid(bundle.path(), pid).unwrap();
+
+        let res = cleanup(bundle.path()).await.unwrap();
+        assert_eq!(res.pid, pid);
+        assert_eq!(res.exit_status, 137);
+
+        let exit = monitor().wait(pid).await;
+        monitor().forget(pid);
+        assert_eq!(exit.status, 137);
+
+        assert!(!bundle.path().join(INIT_PID_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn cleanup_reused_pid() {
+        let bundle = bundle();
+        let child = monitor().spawn(Command::new("sleep").arg("100")).unwrap();
+        let pid = child.id();
+        record_pid(bundle.path(), pid).unwrap();
+        // the pid was recorded before the process started, so it's another process
+        let file = std::fs::File::options()
+            .write(true)
+            .open(bundle.path().join(INIT_PID_FILE))
+            .unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+
+        let res = cleanup(bundle.path()).await.unwrap();
+        assert_eq!(res.pid, pid);
+        assert_eq!(res.exit_status, 137);
+        assert!(start_time(pid).is_some());
+
+        unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+        monitor().wait(pid).await;
+        monitor().forget(pid);
+    }
+
+    #[tokio::test]
+    async fn cleanup_invalid_pid() {
+        let bundle = bundle();
+        for pid in ["4294967295", "2147483648", "-1", "0", "abc"] {
+            std::fs::write(bundle.path().join(INIT_PID_FILE), pid).unwrap();
+            let err = cleanup(bundle.path()).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{pid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_recorded_exit() {
+        let bundle = bundle();
+        let exit = Exit {
+            pid: 1234,
+            status: 3,
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(42),
+        };
+        record_pid(bundle.path(), exit.pid).unwrap();
+        record_exit(bundle.path(), &exit).unwrap();
+        // a state that can't be read falls back to the pid and exit files
+        std::fs::write(bundle.path().join(STATE_FILE), r#"{"id":"c1","#).unwrap();
+
+        let res = cleanup(bundle.path()).await.unwrap();
+        assert_eq!(res.pid, 1234);
+        assert_eq!(res.exit_status, 3);
+        assert_eq!(res.exited_at.unwrap().seconds, 42);
+
+        assert!(!bundle.path().join(INIT_PID_FILE).exists());
+        assert!(!bundle.path().join(INIT_EXIT_FILE).exists());
+        assert!(!bundle.path().join(STATE_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn cleanup_recorded_state() {
+        let bundle = bundle();
+        let child = monitor().spawn(Command::new("sleep").arg("100")).unwrap();
+        let pid = child.id();
+        let exit = Exit {
+            pid: 1234,
+            status: 3,
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(42),
+        };
+        let state = ContainerState {
+            id: "c1".into(),
+            init: ProcessState {
+                pid: exit.pid,
+                status: TaskStatus::Stopped,
+                exit: Some(exit),
+                ..Default::default()
+            },
+            execs: [(
+                "e1".to_string(),
+                ProcessState {
+                    pid,
+                    start_time: start_time(pid),
+                    status: TaskStatus::Running,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        StateStore::new(bundle.path()).save(&state).unwrap();
+        // the state takes precedence over the pid file
+        record_pid(bundle.path(), 1).unwrap();
+
+        let res = cleanup(bundle.path()).await.unwrap();
+        assert_eq!(res.pid, 1234);
+        assert_eq!(res.exit_status, 3);
+        assert_eq!(res.exited_at.unwrap().seconds, 42);
+
+        // the exec process that outlived the init process is killed
+        let exit = monitor().wait(pid).await;
+        monitor().forget(pid);
+        assert_eq!(exit.status, 137);
+
+        assert!(!bundle.path().join(STATE_FILE).exists());
+        assert!(!bundle.path().join(INIT_PID_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn cleanup_empty_bundle() {
+        let bundle = bundle();
+        let res = cleanup(bundle.path()).await.unwrap();
+        assert_eq!(res.pid, 0);
+        assert_eq!(res.exit_status, 137);
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, OsStr};
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::{size_of, zeroed};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{sink, AsyncRead, AsyncWrite, AsyncWriteExt as _, Interest, ReadBuf};
+use tokio::net::UnixListener;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::io::{copy_to_end, open_fifo_reader, open_output, Uri};
+
+/// A newly allocated pseudo terminal.
+pub struct Pty {
+    // the side of the terminal the shim copies from and to
+    pub master: OwnedFd,
+
+    // the side of the terminal to hand to the process as its stdio
+    pub slave: OwnedFd,
+}
+
+/// Allocates a new pseudo terminal.
+pub fn openpty() -> Result<Pty> {
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if master < 0 {
+        return Err(Error::last_os_error());
+    }
+    // safe, since posix_openpt succeeded and we own the fd
+    let master = unsafe { OwnedFd::from_raw_fd(master) };
+
+    if unsafe { libc::grantpt(master.as_raw_fd()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::unlockpt(master.as_raw_fd()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut name = [0; 128];
+    let res = unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+    if res != 0 {
+        return Err(Error::from_raw_os_error(res));
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(OsStr::from_bytes(name.to_bytes()))?;
+
+    Ok(Pty {
+        master,
+        slave: slave.into(),
+    })
+}
+
+/// A unix socket over which a runtime (e.g., runc's `--console-socket`) sends the
+/// master side of a pseudo terminal it allocated for the container.
+/// The socket file is removed when this is dropped.
+pub struct ConsoleSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ConsoleSocket {
+    pub fn bind(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self { listener, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accepts a connection on the socket and receives a pty master through `SCM_RIGHTS`.
+    pub async fn receive(&self) -> Result<OwnedFd> {
+        let (stream, _) = self.listener.accept().await?;
+        loop {
+            stream.readable().await?;
+            match stream.try_io(Interest::READABLE, || recv_fd(stream.as_raw_fd())) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn recv_fd(socket: RawFd) -> Result<OwnedFd> {
+    // runc sends the name of the terminal as the payload, which we don't need
+    let mut payload = [0u8; 4096];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+
+    let space = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+
+    let n = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut fd = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_RIGHTS {
+            let data = unsafe { libc::CMSG_DATA(cmsg) } as *const RawFd;
+            let count = (header.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize)
+                / size_of::<RawFd>();
+            for i in 0..count {
+                // take ownership of every received fd, so that the extra ones are closed
+                let received = unsafe { OwnedFd::from_raw_fd(data.add(i).read_unaligned()) };
+                fd.get_or_insert(received);
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    match fd {
+        Some(fd) => Ok(fd),
+        None if n == 0 => Err(ErrorKind::UnexpectedEof.into()),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "console socket message has no fd",
+        )),
+    }
+}
+
+fn set_nonblocking(fd: &OwnedFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The master side of a pty, registered with the tokio reactor.
+#[derive(Clone)]
+struct Master(Arc<AsyncFd<OwnedFd>>);
+
+impl Master {
+    fn new(fd: OwnedFd) -> Result<Self> {
+        set_nonblocking(&fd)?;
+        Ok(Self(Arc::new(AsyncFd::new(fd)?)))
+    }
+}
+
+impl AsyncRead for Master {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let res = guard.try_io(|fd| {
+                let n = unsafe {
+                    libc::read(fd.as_raw_fd(), unfilled.as_mut_ptr().cast(), unfilled.len())
+                };
+                if n < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            match res {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                // reading from the master fails with EIO once every slave fd is closed
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Master {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            let res = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            match res {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The console of a process running with a terminal.
+///
+/// The console copies the containerd `stdin` into the pty master, and the pty output
+/// into the containerd `stdout`. A terminal has no separate stderr.
+pub struct Console {
+    master: Master,
+    stdin_copy: Option<AbortHandle>,
+    output_copy: Option<JoinHandle<Result<()>>>,
+}
+
+impl Console {
+    /// Starts the copy loops between the pty `master` and the containerd stdio URIs.
+    pub async fn new(master: OwnedFd, stdin: &str, stdout: &str) -> Result<Self> {
+        let master = Master::new(master)?;
+
+        let stdin_copy = match Uri::parse(stdin)? {
+            Uri::Null => None,
+            Uri::Fifo(path) => {
+                let fifo = open_fifo_reader(path)?;
+                let handle = tokio::spawn(copy_to_end(fifo, master.clone()));
+                Some(handle.abort_handle())
+            }
+            uri => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("unsupported terminal stdin {uri:?}"),
+                ))
+            }
+        };
+
+        let output_copy = match open_output(&Uri::parse(stdout)?).await? {
+            Some(writer) => tokio::spawn(copy_to_end(master.clone(), writer)),
+            // keep draining the terminal, so that the process doesn't block on writes
+            None => tokio::spawn(copy_to_end(master.clone(), sink())),
+        };
+
+        Ok(Self {
+            master,
+            stdin_copy,
+            output_copy: Some(output_copy),
+        })
+    }
+
+    /// Sets the size of the terminal, as requested by the `ResizePty` RPC.
+    pub fn resize(&self, width: u32, height: u32) -> Result<()> {
+        let size = libc::winsize {
+            ws_row: height.try_into().unwrap_or(u16::MAX),
+            ws_col: width.try_into().unwrap_or(u16::MAX),
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let res = unsafe { libc::ioctl(self.master.0.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Stops copying stdin into the terminal, as requested by the `CloseIO` RPC.
+    /// An end-of-transmission character is sent, so that the process observes EOF.
+    pub async fn close_stdin(&mut self) -> Result<()> {
+        let Some(handle) = self.stdin_copy.take() else {
+            return Ok(());
+        };
+        handle.abort();
+        self.master.write_all(&[0x04]).await
+    }
+
+    /// Waits for the terminal output to be fully copied to stdout.
+    /// This returns once every fd to the pty slave has been closed.
+    pub async fn wait(&mut self) -> Result<()> {
+        if let Some(handle) = self.stdin_copy.take() {
+            handle.abort();
+        }
+        match self.output_copy.take() {
+            Some(handle) => handle.await.map_err(Error::other)?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Some(handle) = self.stdin_copy.take() {
+            handle.abort();
+        }
+        if let Some(handle) = self.output_copy.take() {
+            handle.abort();
+        }
+    }
+}
+
+/// The consoles of the processes in a shim, keyed by exec id.
+///
+/// Each console is shared behind its own lock, so that a slow operation on one,
+/// like closing its stdin, neither blocks the others nor hides it from them.
+#[derive(Default)]
+pub struct Consoles(Mutex<HashMap<String, Arc<AsyncMutex<Console>>>>);
+
+impl Consoles {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Arc<AsyncMutex<Console>>>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn get(&self, exec_id: &str) -> Result<Arc<AsyncMutex<Console>>> {
+        self.lock().get(exec_id).cloned().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("process {exec_id:?} has no console"),
+            )
+        })
+    }
+
+    pub fn insert(&self, exec_id: impl Into<String>, console: Console) {
+        self.lock()
+            .insert(exec_id.into(), Arc::new(AsyncMutex::new(console)));
+    }
+
+    /// Removes the console of the given process. An operation still in progress
+    /// on it keeps it alive until the operation completes.
+    pub fn remove(&self, exec_id: &str) -> Option<Arc<AsyncMutex<Console>>> {
+        self.lock().remove(exec_id)
+    }
+
+    pub fn contains(&self, exec_id: &str) -> bool {
+        self.lock().contains_key(exec_id)
+    }
+
+    /// Resizes the console of the given process.
+    pub async fn resize(&self, exec_id: &str, width: u32, height: u32) -> Result<()> {
+        let console = self.get(exec_id)?;
+        let console = console.lock().await;
+        console.resize(width, height)
+    }
+
+    /// Closes the stdin of the console of the given process.
+    pub async fn close_stdin(&self, exec_id: &str) -> Result<()> {
+        let console = self.get(exec_id)?;
+        let mut console = console.lock().await;
+        console.close_stdin().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt as _;
+
+    use super::*;
+    use crate::io::open_fifo_writer;
+
+    fn mkfifo(path: &Path) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let res = unsafe { libc::mkfifo(path.as_ptr(), 0o600) };
+        assert_eq!(res, 0, "mkfifo failed: {}", Error::last_os_error());
+    }
+
+    fn send_fd(socket: &UnixStream, fd: &OwnedFd) {
+        let mut payload = *b"/dev/pts/x";
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr().cast(),
+            iov_len: payload.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as usize;
+        let mut control = vec![0u8; space];
+
+        let mut msg: libc::msghdr = unsafe { zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+            (libc::CMSG_DATA(cmsg) as *mut RawFd).write_unaligned(fd.as_raw_fd());
+        }
+
+        let res = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+        assert!(res >= 0, "sendmsg failed: {}", Error::last_os_error());
+    }
+
+    fn window_size(fd: &OwnedFd) -> (u16, u16) {
+        let mut size: libc::winsize = unsafe { zeroed() };
+        let res = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut size) };
+        assert_eq!(res, 0);
+        (size.ws_col, size.ws_row)
+    }
+
+    #[tokio::test]
+    async fn console_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let stdin = dir.path().join("stdin");
+        let stdout = dir.path().join("stdout");
+        mkfifo(&stdin);
+        mkfifo(&stdout);
+
+        // containerd's side of the fifos
+        let mut stdin_writer = open_fifo_writer(&stdin).unwrap();
+        let mut stdout_reader = open_fifo_reader(&stdout).unwrap();
+
+        let Pty { master, slave } = openpty().unwrap();
+        let mut console = Console::new(master, stdin.to_str().unwrap(), stdout.to_str().unwrap())
+            .await
+            .unwrap();
+
+        // stdin reaches the process
+        stdin_writer.write_all(b"hello\n").await.unwrap();
+        let slave = tokio::task::spawn_blocking(move || {
+            let mut reader = BufReader::new(File::from(slave));
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "hello\n");
+            reader.into_inner()
+        })
+        .await
+        .unwrap();
+
+        // the process output reaches stdout, after the echo of the input
+        let mut slave = slave;
+        slave.write_all(b"world\n").unwrap();
+        drop(slave);
+
+        tokio::time::timeout(Duration::from_secs(5), console.wait())
+            .await
+            .expect("console output was not closed")
+            .unwrap();
+
+        let mut output = vec![0; 14];
+        stdout_reader.read_exact(&mut output).await.unwrap();
+        assert_eq!(output, b"hello\r\nworld\r\n");
+    }
+
+    #[tokio::test]
+    async fn console_resize() {
+        let Pty { master, slave } = openpty().unwrap();
+        let consoles = Consoles::default();
+        consoles.insert("exec", Console::new(master, "", "").await.unwrap());
+
+        consoles.resize("exec", 120, 40).await.unwrap();
+        assert_eq!(window_size(&slave), (120, 40));
+
+        let err = consoles.resize("other", 80, 24).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // the console stays available while its stdin is being closed
+        assert!(consoles.contains("exec"));
+        let (closed, resized) = tokio::join!(
+            consoles.close_stdin("exec"),
+            consoles.resize("exec", 80, 24)
+        );
+        closed.unwrap();
+        resized.unwrap();
+        assert_eq!(window_size(&slave), (80, 24));
+        assert!(consoles.remove("exec").is_some());
+        assert!(!consoles.contains("exec"));
+    }
+
+    #[tokio::test]
+    async fn console_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = ConsoleSocket::bind(dir.path().join("console.sock")).unwrap();
+
+        let Pty { master, slave } = openpty().unwrap();
+        let path = socket.path().to_owned();
+        let sender = std::thread::spawn(move || {
+            let stream = UnixStream::connect(path).unwrap();
+            send_fd(&stream, &master);
+        });
+
+        let received = socket.receive().await.unwrap();
+        sender.join().unwrap();
+
+        // the received fd is the master of the same terminal
+        let console = Console::new(received, "", "").await.unwrap();
+        console.resize(100, 30).unwrap();
+        assert_eq!(window_size(&slave), (100, 30));
+
+        let path = socket.path().to_owned();
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::{ErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use trapeze::ServerHandle;
+
+use crate::event::EventPublisher;
+use crate::types::sandbox::{
+    CreateSandboxRequest, CreateSandboxResponse, PingRequest, PingResponse, PlatformRequest,
+    PlatformResponse, Sandbox, SandboxMetricsRequest, SandboxMetricsResponse, SandboxStatusRequest,
+    SandboxStatusResponse, ShutdownSandboxRequest, ShutdownSandboxResponse, StartSandboxRequest,
+    StartSandboxResponse, StopSandboxRequest, StopSandboxResponse, UpdateSandboxRequest,
+    UpdateSandboxResponse, WaitSandboxRequest, WaitSandboxResponse,
+};
+use crate::types::task::{
+    CheckpointTaskRequest, CleanupRequest, CloseIoRequest, ConnectRequest, ConnectResponse,
+    CreateTaskRequest, CreateTaskResponse, DeleteRequest, DeleteResponse, ExecProcessRequest,
+    KillRequest, PauseRequest, PidsRequest, PidsResponse, ResizePtyRequest, ResumeRequest,
+    ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest,
+    StatsResponse, Task, UpdateTaskRequest, VersionResponse, WaitRequest, WaitResponse,
+};
+use crate::types::{Code, Result};
+
+/// The default time given to the daemon to finish the in-flight requests
+/// and flush its events when shutting down.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a shutdown request, `Some(now)` once requested
+type Request = watch::Sender<Option<bool>>;
+
+/// Handle to a shim daemon started with `Arguments::serve`.
+///
+/// The daemon shuts down gracefully on containerd's `Shutdown` request, on `SIGTERM` or `SIGINT`,
+/// or with `shutdown`: it stops accepting connections, waits for the in-flight requests,
+/// flushes the pending events and removes its socket, and then the handle resolves.
+/// Whatever is not done within the shutdown timeout is abandoned.
+///
+/// The handle is a future that resolves with the outcome of the daemon once it has shut down.
+/// While it's awaited, the daemon can be shut down through a `DaemonHandle::controller`.
+pub struct DaemonHandle {
+    request: Arc<Request>,
+    task: JoinHandle<IoResult<()>>,
+}
+
+impl DaemonHandle {
+    // a handle for an action that doesn't serve anything
+    pub(crate) fn done() -> Self {
+        Self {
+            request: Default::default(),
+            task: tokio::spawn(async { Ok(()) }),
+        }
+    }
+
+    pub(crate) fn spawn(
+        server: impl Server,
+        request: Arc<Request>,
+        publishers: Vec<EventPublisher>,
+        timeout: Duration,
+    ) -> Self {
+        let task = tokio::spawn(supervise(server, request.clone(), publishers, timeout));
+        Self { request, task }
+    }
+
+    /// Returns a controller to shut down the daemon while the handle is awaited.
+    pub fn controller(&self) -> DaemonController {
+        DaemonController {
+            request: self.request.clone(),
+        }
+    }
+
+    /// Shuts down the daemon gracefully.
+    pub fn shutdown(&self) {
+        request(&self.request, false);
+    }
+
+    /// Shuts down the daemon without waiting for the in-flight requests.
+    pub fn terminate(&self) {
+        request(&self.request, true);
+    }
+}
+
+/// Shuts down a shim daemon, see `DaemonHandle::controller`.
+#[derive(Clone)]
+pub struct DaemonController {
+    request: Arc<Request>,
+}
+
+impl DaemonController {
+    /// Shuts down the daemon gracefully.
+    pub fn shutdown(&self) {
+        request(&self.request, false);
+    }
+
+    /// Shuts down the daemon without waiting for the in-flight requests.
+    pub fn terminate(&self) {
+        request(&self.request, true);
+    }
+}
+
+impl Drop for DaemonHandle {
+    fn drop(&mut self) {
+        self.terminate();
+    }
+}
+
+impl Future for DaemonHandle {
+    type Output = IoResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx).map(|res| match res {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        })
+    }
+}
+
+/// The server of a daemon, which resolves once it stops serving.
+pub(crate) trait Server: Future<Output = IoResult<()>> + Unpin + Send + 'static {
+    /// Stops accepting connections, and waits for the in-flight requests.
+    fn shutdown(&self);
+
+    /// Stops serving right away.
+    fn terminate(&self);
+}
+
+impl Server for ServerHandle {
+    fn shutdown(&self) {
+        self.controller().shutdown();
+    }
+
+    fn terminate(&self) {
+        self.controller().terminate();
+    }
+}
+
+fn request(request: &Request, now: bool) {
+    request.send_modify(|req| *req = Some(req.unwrap_or_default() || now));
+}
+
+async fn requested(request: &Request) -> bool {
+    let mut rx = request.subscribe();
+    if let Ok(req) = rx.wait_for(Option::is_some).await {
+        return req.unwrap_or_default();
+    }
+    // unreachable, as we hold the sender
+    false
+}
+
+#[cfg(unix)]
+async fn signaled() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut sigterm), Ok(mut sigint)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        log::error!("failed to install the shutdown signal handlers");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("received SIGTERM, shutting down"),
+        _ = sigint.recv() => log::info!("received SIGINT, shutting down"),
+    }
+}
+
+#[cfg(windows)]
+async fn signaled() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        log::error!("failed to install the shutdown signal handlers");
+        return std::future::pending().await;
+    }
+    log::info!("received Ctrl+C, shutting down");
+}
+
+async fn supervise(
+    mut server: impl Server,
+    request: Arc<Request>,
+    publishers: Vec<EventPublisher>,
+    timeout: Duration,
+) -> IoResult<()> {
+    let now = tokio::select! {
+        res = &mut server => {
+            flush(&publishers, timeout).await;
+            return res;
+        }
+        now = requested(&request) => now,
+        () = signaled() => false,
+    };
+
+    let res = if now {
+        server.terminate();
+        server.await
+    } else {
+        server.shutdown();
+        match tokio::time::timeout(timeout, &mut server).await {
+            Ok(res) => res,
+            Err(_) => {
+                log::warn!("timed out waiting for in-flight requests, terminating");
+                server.terminate();
+                server.await
+            }
+        }
+    };
+
+    // the server was terminated on purpose
+    let res = match res {
+        Err(err) if err.kind() == ErrorKind::Interrupted => Ok(()),
+        res => res,
+    };
+
+    flush(&publishers, timeout).await;
+    res
+}
+
+async fn flush(publishers: &[EventPublisher], timeout: Duration) {
+    let flush = async {
+        for publisher in publishers {
+            publisher.flush().await;
+        }
+    };
+    if tokio::time::timeout(timeout, flush).await.is_err() {
+        log::warn!("timed out flushing events, some events might be lost");
+    }
+}
+
+/// Wraps the server of a daemon to act on containerd's `Shutdown` request.
+///
+/// The daemon keeps track of the containers it serves, and only shuts down once
+/// the last one is deleted, unless containerd asks to shut down `now`.
+pub(crate) struct Daemon<S> {
+    inner: S,
+    request: Arc<Request>,
+    containers: Mutex<HashSet<String>>,
+}
+
+impl<S> Daemon<S> {
+    pub fn new(inner: S, request: Arc<Request>) -> Self {
+        Self {
+            inner,
+            request,
+            containers: Default::default(),
+        }
+    }
+
+    fn containers(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.containers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+macro_rules! delegate {
+    ($($method:ident($req:ty) -> $res:ty;)*) => {
+        $(
+            async fn $method(&self, req: $req) -> Result<$res> {
+                self.inner.$method(req).await
+            }
+        )*
+    };
+}
+
+impl<S: Task> Task for Daemon<S> {
+    async fn create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+        let id = req.id.clone();
+        let res = self.inner.create(req).await?;
+        self.containers().insert(id);
+        Ok(res)
+    }
+
+    async fn delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
+        let id = req.id.clone();
+        let is_init = req.exec_id.is_empty();
+        let res = self.inner.delete(req).await?;
+        if is_init {
+            self.containers().remove(&id);
+        }
+        Ok(res)
+    }
+
+    async fn shutdown(&self, req: ShutdownRequest) -> Result<()> {
+        let now = req.now;
+        match self.inner.shutdown(req).await {
+            Ok(()) => {}
+            // the server doesn't implement `shutdown`
+            Err(status) if status.code() == Code::NotFound => {}
+            Err(status) => return Err(status),
+        }
+        if now || self.containers().is_empty() {
+            request(&self.request, now);
+        }
+        Ok(())
+    }
+
+    delegate! {
+        state(StateRequest) -> StateResponse;
+        start(StartRequest) -> StartResponse;
+        pids(PidsRequest) -> PidsResponse;
+        pause(PauseRequest) -> ();
+        resume(ResumeRequest) -> ();
+        checkpoint(CheckpointTaskRequest) -> ();
+        kill(KillRequest) -> ();
+        exec(ExecProcessRequest) -> ();
+        resize_pty(ResizePtyRequest) -> ();
+        close_io(CloseIoRequest) -> ();
+        update(UpdateTaskRequest) -> ();
+        wait(WaitRequest) -> WaitResponse;
+        stats(StatsRequest) -> StatsResponse;
+        connect(ConnectRequest) -> ConnectResponse;
+        cleanup(CleanupRequest) -> DeleteResponse;
+        version(()) -> VersionResponse;
+    }
+}
+
+impl<S: Sandbox> Sandbox for Daemon<S> {
+    delegate! {
+        create_sandbox(CreateSandboxRequest) -> CreateSandboxResponse;
+        start_sandbox(StartSandboxRequest) -> StartSandboxResponse;
+        platform(PlatformRequest) -> PlatformResponse;
+        stop_sandbox(StopSandboxRequest) -> StopSandboxResponse;
+        update_sandbox(UpdateSandboxRequest) -> UpdateSandboxResponse;
+        wait_sandbox(WaitSandboxRequest) -> WaitSandboxResponse;
+        sandbox_status(SandboxStatusRequest) -> SandboxStatusResponse;
+        ping_sandbox(PingRequest) -> PingResponse;
+        shutdown_sandbox(ShutdownSandboxRequest) -> ShutdownSandboxResponse;
+        sandbox_metrics(SandboxMetricsRequest) -> SandboxMetricsResponse;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::future::pending;
+    use std::path::Path;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use trapeze::Client;
+
+    use super::*;
+    use crate::args::Arguments;
+    use crate::types::events::{Envelope, Events, ForwardRequest, TaskCreate};
+
+    // delivers the events after a delay
+    struct SlowEvents {
+        tx: UnboundedSender<Envelope>,
+    }
+
+    impl Events for SlowEvents {
+        async fn forward(&self, req: ForwardRequest) -> Result<()> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _ = self.tx.send(req.envelope.unwrap_or_default());
+            Ok(())
+        }
+    }
+
+    struct Server {
+        publisher: EventPublisher,
+    }
+
+    impl Task for Server {
+        async fn create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+            let event = TaskCreate {
+                container_id: req.id,
+                ..Default::default()
+            };
+            self.publisher.publish(event).await?;
+            Ok(CreateTaskResponse { pid: 42 })
+        }
+
+        async fn delete(&self, _: DeleteRequest) -> Result<DeleteResponse> {
+            Ok(DeleteResponse::default())
+        }
+
+        async fn state(&self, _: StateRequest) -> Result<StateResponse> {
+            pending().await
+        }
+    }
+
+    impl Sandbox for Server {
+        async fn update_sandbox(&self, req: UpdateSandboxRequest) -> Result<UpdateSandboxResponse> {
+            assert_eq!(req.annotations["key"], "value");
+            Ok(UpdateSandboxResponse {})
+        }
+    }
+
+    async fn serve(socket: &Path) -> (DaemonHandle, Client, UnboundedReceiver<Envelope>) {
+        let (tx, rx) = unbounded_channel();
+        let publisher = EventPublisher::new(SlowEvents { tx });
+
+        let args = Arguments {
+            action: "daemon".into(),
+            ..Default::default()
+        };
+        args.publishers.lock().unwrap().push(publisher.clone());
+        let args = args.with_shutdown_timeout(Duration::from_millis(500));
+
+        let handle = args.serve(socket, Server { publisher }).await.unwrap();
+        let client = Client::connect(format!("unix://{}", socket.display()))
+            .await
+            .unwrap();
+        (handle, client, rx)
+    }
+
+    fn create(id: &str) -> CreateTaskRequest {
+        CreateTaskRequest {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    fn delete(id: &str) -> DeleteRequest {
+        DeleteRequest {
+            id: id.into(),
+            exec_id: "".into(),
+        }
+    }
+
+    async fn resolved(handle: &mut DaemonHandle) -> bool {
+        tokio::time::timeout(Duration::from_secs(2), handle)
+            .await
+            .map(|res| res.unwrap())
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn shutdown_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+        let (mut handle, client, mut events) = serve(&socket).await;
+
+        Task::create(&client, create("c1")).await.unwrap();
+
+        // c1 was not deleted yet, the daemon keeps running
+        Task::shutdown(&client, ShutdownRequest::default())
+            .await
+            .unwrap();
+        let req = PidsRequest { id: "c1".into() };
+        let err = Task::pids(&client, req).await.unwrap_err();
+        assert_eq!(err.code(), trapeze::Code::NotFound);
+
+        Task::delete(&client, delete("c1")).await.unwrap();
+        let _ = Task::shutdown(&client, ShutdownRequest::default()).await;
+        drop(client);
+
+        assert!(resolved(&mut handle).await);
+        assert!(!socket.exists());
+
+        // the event was flushed before the handle resolved
+        assert_eq!(events.try_recv().unwrap().topic, "/tasks/create");
+    }
+
+    #[tokio::test]
+    async fn shutdown_now() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+        let (mut handle, client, _events) = serve(&socket).await;
+
+        Task::create(&client, create("c1")).await.unwrap();
+        let req = ShutdownRequest {
+            id: "c1".into(),
+            now: true,
+        };
+        let _ = Task::shutdown(&client, req).await;
+
+        assert!(resolved(&mut handle).await);
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+        let (mut handle, client, _events) = serve(&socket).await;
+
+        // a request that never completes
+        let req = StateRequest {
+            id: "c1".into(),
+            exec_id: "".into(),
+        };
+        let state = tokio::spawn(async move { Task::state(&client, req).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        handle.controller().shutdown();
+        assert!(resolved(&mut handle).await);
+        assert!(!socket.exists());
+        state.abort();
+    }
+
+    #[tokio::test]
+    async fn sandbox_rpcs() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+        let (_handle, client, _) = serve(&socket).await;
+
+        // the daemon forwards the newer sandbox requests to the server
+        let req = UpdateSandboxRequest {
+            sandbox_id: "s1".into(),
+            annotations: [("key".to_string(), "value".to_string())].into(),
+            ..Default::default()
+        };
+        Sandbox::update_sandbox(&client, req).await.unwrap();
+
+        // and the ones it doesn't implement are not supported
+        let req = SandboxMetricsRequest {
+            sandbox_id: "s1".into(),
+        };
+        let err = Sandbox::sandbox_metrics(&client, req).await.unwrap_err();
+        assert_eq!(err.code(), trapeze::Code::NotFound);
+    }
+}
@@ -1,10 +1,14 @@
-use std::io::Result as IoResult;
+use std::io::{Error as IoError, Result as IoResult};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
-use prost::Name;
-use trapeze::{Client, Result};
+use prost::{Message as _, Name};
+use tokio::io::AsyncWriteExt as _;
+use tokio::sync::{mpsc, oneshot};
+use trapeze::{Client, Result, Status};
 
 use crate::types::events::*;
 use crate::types::prost::{Any, Timestamp};
@@ -14,13 +18,6 @@ pub trait Event: Name {
     fn topic(&self) -> &'static str;
 }
 
-struct NullEvents;
-impl Events for NullEvents {
-    async fn forward(&self, _: ForwardRequest) -> Result<()> {
-        Ok(())
-    }
-}
-
 #[async_trait]
 trait DynEvents {
     async fn forward(&self, forward_request: ForwardRequest) -> Result<()>;
@@ -33,46 +30,252 @@ impl<E: Events> DynEvents for E {
     }
 }
 
+enum Transport {
+    // a fixed `Events` implementation
+    Events(Arc<dyn DynEvents + Send + Sync>),
+
+    // containerd's TTRPC endpoint, the client is reconnected after a failure
+    Ttrpc {
+        address: String,
+        client: Option<Client>,
+    },
+}
+
+impl Transport {
+    async fn forward(&mut self, req: ForwardRequest) -> Result<()> {
+        match self {
+            Transport::Events(events) => events.forward(req).await,
+            Transport::Ttrpc { address, client } => {
+                let events = match client {
+                    Some(client) => client,
+                    None => client.insert(Client::connect(&*address).await?),
+                };
+                let res = Events::forward(&*events, req).await;
+                if res.is_err() {
+                    *client = None;
+                }
+                res
+            }
+        }
+    }
+}
+
+/// Publishes events by invoking containerd's `publish` subcommand,
+/// used when the TTRPC endpoint is unavailable.
+#[derive(Clone, Debug)]
+pub(crate) struct PublishBinary {
+    // the binary to invoke, usually `containerd`
+    pub binary: PathBuf,
+
+    // the address of containerd's GRPC socket
+    pub address: String,
+}
+
+impl PublishBinary {
+    async fn publish(&self, envelope: &Envelope) -> IoResult<()> {
+        let event = envelope.event.clone().unwrap_or_default().encode_to_vec();
+
+        let mut cmd = Command::new(&self.binary);
+        cmd.arg("--address")
+            .arg(&self.address)
+            .arg("publish")
+            .arg("--topic")
+            .arg(&envelope.topic)
+            .arg("--namespace")
+            .arg(&envelope.namespace)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        // the reaper of the monitor would reap the child behind our back, but starting it
+        // would do the same to the children of the shim, so it's only used if already started
+        #[cfg(target_os = "linux")]
+        if let Some(monitor) = crate::process::started_monitor() {
+            use std::io::Write as _;
+
+            let mut child = monitor.spawn(&mut cmd)?;
+            // events are small enough to fit in the pipe buffer
+            let written = child.stdin.take().unwrap().write_all(&event);
+            let pid = child.id();
+            let exit = monitor.wait(pid).await;
+            monitor.forget(pid);
+            return self.check(written, exit.status);
+        }
+
+        let mut child = tokio::process::Command::from(cmd).spawn()?;
+        let written = child.stdin.take().unwrap().write_all(&event).await;
+        let status = child.wait().await?;
+        self.check(written, status.code().unwrap_or(1) as u32)
+    }
+
+    fn check(&self, written: IoResult<()>, status: u32) -> IoResult<()> {
+        written?;
+        if status != 0 {
+            let binary = &self.binary;
+            return Err(IoError::other(format!(
+                "{binary:?} exited with status {status}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How many times, and how often, an event is retried before it's dropped.
+/// The `DURABLE_TOPICS` events are retried until they are delivered.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Retry {
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 10,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+// the events containerd waits for, e.g., to reap a task, which are never dropped
+const DURABLE_TOPICS: &[&str] = &["/tasks/exit", "/tasks/delete", "/sandboxes/exit"];
+
+enum Item {
+    Event(Envelope),
+    Flush(oneshot::Sender<()>),
+}
+
+struct Worker {
+    transport: Transport,
+    fallback: Option<PublishBinary>,
+    retry: Retry,
+}
+
+impl Worker {
+    async fn run(mut self, mut queue: mpsc::UnboundedReceiver<Item>) {
+        while let Some(item) = queue.recv().await {
+            match item {
+                Item::Event(envelope) => self.deliver(envelope).await,
+                Item::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    async fn deliver(&mut self, envelope: Envelope) {
+        let topic = &envelope.topic;
+        let durable = DURABLE_TOPICS.contains(&topic.as_str());
+        let mut backoff = self.retry.backoff;
+        for attempt in 1.. {
+            let req = ForwardRequest {
+                envelope: Some(envelope.clone()),
+            };
+            let Err(err) = self.transport.forward(req).await else {
+                return;
+            };
+            log::warn!("failed to publish {topic:?} event (attempt {attempt}): {err}");
+
+            if let Some(fallback) = &self.fallback {
+                match fallback.publish(&envelope).await {
+                    Ok(()) => return,
+                    Err(err) => log::warn!("failed to publish {topic:?} event with binary: {err}"),
+                }
+            }
+
+            if !durable && attempt >= self.retry.attempts {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+        }
+        log::error!(
+            "dropping {topic:?} event after {} attempts",
+            self.retry.attempts
+        );
+    }
+}
+
 /// Event publisher connects to containerd's TTRPC endpoint to publish events from shim.
+///
+/// Events are queued and delivered in order by a background task. Failed deliveries
+/// are retried with backoff, reconnecting to containerd, and falling back to containerd's
+/// `publish` subcommand when a publish binary is configured.
+/// Most events are dropped after a few attempts, but the ones containerd waits for,
+/// like `TaskExit` and `TaskDelete`, are retried until they are delivered.
 #[derive(Clone)]
 pub struct EventPublisher {
-    events: Arc<dyn DynEvents + Send + Sync>,
+    queue: Option<mpsc::UnboundedSender<Item>>,
     namespace: String,
 }
 
 impl EventPublisher {
-    /// Connect to containerd's TTRPC endpoint
-    pub(crate) async fn connect(address: impl AsRef<str>) -> IoResult<Self> {
-        let client = Client::connect(address).await?;
-        Ok(Self::new(client))
+    /// Connect to containerd's TTRPC endpoint.
+    /// The connection is established lazily, and re-established if it fails.
+    pub(crate) fn connect(address: impl Into<String>, fallback: Option<PublishBinary>) -> Self {
+        let transport = Transport::Ttrpc {
+            address: address.into(),
+            client: None,
+        };
+        Self::spawn(transport, fallback, Retry::default())
     }
 
     pub(crate) fn null() -> Self {
-        Self::new(NullEvents)
+        Self {
+            queue: None,
+            namespace: "".into(),
+        }
     }
 
-    pub(crate) fn new(events: impl Events) -> Self {
+    /// Publish events to a custom `Events` implementation, e.g., a fake server in tests.
+    pub fn new(events: impl Events) -> Self {
+        Self::spawn(Transport::Events(Arc::new(events)), None, Retry::default())
+    }
+
+    fn spawn(transport: Transport, fallback: Option<PublishBinary>, retry: Retry) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let worker = Worker {
+            transport,
+            fallback,
+            retry,
+        };
+        tokio::spawn(worker.run(rx));
         Self {
-            events: Arc::new(events),
+            queue: Some(tx),
             namespace: "".into(),
         }
     }
 
     /// Publish a new event.
+    /// This returns once the event is queued, use `flush` to wait for it to be delivered.
     pub async fn publish(&self, event: impl Event) -> Result<()> {
-        let req = ForwardRequest {
-            envelope: Envelope {
-                topic: event.topic().into(),
-                timestamp: Timestamp::from(SystemTime::now()).into(),
-                namespace: self.namespace.clone(),
-                event: Any::from_msg(&event).unwrap().into(),
-            }
-            .into(),
+        let Some(queue) = &self.queue else {
+            return Ok(());
         };
 
-        self.events.forward(req).await?;
+        let envelope = Envelope {
+            topic: event.topic().into(),
+            timestamp: Timestamp::from(SystemTime::now()).into(),
+            namespace: self.namespace.clone(),
+            event: Any::from_msg(&event).unwrap().into(),
+        };
 
-        Ok(())
+        queue
+            .send(Item::Event(envelope))
+            .map_err(|_| Status::unavailable("event publisher stopped"))
+    }
+
+    /// Waits until every event published so far has been delivered or dropped.
+    pub async fn flush(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let (tx, rx) = oneshot::channel();
+        if queue.send(Item::Flush(tx)).is_ok() {
+            let _ = rx.await;
+        }
     }
 
     pub fn with_namespace(&self, namespace: impl Into<String>) -> Self {
@@ -82,71 +285,58 @@ impl EventPublisher {
     }
 }
 
-impl Event for TaskCreate {
-    fn topic(&self) -> &'static str {
-        "/tasks/create"
-    }
-}
-
-impl Event for TaskStart {
-    fn topic(&self) -> &'static str {
-        "/tasks/start"
-    }
+macro_rules! impl_event {
+    ($event:ty, $topic:literal) => {
+        impl Event for $event {
+            fn topic(&self) -> &'static str {
+                $topic
+            }
+        }
+    };
 }
 
-impl Event for TaskExecAdded {
-    fn topic(&self) -> &'static str {
-        "/tasks/exec-added"
-    }
-}
+crate::types::for_each_event!(impl_event);
 
-impl Event for TaskExecStarted {
-    fn topic(&self) -> &'static str {
-        "/tasks/exec-started"
-    }
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
 
-impl Event for TaskPaused {
-    fn topic(&self) -> &'static str {
-        "/tasks/paused"
-    }
-}
+    use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedSender};
+    use trapeze::{service, Server};
 
-impl Event for TaskResumed {
-    fn topic(&self) -> &'static str {
-        "/tasks/resumed"
-    }
-}
+    use super::*;
 
-impl Event for TaskExit {
-    fn topic(&self) -> &'static str {
-        "/tasks/exit"
+    // fails the first `failures` requests, and forwards the rest
+    struct FlakyEvents {
+        failures: AtomicU32,
+        tx: UnboundedSender<Envelope>,
     }
-}
 
-impl Event for TaskDelete {
-    fn topic(&self) -> &'static str {
-        "/tasks/delete"
+    impl Events for FlakyEvents {
+        async fn forward(&self, req: ForwardRequest) -> trapeze::Result<()> {
+            let fail = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if fail {
+                return Err(Status::unavailable("containerd is restarting"));
+            }
+            let _ = self.tx.send(req.envelope.unwrap_or_default());
+            Ok(())
+        }
     }
-}
 
-impl Event for TaskOom {
-    fn topic(&self) -> &'static str {
-        "/tasks/oom"
-    }
-}
+    const FAST_RETRY: Retry = Retry {
+        attempts: 100,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(10),
+    };
 
-impl Event for TaskCheckpointed {
-    fn topic(&self) -> &'static str {
-        "/tasks/checkpointed"
+    fn oom(id: &str) -> TaskOom {
+        TaskOom {
+            container_id: id.into(),
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use tokio::sync::mpsc::{channel, Sender};
-
-    use super::*;
 
     struct FakePublisher {
         tx: Sender<Envelope>,
@@ -186,4 +376,196 @@ mod tests {
             })
         );
     }
+
+    #[tokio::test]
+    async fn retry_in_order() {
+        let (tx, mut rx) = unbounded_channel();
+        let events = FlakyEvents {
+            failures: AtomicU32::new(3),
+            tx,
+        };
+        let transport = Transport::Events(Arc::new(events));
+        let publisher = EventPublisher::spawn(transport, None, FAST_RETRY);
+
+        for id in ["c1", "c2", "c3"] {
+            publisher.publish(oom(id)).await.unwrap();
+        }
+        publisher.flush().await;
+
+        for id in ["c1", "c2", "c3"] {
+            let envelope = rx.try_recv().unwrap();
+            assert_eq!(envelope.event.unwrap().to_msg(), Ok(oom(id)));
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_durable_events() {
+        let (tx, mut rx) = unbounded_channel();
+        let events = FlakyEvents {
+            failures: AtomicU32::new(5),
+            tx,
+        };
+        let transport = Transport::Events(Arc::new(events));
+        let retry = Retry {
+            attempts: 2,
+            ..FAST_RETRY
+        };
+        let publisher = EventPublisher::spawn(transport, None, retry);
+
+        // the first event is dropped, but the exit outlasts the failures
+        let exit = TaskExit {
+            container_id: "c1".into(),
+            ..Default::default()
+        };
+        publisher.publish(oom("c1")).await.unwrap();
+        publisher.publish(exit.clone()).await.unwrap();
+        publisher.flush().await;
+
+        let envelope = rx.try_recv().unwrap();
+        assert_eq!(envelope.event.unwrap().to_msg(), Ok(exit));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = format!("unix://{}", dir.path().join("events.sock").display());
+
+        // containerd is not listening yet
+        let publisher = EventPublisher::spawn(
+            Transport::Ttrpc {
+                address: address.clone(),
+                client: None,
+            },
+            None,
+            FAST_RETRY,
+        );
+        publisher.publish(oom("c1")).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (tx, mut rx) = unbounded_channel();
+        let events = FlakyEvents {
+            failures: AtomicU32::new(1),
+            tx,
+        };
+        let server = Server::new()
+            .register(service!(events : Events))
+            .bind(&address)
+            .await
+            .unwrap();
+
+        publisher.publish(oom("c2")).await.unwrap();
+        publisher.flush().await;
+
+        for id in ["c1", "c2"] {
+            let envelope = rx.try_recv().unwrap();
+            assert_eq!(envelope.event.unwrap().to_msg(), Ok(oom(id)));
+        }
+
+        server.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn publish_binary_fallback() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("containerd");
+        std::fs::write(
+            &binary,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}/args\ncat > {0}/event\n",
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let address = format!("unix://{}", dir.path().join("missing.sock").display());
+        let fallback = PublishBinary {
+            binary,
+            address: "/run/containerd/containerd.sock".into(),
+        };
+        let publisher = EventPublisher::spawn(
+            Transport::Ttrpc {
+                address,
+                client: None,
+            },
+            Some(fallback),
+            FAST_RETRY,
+        )
+        .with_namespace("ns1");
+
+        publisher.publish(oom("c1")).await.unwrap();
+        publisher.flush().await;
+
+        let args = std::fs::read_to_string(dir.path().join("args")).unwrap();
+        assert_eq!(
+            args,
+            "--address /run/containerd/containerd.sock publish --topic /tasks/oom --namespace ns1\n"
+        );
+
+        let event = std::fs::read(dir.path().join("event")).unwrap();
+        let event = Any::decode(&event[..]).unwrap();
+        assert_eq!(event.to_msg(), Ok(oom("c1")));
+    }
+
+    #[test]
+    fn topics() {
+        // see containerd's `pkg/events` and `core/events`
+        let known = [
+            ("containerd.events.ContainerCreate", "/containers/create"),
+            ("containerd.events.ContainerUpdate", "/containers/update"),
+            ("containerd.events.ContainerDelete", "/containers/delete"),
+            ("containerd.events.ContentDelete", "/content/delete"),
+            (
+                "containerd.services.images.v1.ImageCreate",
+                "/images/create",
+            ),
+            (
+                "containerd.services.images.v1.ImageUpdate",
+                "/images/update",
+            ),
+            (
+                "containerd.services.images.v1.ImageDelete",
+                "/images/delete",
+            ),
+            ("containerd.events.NamespaceCreate", "/namespaces/create"),
+            ("containerd.events.NamespaceUpdate", "/namespaces/update"),
+            ("containerd.events.NamespaceDelete", "/namespaces/delete"),
+            ("containerd.events.SandboxCreate", "/sandboxes/create"),
+            ("containerd.events.SandboxStart", "/sandboxes/start"),
+            ("containerd.events.SandboxExit", "/sandboxes/exit"),
+            ("containerd.events.SnapshotPrepare", "/snapshot/prepare"),
+            ("containerd.events.SnapshotCommit", "/snapshot/commit"),
+            ("containerd.events.SnapshotRemove", "/snapshot/remove"),
+            ("containerd.events.TaskCreate", "/tasks/create"),
+            ("containerd.events.TaskStart", "/tasks/start"),
+            ("containerd.events.TaskDelete", "/tasks/delete"),
+            ("containerd.events.TaskExit", "/tasks/exit"),
+            ("containerd.events.TaskOOM", "/tasks/oom"),
+            ("containerd.events.TaskExecAdded", "/tasks/exec-added"),
+            ("containerd.events.TaskExecStarted", "/tasks/exec-started"),
+            ("containerd.events.TaskPaused", "/tasks/paused"),
+            ("containerd.events.TaskResumed", "/tasks/resumed"),
+            ("containerd.events.TaskCheckpointed", "/tasks/checkpointed"),
+        ];
+
+        let mut events = vec![];
+        macro_rules! collect {
+            ($event:ty, $topic:literal) => {
+                let event = <$event>::default();
+                assert_eq!(event.topic(), $topic);
+                events.push((<$event>::full_name(), event.topic()));
+            };
+        }
+        crate::types::for_each_event!(collect);
+
+        let mut known: Vec<_> = known.map(|(name, topic)| (name.to_string(), topic)).into();
+        known.sort();
+        events.sort();
+        assert_eq!(events, known);
+    }
 }
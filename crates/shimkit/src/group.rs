@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::types::sandbox::Sandbox;
+use crate::types::task::{
+    CheckpointTaskRequest, CleanupRequest, CloseIoRequest, ConnectRequest, ConnectResponse,
+    CreateTaskRequest, CreateTaskResponse, DeleteRequest, DeleteResponse, ExecProcessRequest,
+    KillRequest, PauseRequest, PidsRequest, PidsResponse, ResizePtyRequest, ResumeRequest,
+    StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest, StatsResponse, Task,
+    UpdateTaskRequest, VersionResponse, WaitRequest, WaitResponse,
+};
+use crate::types::{Result, Status};
+
+type Factory<S> = Box<dyn Fn(&str) -> S + Send + Sync>;
+
+struct Inner<S> {
+    factory: Factory<S>,
+    // `None` while the container is being created
+    containers: Mutex<HashMap<String, Option<Arc<S>>>>,
+}
+
+/// Serves several containers from a single shim daemon, e.g., all the containers of a pod.
+///
+/// Every container gets its own `Task` implementation, created by the factory on `create`,
+/// and the requests are routed to it by container id.
+/// When served with `Arguments::serve`, the daemon keeps count of the containers,
+/// and only shuts down (removing its socket) once the last one has been deleted.
+pub struct ShimGroup<S> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S> Clone for ShimGroup<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: Task> ShimGroup<S> {
+    /// Creates an empty group, where `factory` creates the `Task` implementation
+    /// of a container given its id.
+    /// The requests not tied to a container, like `version`, use an instance with an empty id.
+    pub fn new(factory: impl Fn(&str) -> S + Send + Sync + 'static) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                factory: Box::new(factory),
+                containers: Default::default(),
+            }),
+        }
+    }
+
+    fn containers(&self) -> MutexGuard<'_, HashMap<String, Option<Arc<S>>>> {
+        self.inner
+            .containers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn container(&self, id: &str) -> Result<Arc<S>> {
+        self.containers()
+            .get(id)
+            .cloned()
+            .flatten()
+            .ok_or_else(|| Status::not_found(format!("container {id:?} not found")))
+    }
+
+    /// Returns the number of containers in the group.
+    pub fn len(&self) -> usize {
+        self.containers().values().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the ids of the containers in the group.
+    pub fn ids(&self) -> Vec<String> {
+        let containers = self.containers();
+        let created = containers.iter().filter(|(_, c)| c.is_some());
+        created.map(|(id, _)| id.clone()).collect()
+    }
+}
+
+impl<S: Task> Task for ShimGroup<S> {
+    async fn create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+        let id = req.id.clone();
+
+        // reserve the id while the container is created
+        {
+            let mut containers = self.containers();
+            if containers.contains_key(&id) {
+                return Err(Status::already_exists(format!(
+                    "container {id:?} already exists"
+                )));
+            }
+            containers.insert(id.clone(), None);
+        }
+
+        let container = Arc::new((self.inner.factory)(&id));
+        let res = match container.create(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                self.containers().remove(&id);
+                return Err(err);
+            }
+        };
+
+        self.containers().insert(id, Some(container));
+        Ok(res)
+    }
+
+    async fn state(&self, req: StateRequest) -> Result<StateResponse> {
+        self.container(&req.id)?.state(req).await
+    }
+
+    async fn start(&self, req: StartRequest) -> Result<StartResponse> {
+        self.container(&req.id)?.start(req).await
+    }
+
+    async fn delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
+        let id = req.id.clone();
+        let is_init = req.exec_id.is_empty();
+        let res = self.container(&id)?.delete(req).await?;
+
+        if is_init {
+            self.containers().remove(&id);
+        }
+        Ok(res)
+    }
+
+    async fn pids(&self, req: PidsRequest) -> Result<PidsResponse> {
+        self.container(&req.id)?.pids(req).await
+    }
+
+    async fn pause(&self, req: PauseRequest) -> Result<()> {
+        self.container(&req.id)?.pause(req).await
+    }
+
+    async fn resume(&self, req: ResumeRequest) -> Result<()> {
+        self.container(&req.id)?.resume(req).await
+    }
+
+    async fn checkpoint(&self, req: CheckpointTaskRequest) -> Result<()> {
+        self.container(&req.id)?.checkpoint(req).await
+    }
+
+    async fn kill(&self, req: KillRequest) -> Result<()> {
+        self.container(&req.id)?.kill(req).await
+    }
+
+    async fn exec(&self, req: ExecProcessRequest) -> Result<()> {
+        self.container(&req.id)?.exec(req).await
+    }
+
+    async fn resize_pty(&self, req: ResizePtyRequest) -> Result<()> {
+        self.container(&req.id)?.resize_pty(req).await
+    }
+
+    async fn close_io(&self, req: CloseIoRequest) -> Result<()> {
+        self.container(&req.id)?.close_io(req).await
+    }
+
+    async fn update(&self, req: UpdateTaskRequest) -> Result<()> {
+        self.container(&req.id)?.update(req).await
+    }
+
+    async fn wait(&self, req: WaitRequest) -> Result<WaitResponse> {
+        self.container(&req.id)?.wait(req).await
+    }
+
+    async fn stats(&self, req: StatsRequest) -> Result<StatsResponse> {
+        self.container(&req.id)?.stats(req).await
+    }
+
+    async fn connect(&self, req: ConnectRequest) -> Result<ConnectResponse> {
+        self.container(&req.id)?.connect(req).await
+    }
+
+    async fn cleanup(&self, req: CleanupRequest) -> Result<DeleteResponse> {
+        (self.inner.factory)("").cleanup(req).await
+    }
+
+    async fn version(&self, _: ()) -> Result<VersionResponse> {
+        (self.inner.factory)("").version(()).await
+    }
+}
+
+impl<S: Task> Sandbox for ShimGroup<S> {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use trapeze::Client;
+
+    use super::*;
+    use crate::args::Arguments;
+    use crate::types::task::{ShutdownRequest, Status as TaskStatus};
+    use crate::types::Code;
+
+    // a fake container, which only knows its own id
+    struct Container {
+        id: String,
+    }
+
+    impl Task for Container {
+        async fn create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+            assert_eq!(req.id, self.id);
+            tokio::task::yield_now().await;
+            if req.bundle == "invalid" {
+                return Err(Status::invalid_argument("invalid bundle"));
+            }
+            Ok(CreateTaskResponse { pid: 42 })
+        }
+
+        async fn state(&self, req: StateRequest) -> Result<StateResponse> {
+            assert_eq!(req.id, self.id);
+            Ok(StateResponse {
+                id: self.id.clone(),
+                status: TaskStatus::Created.into(),
+                ..Default::default()
+            })
+        }
+
+        async fn delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
+            assert_eq!(req.id, self.id);
+            Ok(DeleteResponse::default())
+        }
+    }
+
+    fn group() -> ShimGroup<Container> {
+        ShimGroup::new(|id| Container { id: id.into() })
+    }
+
+    fn create(id: &str) -> CreateTaskRequest {
+        CreateTaskRequest {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    fn delete(id: &str, exec_id: &str) -> DeleteRequest {
+        DeleteRequest {
+            id: id.into(),
+            exec_id: exec_id.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn route_by_id() {
+        let group = group();
+        group.create(create("c1")).await.unwrap();
+        group.create(create("c2")).await.unwrap();
+
+        let err = group.create(create("c1")).await.unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+
+        for id in ["c1", "c2"] {
+            let req = StateRequest {
+                id: id.into(),
+                exec_id: "".into(),
+            };
+            assert_eq!(group.state(req).await.unwrap().id, id);
+        }
+
+        let req = StateRequest {
+            id: "c3".into(),
+            exec_id: "".into(),
+        };
+        let err = group.state(req).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let mut ids = group.ids();
+        ids.sort();
+        assert_eq!(ids, ["c1", "c2"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_creates() {
+        let created = Arc::new(Mutex::new(vec![]));
+        let group = {
+            let created = created.clone();
+            ShimGroup::new(move |id| {
+                created.lock().unwrap().push(id.to_string());
+                Container { id: id.into() }
+            })
+        };
+
+        let (a, b) = tokio::join!(group.create(create("c1")), group.create(create("c1")));
+        assert!(a.is_ok() != b.is_ok(), "{a:?} {b:?}");
+        assert_eq!(created.lock().unwrap()[..], ["c1"]);
+        assert_eq!(group.ids(), ["c1"]);
+
+        // a failed create releases the id
+        let req = CreateTaskRequest {
+            bundle: "invalid".into(),
+            ..create("c2")
+        };
+        let err = group.create(req).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        group.create(create("c2")).await.unwrap();
+        assert_eq!(group.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refcount() {
+        let group = group();
+        group.create(create("c1")).await.unwrap();
+        group.create(create("c2")).await.unwrap();
+
+        // deleting an exec process keeps the container
+        group.delete(delete("c1", "e1")).await.unwrap();
+        assert_eq!(group.len(), 2);
+
+        group.delete(delete("c1", "")).await.unwrap();
+        assert_eq!(group.len(), 1);
+
+        group.delete(delete("c2", "")).await.unwrap();
+        assert!(group.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_when_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+        let address = format!("unix://{}", socket.display());
+
+        let args = Arguments {
+            action: "daemon".into(),
+            ..Default::default()
+        };
+        let handle = args.serve(&socket, group()).await.unwrap();
+        let client = Client::connect(&address).await.unwrap();
+
+        Task::create(&client, create("c1")).await.unwrap();
+        Task::create(&client, create("c2")).await.unwrap();
+        Task::delete(&client, delete("c1", "")).await.unwrap();
+
+        // c2 is still running, the server keeps serving
+        Task::shutdown(&client, ShutdownRequest::default())
+            .await
+            .unwrap();
+        Task::state(
+            &client,
+            StateRequest {
+                id: "c2".into(),
+                exec_id: "".into(),
+            },
+        )
+        .await
+        .unwrap();
+
+        Task::delete(&client, delete("c2", "")).await.unwrap();
+        let _ = Task::shutdown(&client, ShutdownRequest::default()).await;
+        drop(client);
+
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!socket.exists());
+    }
+}
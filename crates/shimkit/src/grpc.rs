@@ -0,0 +1,262 @@
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tonic::transport::Server as GrpcServer;
+use tonic::{Request, Response};
+
+use crate::daemon::Server;
+use crate::socket::ShimListener;
+use crate::types::sandbox::sandbox_server::{Sandbox as GrpcSandbox, SandboxServer};
+use crate::types::sandbox::{
+    CreateSandboxRequest, CreateSandboxResponse, PingRequest, PingResponse, PlatformRequest,
+    PlatformResponse, Sandbox, SandboxMetricsRequest, SandboxMetricsResponse, SandboxStatusRequest,
+    SandboxStatusResponse, ShutdownSandboxRequest, ShutdownSandboxResponse, StartSandboxRequest,
+    StartSandboxResponse, StopSandboxRequest, StopSandboxResponse, UpdateSandboxRequest,
+    UpdateSandboxResponse, WaitSandboxRequest, WaitSandboxResponse,
+};
+use crate::types::task::task_server::{Task as GrpcTask, TaskServer};
+use crate::types::task::{
+    CheckpointTaskRequest, CleanupRequest, CloseIoRequest, ConnectRequest, ConnectResponse,
+    CreateTaskRequest, CreateTaskResponse, DeleteRequest, DeleteResponse, ExecProcessRequest,
+    KillRequest, PauseRequest, PidsRequest, PidsResponse, ResizePtyRequest, ResumeRequest,
+    ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest,
+    StatsResponse, Task, UpdateTaskRequest, VersionResponse, WaitRequest, WaitResponse,
+};
+use crate::types::Status;
+
+/// Serves `server` over gRPC on `listener`, with the same `Task` and `Sandbox`
+/// implementations served over TTRPC.
+pub(crate) fn serve<S: Task + Sandbox>(listener: ShimListener, server: S) -> GrpcHandle {
+    let server = Arc::new(server);
+    let (shutdown, mut requested) = watch::channel(false);
+    let signal = async move {
+        let _ = requested.wait_for(|requested| *requested).await;
+    };
+
+    let router = GrpcServer::builder()
+        .add_service(TaskServer::new(Adapter(Arc::clone(&server))))
+        .add_service(SandboxServer::new(Adapter(server)));
+    let task = tokio::spawn(router.serve_with_incoming_shutdown(listener, signal));
+
+    GrpcHandle {
+        shutdown,
+        terminated: AtomicBool::new(false),
+        task,
+    }
+}
+
+/// Handle to a gRPC server started with `serve`.
+/// It resolves to `Ok(())` once terminated on purpose, as when shut down.
+pub(crate) struct GrpcHandle {
+    shutdown: watch::Sender<bool>,
+    terminated: AtomicBool,
+    task: JoinHandle<Result<(), tonic::transport::Error>>,
+}
+
+impl Server for GrpcHandle {
+    fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    fn terminate(&self) {
+        self.terminated.store(true, Ordering::Relaxed);
+        self.task.abort();
+    }
+}
+
+impl Drop for GrpcHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Future for GrpcHandle {
+    type Output = IoResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let terminated = self.terminated.load(Ordering::Relaxed);
+        Pin::new(&mut self.task).poll(cx).map(|res| match res {
+            Ok(res) => res.map_err(IoError::other),
+            Err(err) if err.is_cancelled() && terminated => Ok(()),
+            Err(_) => Err(IoError::new(
+                ErrorKind::Interrupted,
+                "gRPC server terminated abruptly",
+            )),
+        })
+    }
+}
+
+// implements the tonic services on top of the TTRPC ones
+struct Adapter<S>(Arc<S>);
+
+fn status(status: Status) -> tonic::Status {
+    tonic::Status::new(tonic::Code::from_i32(status.code), status.message)
+}
+
+macro_rules! adapt {
+    ($grpc:ident for $service:ident { $($method:ident($req:ty) -> $res:ty;)* }) => {
+        #[tonic::async_trait]
+        impl<S: Task + Sandbox> $grpc for Adapter<S> {
+            $(
+                async fn $method(
+                    &self,
+                    req: Request<$req>,
+                ) -> Result<Response<$res>, tonic::Status> {
+                    $service::$method(&*self.0, req.into_inner())
+                        .await
+                        .map(Response::new)
+                        .map_err(status)
+                }
+            )*
+        }
+    };
+}
+
+adapt! {
+    GrpcTask for Task {
+        state(StateRequest) -> StateResponse;
+        create(CreateTaskRequest) -> CreateTaskResponse;
+        start(StartRequest) -> StartResponse;
+        delete(DeleteRequest) -> DeleteResponse;
+        pids(PidsRequest) -> PidsResponse;
+        pause(PauseRequest) -> ();
+        resume(ResumeRequest) -> ();
+        checkpoint(CheckpointTaskRequest) -> ();
+        kill(KillRequest) -> ();
+        exec(ExecProcessRequest) -> ();
+        resize_pty(ResizePtyRequest) -> ();
+        close_io(CloseIoRequest) -> ();
+        update(UpdateTaskRequest) -> ();
+        wait(WaitRequest) -> WaitResponse;
+        stats(StatsRequest) -> StatsResponse;
+        connect(ConnectRequest) -> ConnectResponse;
+        shutdown(ShutdownRequest) -> ();
+        cleanup(CleanupRequest) -> DeleteResponse;
+        version(()) -> VersionResponse;
+    }
+}
+
+adapt! {
+    GrpcSandbox for Sandbox {
+        create_sandbox(CreateSandboxRequest) -> CreateSandboxResponse;
+        start_sandbox(StartSandboxRequest) -> StartSandboxResponse;
+        platform(PlatformRequest) -> PlatformResponse;
+        stop_sandbox(StopSandboxRequest) -> StopSandboxResponse;
+        update_sandbox(UpdateSandboxRequest) -> UpdateSandboxResponse;
+        wait_sandbox(WaitSandboxRequest) -> WaitSandboxResponse;
+        sandbox_status(SandboxStatusRequest) -> SandboxStatusResponse;
+        ping_sandbox(PingRequest) -> PingResponse;
+        shutdown_sandbox(ShutdownSandboxRequest) -> ShutdownSandboxResponse;
+        sandbox_metrics(SandboxMetricsRequest) -> SandboxMetricsResponse;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use hyper_util::rt::TokioIo;
+    use tokio::net::UnixStream;
+    use tonic::transport::{Endpoint, Uri};
+    use trapeze::Client;
+
+    use super::*;
+    use crate::args::Arguments;
+    use crate::bootstrap::Protocol;
+    use crate::types::task::task_client::TaskClient;
+
+    struct Server;
+
+    impl Task for Server {
+        async fn version(&self, _: ()) -> crate::types::Result<VersionResponse> {
+            Ok(VersionResponse {
+                executable: "shim".into(),
+                info: vec![("Version", "1.2.3").into()],
+            })
+        }
+    }
+
+    impl Sandbox for Server {}
+
+    fn args(protocol: Protocol) -> Arguments {
+        Arguments {
+            action: "daemon".into(),
+            ..Default::default()
+        }
+        .with_protocol(protocol)
+    }
+
+    async fn grpc_client(socket: &Path) -> TaskClient<tonic::transport::Channel> {
+        let socket = socket.to_owned();
+        let channel = Endpoint::from_static("http://[::]:0")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let socket = socket.clone();
+                async move { UnixStream::connect(socket).await.map(TokioIo::new) }
+            }))
+            .await
+            .unwrap();
+        TaskClient::new(channel)
+    }
+
+    #[tokio::test]
+    async fn version_over_both_transports() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let ttrpc_socket = dir.path().join("ttrpc.sock");
+        let ttrpc = args(Protocol::Ttrpc).serve(&ttrpc_socket, Server);
+        let ttrpc = ttrpc.await.unwrap();
+        let address = format!("unix://{}", ttrpc_socket.display());
+        let client = Client::connect(&address).await.unwrap();
+        let res = Task::version(&client, ()).await.unwrap();
+        assert_eq!(res.executable, "shim");
+
+        // containerd only talks gRPC to the v3 task API
+        use crate::types::task::task_server::SERVICE_NAME;
+        assert_eq!(SERVICE_NAME, "containerd.task.v3.Task");
+
+        let grpc_socket = dir.path().join("grpc.sock");
+        let grpc = args(Protocol::Grpc).serve(&grpc_socket, Server);
+        let grpc = grpc.await.unwrap();
+        let mut client = grpc_client(&grpc_socket).await;
+        let res = client.version(()).await.unwrap().into_inner();
+        assert_eq!(res.executable, "shim");
+        assert_eq!(res.info[0].value, "1.2.3");
+
+        // the methods not implemented by the server fail like they do over TTRPC
+        let err = client.pids(PidsRequest::default()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        for handle in [ttrpc, grpc] {
+            handle.shutdown();
+            tokio::time::timeout(Duration::from_secs(5), handle)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(!ttrpc_socket.exists());
+        assert!(!grpc_socket.exists());
+    }
+
+    #[tokio::test]
+    async fn terminate() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("grpc.sock");
+        let listener = ShimListener::bind(&socket).unwrap().unwrap();
+        let handle = serve(listener, Server);
+        let _client = grpc_client(&socket).await;
+
+        // a deliberate terminate is not an error
+        crate::daemon::Server::terminate(&handle);
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,516 @@
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt as _;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use tokio::io::{copy, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::unix::pipe;
+use tokio::task::{AbortHandle, JoinSet};
+
+use crate::process::monitor;
+
+/// Destination or source of a process stdio stream, as specified by containerd.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Uri {
+    /// No stream, e.g., an empty string
+    Null,
+
+    /// A named fifo, e.g., `/run/containerd/fifo/abc-stdout` or `fifo:///path`
+    Fifo(PathBuf),
+
+    /// A file, e.g., `file:///var/log/container.log`
+    File(PathBuf),
+
+    /// A logging driver binary, e.g., `binary:///usr/bin/logger?key=value`
+    Binary { path: PathBuf, args: Vec<String> },
+}
+
+impl Uri {
+    /// Parses a stdio URI from a `CreateTaskRequest` or `ExecProcessRequest`.
+    pub fn parse(uri: &str) -> Result<Self> {
+        if uri.is_empty() {
+            return Ok(Uri::Null);
+        }
+
+        let Some((scheme, rest)) = uri.split_once("://") else {
+            // paths without a scheme are fifos
+            return Ok(Uri::Fifo(uri.into()));
+        };
+
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let path = PathBuf::from(percent_decode(path)?);
+
+        match scheme {
+            "fifo" => Ok(Uri::Fifo(path)),
+            "file" => Ok(Uri::File(path)),
+            "binary" => {
+                let mut args = vec![];
+                for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+                    let (key, value) = match pair.split_once('=') {
+                        Some((key, value)) => (key, Some(value)),
+                        None => (pair, None),
+                    };
+                    args.push(percent_decode(key)?);
+                    if let Some(value) = value {
+                        args.push(percent_decode(value)?);
+                    }
+                }
+                Ok(Uri::Binary { path, args })
+            }
+            scheme => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported stdio scheme {scheme:?}"),
+            )),
+        }
+    }
+}
+
+fn percent_decode(input: &str) -> Result<String> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid URI {input:?}"));
+    let mut bytes = input.bytes();
+    let mut output = vec![];
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            output.push(byte);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let [Some(hi), Some(lo)] = hex else {
+            return Err(invalid());
+        };
+        let hex = std::str::from_utf8(&[hi, lo])
+            .map_err(|_| invalid())?
+            .to_owned();
+        output.push(u8::from_str_radix(&hex, 16).map_err(|_| invalid())?);
+    }
+    String::from_utf8(output).map_err(|_| invalid())
+}
+
+/// Creates a new pipe, returning the `(read, write)` ends.
+pub(crate) fn os_pipe() -> Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    // safe, since pipe2 succeeded and we own both ends
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Duplicates a file descriptor into the lowest available number `>= min`.
+fn dup_above(fd: &OwnedFd, min: RawFd) -> Result<OwnedFd> {
+    let new = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min) };
+    if new < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(new) })
+}
+
+/// Opens a fifo for reading.
+/// The fifo is opened in read-write mode, so that opening it doesn't block waiting
+/// for a writer, and reading from it doesn't return EOF when the writer goes away.
+pub(crate) fn open_fifo_reader(path: impl AsRef<Path>) -> Result<pipe::Receiver> {
+    pipe::OpenOptions::new()
+        .read_write(true)
+        .open_receiver(path)
+}
+
+/// Opens a fifo for writing.
+/// The fifo is opened in read-write mode, so that opening it doesn't block waiting
+/// for a reader, and writing to it doesn't fail with `EPIPE` when the reader goes away.
+pub(crate) fn open_fifo_writer(path: impl AsRef<Path>) -> Result<pipe::Sender> {
+    pipe::OpenOptions::new().read_write(true).open_sender(path)
+}
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Opens the destination of an output stream.
+/// Returns `None` for `Uri::Null`. Binary URIs are not handled here.
+pub(crate) async fn open_output(uri: &Uri) -> Result<Option<Writer>> {
+    match uri {
+        Uri::Null => Ok(None),
+        Uri::Fifo(path) => Ok(Some(Box::new(open_fifo_writer(path)?))),
+        Uri::File(path) => {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            Ok(Some(Box::new(file)))
+        }
+        Uri::Binary { .. } => Err(Error::new(
+            ErrorKind::Unsupported,
+            "binary loggers must be started with ProcessIo",
+        )),
+    }
+}
+
+/// Copies `reader` into `writer` until EOF, flushing the writer at the end.
+pub(crate) async fn copy_to_end(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    copy(&mut reader, &mut writer).await?;
+    writer.flush().await
+}
+
+/// The IO of a container process, wired according to the containerd stdio URIs.
+///
+/// The process side of each stream is taken with `take_stdin`, `take_stdout` and
+/// `take_stderr`, and should be handed to the process (e.g., with `Stdio::from`)
+/// and then dropped, so that the copy loops can observe EOF once the process exits.
+#[derive(Default)]
+pub struct ProcessIo {
+    stdin: Option<OwnedFd>,
+    stdout: Option<OwnedFd>,
+    stderr: Option<OwnedFd>,
+    stdin_copy: Option<AbortHandle>,
+    copies: JoinSet<Result<()>>,
+}
+
+impl ProcessIo {
+    /// Opens the IO for process `id` in `namespace`.
+    /// Streams with an empty URI are not opened, and their `take_*` method returns `None`.
+    /// When `stdout` is a binary logger, the same logger also handles `stderr`.
+    pub async fn open(
+        id: &str,
+        namespace: &str,
+        stdin: &str,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<Self> {
+        let mut io = ProcessIo::default();
+
+        match Uri::parse(stdin)? {
+            Uri::Null => {}
+            Uri::Fifo(path) => {
+                let fifo = open_fifo_reader(path)?;
+                let (rx, tx) = os_pipe()?;
+                let tx = pipe::Sender::from_owned_fd(tx)?;
+                // stdin is not awaited by `wait`, the process may exit without consuming it
+                let handle = tokio::spawn(copy_to_end(fifo, tx));
+                io.stdin = Some(rx);
+                io.stdin_copy = Some(handle.abort_handle());
+            }
+            uri => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("unsupported stdin {uri:?}"),
+                ))
+            }
+        }
+
+        match Uri::parse(stdout)? {
+            Uri::Binary { path, args } => {
+                let (stdout, stderr) = start_logger(id, namespace, path, args).await?;
+                io.stdout = Some(stdout);
+                io.stderr = Some(stderr);
+                return Ok(io);
+            }
+            uri => io.stdout = io.open_output(&uri).await?,
+        }
+
+        match Uri::parse(stderr)? {
+            Uri::Binary { .. } => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "binary stderr requires a binary stdout",
+                ))
+            }
+            uri => io.stderr = io.open_output(&uri).await?,
+        }
+
+        Ok(io)
+    }
+
+    async fn open_output(&mut self, uri: &Uri) -> Result<Option<OwnedFd>> {
+        let Some(writer) = open_output(uri).await? else {
+            return Ok(None);
+        };
+        let (rx, tx) = os_pipe()?;
+        let rx = pipe::Receiver::from_owned_fd(rx)?;
+        self.copies.spawn(copy_to_end(rx, writer));
+        Ok(Some(tx))
+    }
+
+    /// Takes the process side of stdin.
+    pub fn take_stdin(&mut self) -> Option<OwnedFd> {
+        self.stdin.take()
+    }
+
+    /// Takes the process side of stdout.
+    pub fn take_stdout(&mut self) -> Option<OwnedFd> {
+        self.stdout.take()
+    }
+
+    /// Takes the process side of stderr.
+    pub fn take_stderr(&mut self) -> Option<OwnedFd> {
+        self.stderr.take()
+    }
+
+    /// Closes the process stdin, as requested by the `CloseIO` RPC.
+    /// The process will observe EOF on its stdin.
+    pub fn close_stdin(&mut self) {
+        if let Some(handle) = self.stdin_copy.take() {
+            handle.abort();
+        }
+        self.stdin.take();
+    }
+
+    /// Waits for the output streams to be fully copied to their destination.
+    /// This returns once every process side of stdout and stderr has been closed.
+    pub async fn wait(&mut self) -> Result<()> {
+        self.close_stdin();
+        self.stdout.take();
+        self.stderr.take();
+        while let Some(res) = self.copies.join_next().await {
+            res.map_err(Error::other)??;
+        }
+        Ok(())
+    }
+}
+
+/// Starts a logging driver binary, following containerd's binary logging protocol.
+/// The logger receives the process stdout and stderr as fds 3 and 4, and signals that
+/// it's ready by closing fd 5. Returns the process side of stdout and stderr.
+async fn start_logger(
+    id: &str,
+    namespace: &str,
+    path: PathBuf,
+    args: Vec<String>,
+) -> Result<(OwnedFd, OwnedFd)> {
+    let (stdout_r, stdout_w) = os_pipe()?;
+    let (stderr_r, stderr_w) = os_pipe()?;
+    let (ready_r, ready_w) = os_pipe()?;
+
+    // make sure the fds don't collide with their target numbers
+    let fds = [
+        dup_above(&stdout_r, 6)?,
+        dup_above(&stderr_r, 6)?,
+        dup_above(&ready_w, 6)?,
+    ];
+    drop((stdout_r, stderr_r, ready_w));
+
+    let mut cmd = Command::new(path);
+    cmd.args(args)
+        .env("CONTAINER_ID", id)
+        .env("CONTAINER_NAMESPACE", namespace)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    let raw_fds = fds.each_ref().map(|fd| fd.as_raw_fd());
+    unsafe {
+        cmd.pre_exec(move || {
+            for (target, fd) in (3..).zip(raw_fds) {
+                // dup2 clears the close-on-exec flag of the new fd
+                if libc::dup2(fd, target) < 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let child = monitor().spawn(&mut cmd)?;
+    drop((cmd, fds));
+
+    let pid = child.id();
+    tokio::spawn(async move {
+        monitor().wait(pid).await;
+        monitor().forget(pid);
+    });
+
+    // wait for the logger to close its end of the ready pipe
+    let mut ready = pipe::Receiver::from_owned_fd(ready_r)?;
+    let _ = ready.read(&mut [0]).await?;
+
+    Ok((stdout_w, stderr_w))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{Read as _, Write as _};
+    use std::os::unix::ffi::OsStrExt as _;
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn mkfifo(path: &Path) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let res = unsafe { libc::mkfifo(path.as_ptr(), 0o600) };
+        assert_eq!(res, 0, "mkfifo failed: {}", Error::last_os_error());
+    }
+
+    async fn wait_for_file(path: &Path, expected: &str) {
+        for _ in 0..500 {
+            if std::fs::read_to_string(path).unwrap_or_default() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timeout waiting for {path:?}");
+    }
+
+    #[test]
+    fn parse_uris() {
+        assert_eq!(Uri::parse("").unwrap(), Uri::Null);
+        assert_eq!(
+            Uri::parse("/run/containerd/fifo/1-stdout").unwrap(),
+            Uri::Fifo("/run/containerd/fifo/1-stdout".into())
+        );
+        assert_eq!(
+            Uri::parse("fifo:///path/to/fifo").unwrap(),
+            Uri::Fifo("/path/to/fifo".into())
+        );
+        assert_eq!(
+            Uri::parse("file:///var/log/my%20container.log").unwrap(),
+            Uri::File("/var/log/my container.log".into())
+        );
+        assert_eq!(
+            Uri::parse("binary:///usr/bin/logger?id=abc&flag&path=%2Ftmp").unwrap(),
+            Uri::Binary {
+                path: "/usr/bin/logger".into(),
+                args: vec![
+                    "id".into(),
+                    "abc".into(),
+                    "flag".into(),
+                    "path".into(),
+                    "/tmp".into()
+                ],
+            }
+        );
+        assert!(Uri::parse("http://example.com").is_err());
+        assert!(Uri::parse("file:///bad%2").is_err());
+    }
+
+    #[tokio::test]
+    async fn fifo_stdout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stdout");
+        mkfifo(&path);
+
+        // containerd's side of the fifo
+        let mut reader = open_fifo_reader(&path).unwrap();
+
+        let mut io = ProcessIo::open("id", "ns", "", path.to_str().unwrap(), "")
+            .await
+            .unwrap();
+        assert!(io.take_stdin().is_none());
+        assert!(io.take_stderr().is_none());
+
+        let mut stdout = File::from(io.take_stdout().unwrap());
+        stdout.write_all(b"hello world").unwrap();
+        drop(stdout);
+        io.wait().await.unwrap();
+
+        let mut buf = [0; 11];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn fifo_stdin_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stdin");
+        mkfifo(&path);
+
+        // containerd's side of the fifo
+        let mut writer = open_fifo_writer(&path).unwrap();
+
+        let mut io = ProcessIo::open("id", "ns", path.to_str().unwrap(), "", "")
+            .await
+            .unwrap();
+        let mut stdin = File::from(io.take_stdin().unwrap());
+
+        writer.write_all(b"input").await.unwrap();
+
+        let stdin = tokio::task::spawn_blocking(move || {
+            let mut buf = [0; 5];
+            stdin.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"input");
+            stdin
+        })
+        .await
+        .unwrap();
+
+        io.close_stdin();
+
+        let read = tokio::task::spawn_blocking(move || {
+            let mut stdin = stdin;
+            let mut buf = vec![];
+            stdin.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        let read = tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("stdin was not closed")
+            .unwrap();
+        assert!(read.is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("container.log");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "existing\n").unwrap();
+
+        let uri = format!("file://{}", path.display());
+        let mut io = ProcessIo::open("id", "ns", "", &uri, &uri).await.unwrap();
+
+        let mut stdout = File::from(io.take_stdout().unwrap());
+        stdout.write_all(b"stdout\n").unwrap();
+        drop(stdout);
+
+        let mut stderr = File::from(io.take_stderr().unwrap());
+        stderr.write_all(b"stderr\n").unwrap();
+        drop(stderr);
+
+        io.wait().await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("existing\n"));
+        assert!(content.contains("stdout\n"));
+        assert!(content.contains("stderr\n"));
+    }
+
+    #[tokio::test]
+    async fn binary_logger() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = dir.path().join("logger.sh");
+        std::fs::write(
+            &logger,
+            r#"#!/bin/sh
+echo "$CONTAINER_ID $CONTAINER_NAMESPACE $1" > "$2/env"
+exec 5>&-
+cat <&3 > "$2/stdout.tmp" && mv "$2/stdout.tmp" "$2/stdout"
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&logger, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let uri = format!("binary://{}?dir={}", logger.display(), dir.path().display());
+        let mut io = ProcessIo::open("my-id", "my-ns", "", &uri, "")
+            .await
+            .unwrap();
+
+        // the logger signals it's ready after writing the env file
+        let env = std::fs::read_to_string(dir.path().join("env")).unwrap();
+        assert_eq!(env, "my-id my-ns dir\n");
+
+        let mut stdout = File::from(io.take_stdout().unwrap());
+        stdout.write_all(b"logged").unwrap();
+        drop(stdout);
+        io.wait().await.unwrap();
+
+        wait_for_file(&dir.path().join("stdout"), "logged").await;
+    }
+}
@@ -1,6 +1,41 @@
 pub mod args;
+pub mod bootstrap;
+pub mod bundle;
+#[cfg(target_os = "linux")]
+pub mod cgroups;
+#[cfg(target_os = "linux")]
+pub mod cleanup;
+#[cfg(target_os = "linux")]
+pub mod console;
+pub mod daemon;
 pub mod event;
+pub mod group;
+#[cfg(all(unix, feature = "grpc"))]
+mod grpc;
+#[cfg(target_os = "linux")]
+pub mod io;
+#[cfg(unix)]
+pub mod log;
+#[cfg(target_os = "linux")]
+pub mod mount;
+#[cfg(target_os = "linux")]
+pub mod oom;
+pub mod options;
+#[cfg(target_os = "linux")]
+pub mod process;
 pub mod run;
+#[cfg(target_os = "linux")]
+pub mod runc;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+#[cfg(unix)]
+mod socket;
+#[cfg(target_os = "linux")]
+pub mod state;
+#[cfg(target_os = "linux")]
+pub mod task;
+#[cfg(all(target_os = "linux", any(test, feature = "testing")))]
+pub mod testing;
 pub mod utils;
 
 pub use shimkit_types as types;
@@ -14,4 +49,4 @@ mod stdio;
 
 pub use shimkit_macros::main;
 
-pub use trapeze;
\ No newline at end of file
+pub use trapeze;
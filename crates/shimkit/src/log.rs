@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{stderr, ErrorKind, Result, Write as _};
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ::log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::args::Arguments;
+use crate::bundle::LOG_FILE;
+
+/// The format of the log records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// logrus text lines, e.g., `time="..." level=info msg="..."`.
+    #[default]
+    Text,
+    /// logrus JSON objects, one per line.
+    Json,
+}
+
+/// A logger writing containerd compatible records to the `log` fifo of the bundle.
+///
+/// Records use the logrus format containerd expects, with the `time`, `level` and `msg`
+/// fields, and are tagged with the `namespace` and `id` of the shim.
+/// The level is `debug` when containerd runs the shim with `-debug`, and `info` otherwise.
+/// If containerd stops reading the fifo and starts reading it again, e.g., on restart,
+/// the fifo is reopened.
+pub struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    fields: Vec<(&'static str, String)>,
+    sink: Mutex<Sink>,
+}
+
+impl Logger {
+    /// Creates a logger for the shim started with `args`.
+    /// It writes to the `log` fifo in the working directory, or to stderr if there is none,
+    /// e.g., when running interactively.
+    pub fn new(args: &Arguments) -> Self {
+        let level = match args.debug {
+            true => LevelFilter::Debug,
+            false => LevelFilter::Info,
+        };
+        let fields = vec![
+            ("id", args.id.clone()),
+            ("namespace", args.namespace.clone()),
+        ];
+        Self {
+            level,
+            format: LogFormat::default(),
+            fields,
+            sink: Mutex::new(Sink::new(LOG_FILE)),
+        }
+    }
+
+    /// Sets the format of the records, defaults to `LogFormat::Text`.
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the file or fifo the records are written to.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.sink = Mutex::new(Sink::new(path));
+        self
+    }
+
+    /// Installs this logger as the global logger.
+    pub fn init(self) -> std::result::Result<(), SetLoggerError> {
+        ::log::set_max_level(self.level);
+        ::log::set_boxed_logger(Box::new(self))
+    }
+
+    fn sink(&self) -> MutexGuard<'_, Sink> {
+        self.sink.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn format(&self, record: &Record<'_>) -> String {
+        let time = format_time(SystemTime::now());
+        let level = level_name(record.level());
+        let msg = record.args().to_string();
+        match self.format {
+            LogFormat::Text => {
+                let mut line = String::new();
+                let _ = write!(line, "time={}", quote(&time));
+                let _ = write!(line, " level={level}");
+                let _ = write!(line, " msg={}", quote(&msg));
+                for (key, value) in &self.fields {
+                    let _ = write!(line, " {key}={}", quote(value));
+                }
+                line
+            }
+            LogFormat::Json => {
+                // logrus sorts the keys
+                let mut fields: BTreeMap<&str, &str> =
+                    self.fields.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                fields.insert("time", &time);
+                fields.insert("level", level);
+                fields.insert("msg", &msg);
+                serde_json::to_string(&fields).unwrap_or_default()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = self.format(record);
+        line.push('\n');
+        self.sink().write(line.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+// the `log` fifo, reopened when its reader goes away
+struct Sink {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Sink {
+    fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: None,
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) {
+        if !self.path.exists() {
+            let _ = stderr().write_all(line);
+            return;
+        }
+        for _ in 0..2 {
+            let Some(file) = self.file() else {
+                // nobody is reading the fifo, the record is lost
+                return;
+            };
+            match file.write_all(line) {
+                Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                    // the reader went away, try again with a new one
+                    self.file = None;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn file(&mut self) -> Option<&mut File> {
+        if self.file.is_none() {
+            self.file = open_writer(&self.path).ok();
+        }
+        self.file.as_mut()
+    }
+}
+
+// opens the fifo for writing without blocking if there's no reader, which
+// fails with `ENXIO` instead, and then makes the writes blocking again
+fn open_writer(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .append(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warning",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+// quotes the value like logrus does, only if it has characters other than [a-zA-Z0-9-._/@^+]
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._/@^+".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        serde_json::to_string(value).unwrap_or_default()
+    }
+}
+
+// formats the time as RFC 3339 in UTC with a fixed 9 digits fraction, like containerd
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:09}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_nanos()
+    )
+}
+
+// converts days since the unix epoch to a (year, month, day) date
+// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::io::Read as _;
+    use std::os::unix::ffi::OsStrExt as _;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn logger(format: LogFormat) -> Logger {
+        let args = Arguments {
+            id: "c1".into(),
+            namespace: "k8s.io".into(),
+            ..Default::default()
+        };
+        Logger::new(&args).with_format(format)
+    }
+
+    fn record(logger: &Logger, msg: &str) -> String {
+        logger.format(
+            &Record::builder()
+                .level(Level::Warn)
+                .args(format_args!("{msg}"))
+                .build(),
+        )
+    }
+
+    #[test]
+    fn format_text() {
+        let line = record(&logger(LogFormat::Text), "hello \"world\"");
+        let (time, rest) = line.split_once(' ').unwrap();
+        assert!(time.starts_with("time=\"") && time.ends_with("Z\""));
+        assert_eq!(
+            rest,
+            r#"level=warning msg="hello \"world\"" id=c1 namespace=k8s.io"#
+        );
+    }
+
+    #[test]
+    fn format_json() {
+        let line = record(&logger(LogFormat::Json), "hello");
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "warning");
+        assert_eq!(value["msg"], "hello");
+        assert_eq!(value["id"], "c1");
+        assert_eq!(value["namespace"], "k8s.io");
+        assert!(value["time"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn level_from_debug() {
+        let args = Arguments::default();
+        assert_eq!(Logger::new(&args).level, LevelFilter::Info);
+
+        let args = Arguments {
+            debug: true,
+            ..Default::default()
+        };
+        assert_eq!(Logger::new(&args).level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn time_format() {
+        let time = UNIX_EPOCH + Duration::new(951_782_400 + 3_723, 5);
+        assert_eq!(format_time(time), "2000-02-29T01:02:03.000000005Z");
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn reopen_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+
+        let reader = || {
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+                .unwrap()
+        };
+        let read = |mut file: &File| {
+            let mut buf = String::new();
+            let _ = file.read_to_string(&mut buf);
+            buf
+        };
+
+        let mut sink = Sink::new(&path);
+
+        // without a reader the records are dropped
+        sink.write(b"lost\n");
+
+        let first = reader();
+        sink.write(b"one\n");
+        assert_eq!(read(&first), "one\n");
+
+        // the reader goes away, and a new one shows up
+        drop(first);
+        let second = reader();
+        sink.write(b"two\n");
+        assert_eq!(read(&second), "two\n");
+    }
+}
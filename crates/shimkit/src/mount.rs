@@ -0,0 +1,655 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Component, Path, PathBuf};
+use std::ptr::null;
+
+use libc::c_ulong;
+
+use crate::types::task::Mount;
+
+/// The file in the bundle where the mounts of the rootfs are recorded.
+pub const MOUNTS_FILE: &str = "rootfs.mounts";
+
+// the options that map to mount flags, and whether they clear the flag instead of setting it
+const FLAGS: &[(&str, bool, c_ulong)] = &[
+    ("async", true, libc::MS_SYNCHRONOUS),
+    ("atime", true, libc::MS_NOATIME),
+    ("bind", false, libc::MS_BIND),
+    ("defaults", false, 0),
+    ("dev", true, libc::MS_NODEV),
+    ("diratime", true, libc::MS_NODIRATIME),
+    ("dirsync", false, libc::MS_DIRSYNC),
+    ("exec", true, libc::MS_NOEXEC),
+    ("mand", false, libc::MS_MANDLOCK),
+    ("noatime", false, libc::MS_NOATIME),
+    ("nodev", false, libc::MS_NODEV),
+    ("nodiratime", false, libc::MS_NODIRATIME),
+    ("noexec", false, libc::MS_NOEXEC),
+    ("nomand", true, libc::MS_MANDLOCK),
+    ("norelatime", true, libc::MS_RELATIME),
+    ("nostrictatime", true, libc::MS_STRICTATIME),
+    ("nosuid", false, libc::MS_NOSUID),
+    ("rbind", false, libc::MS_BIND | libc::MS_REC),
+    ("relatime", false, libc::MS_RELATIME),
+    ("remount", false, libc::MS_REMOUNT),
+    ("ro", false, libc::MS_RDONLY),
+    ("rw", true, libc::MS_RDONLY),
+    ("strictatime", false, libc::MS_STRICTATIME),
+    ("suid", true, libc::MS_NOSUID),
+    ("sync", false, libc::MS_SYNCHRONOUS),
+];
+
+const PROPAGATION_FLAGS: &[(&str, c_ulong)] = &[
+    ("private", libc::MS_PRIVATE),
+    ("rprivate", libc::MS_PRIVATE | libc::MS_REC),
+    ("shared", libc::MS_SHARED),
+    ("rshared", libc::MS_SHARED | libc::MS_REC),
+    ("slave", libc::MS_SLAVE),
+    ("rslave", libc::MS_SLAVE | libc::MS_REC),
+    ("unbindable", libc::MS_UNBINDABLE),
+    ("runbindable", libc::MS_UNBINDABLE | libc::MS_REC),
+];
+
+/// The fstab style options of a mount, split into the arguments of mount(2).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    // the flags, without the propagation type
+    pub flags: c_ulong,
+
+    // the propagation type, e.g., `MS_PRIVATE | MS_REC` for `rprivate`
+    pub propagation: c_ulong,
+
+    // the filesystem specific options, e.g., `lowerdir=...` for overlay
+    pub data: String,
+}
+
+impl MountOptions {
+    pub fn parse(options: &[impl AsRef<str>]) -> Self {
+        let mut parsed = Self::default();
+        let mut data = vec![];
+        for option in options {
+            let option = option.as_ref();
+            if let Some((_, clear, flag)) = FLAGS.iter().find(|(name, ..)| *name == option) {
+                if *clear {
+                    parsed.flags &= !flag;
+                } else {
+                    parsed.flags |= flag;
+                }
+            } else if let Some((_, flag)) = PROPAGATION_FLAGS.iter().find(|(n, _)| *n == option) {
+                parsed.propagation |= flag;
+            } else {
+                data.push(option);
+            }
+        }
+        parsed.data = data.join(",");
+        parsed
+    }
+}
+
+/// Mounts `mount` on `target`.
+///
+/// Read-only bind mounts and propagation types are applied with extra mount calls,
+/// as mount(2) ignores them in the initial call.
+/// With the `remount` option, the flags of the mount already on `target` are changed instead.
+pub fn mount(mount: &Mount, target: impl AsRef<Path>) -> Result<()> {
+    mount_with(mount, || Ok(target.as_ref()))
+}
+
+// mounts on the target returned by `resolve`, which is called again after the first mount call,
+// so that the next ones can resolve to the mount it added
+fn mount_with<T: AsRef<Path>>(mount: &Mount, resolve: impl Fn() -> Result<T>) -> Result<()> {
+    let MountOptions {
+        flags,
+        propagation,
+        mut data,
+    } = MountOptions::parse(&mount.options);
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    // the directory of the compacted lowerdirs is kept open until they are mounted
+    let mut _lowerdir = None;
+    if data.len() >= page_size && mount.r#type == "overlay" {
+        if let Some((dir, compacted)) = compact_lowerdir(&data)? {
+            _lowerdir = Some(dir);
+            data = compacted;
+        }
+    }
+    if data.len() >= page_size {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("mount options exceed the page size: {data:?}"),
+        ));
+    }
+
+    let target = resolve()?;
+    mount_raw(&mount.source, target.as_ref(), &mount.r#type, flags, &data)?;
+    if propagation != 0 {
+        let flags = propagation | (flags & libc::MS_REC);
+        mount_raw("", resolve()?.as_ref(), "", flags, "")?;
+    }
+    let readonly_bind = libc::MS_BIND | libc::MS_RDONLY;
+    if flags & libc::MS_REMOUNT == 0 && flags & readonly_bind == readonly_bind {
+        let flags = flags | libc::MS_REMOUNT;
+        mount_raw("", resolve()?.as_ref(), "", flags, "")?;
+    }
+    Ok(())
+}
+
+// shortens the `lowerdir` option of an overlay mount, like containerd does, by opening
+// the common parent directory of the layers and making them relative to its fd,
+// or returns `None` if they have no common parent other than `/`
+fn compact_lowerdir(data: &str) -> Result<Option<(OwnedFd, String)>> {
+    let mut options: Vec<String> = data.split(',').map(String::from).collect();
+    let Some(lowerdir) = options.iter_mut().find(|o| o.starts_with("lowerdir=")) else {
+        return Ok(None);
+    };
+    let dirs: Vec<&str> = lowerdir["lowerdir=".len()..].split(':').collect();
+
+    let first = dirs[0].as_bytes();
+    let common = dirs[1..].iter().fold(first.len(), |len, dir| {
+        let prefix = first.iter().zip(dir.as_bytes()).take(len);
+        prefix.take_while(|(a, b)| a == b).count()
+    });
+    let Some(end) = first[..common].iter().rposition(|b| *b == b'/') else {
+        return Ok(None);
+    };
+    let parent = &dirs[0][..=end];
+    if parent == "/" || dirs.iter().any(|dir| dir.len() <= parent.len()) {
+        return Ok(None);
+    }
+
+    let parent_c = CString::new(parent)?;
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let fd = unsafe { libc::open(parent_c.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // safe, since open returned a new fd
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let prefix = format!("/proc/self/fd/{}/", fd.as_raw_fd());
+    let dirs: Vec<String> = dirs
+        .iter()
+        .map(|dir| format!("{prefix}{}", &dir[parent.len()..]))
+        .collect();
+    *lowerdir = format!("lowerdir={}", dirs.join(":"));
+    Ok(Some((fd, options.join(","))))
+}
+
+fn mount_raw(source: &str, target: &Path, fstype: &str, flags: c_ulong, data: &str) -> Result<()> {
+    let cstr = |s: &str| CString::new(s).map(Some);
+    let source = if source.is_empty() {
+        Ok(None)
+    } else {
+        cstr(source)
+    }?;
+    let fstype = if fstype.is_empty() {
+        Ok(None)
+    } else {
+        cstr(fstype)
+    }?;
+    let data = if data.is_empty() {
+        Ok(None)
+    } else {
+        cstr(data)
+    }?;
+    let target = CString::new(target.as_os_str().as_bytes())?;
+
+    let as_ptr = |s: &Option<CString>| s.as_ref().map_or(null(), |s| s.as_ptr());
+    let res = unsafe {
+        libc::mount(
+            as_ptr(&source),
+            target.as_ptr(),
+            as_ptr(&fstype),
+            flags,
+            as_ptr(&data).cast(),
+        )
+    };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lazily unmounts `target`, doing nothing if it's not a mount point.
+pub fn unmount(target: impl AsRef<Path>) -> Result<()> {
+    let target = CString::new(target.as_ref().as_os_str().as_bytes())?;
+    let res = unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+    if res < 0 {
+        let err = Error::last_os_error();
+        return match err.raw_os_error() {
+            // not mounted, or not there at all
+            Some(libc::EINVAL | libc::ENOENT) => Ok(()),
+            // we are not allowed to unmount, so we didn't mount it either
+            Some(libc::EPERM) => Ok(()),
+            _ => Err(err),
+        };
+    }
+    Ok(())
+}
+
+/// Mounts the rootfs of a container in `<bundle>/rootfs`, as listed in `CreateTaskRequest.rootfs`.
+///
+/// The mounts are performed in order, on the rootfs itself or on their `target` inside of it,
+/// and recorded in the bundle so that `unmount_rootfs` can undo them.
+/// If a mount fails, the previous ones are undone.
+///
+/// The targets are resolved without following symlinks, as the ones in the rootfs come from
+/// the image and could point anywhere on the host: a target with a symlink in its path fails.
+pub fn mount_rootfs(bundle: impl AsRef<Path>, mounts: &[Mount]) -> Result<PathBuf> {
+    let bundle = bundle.as_ref();
+    let rootfs = bundle.join("rootfs");
+    std::fs::create_dir_all(&rootfs)?;
+
+    let mut mounted = vec![];
+    for m in mounts {
+        let res = target(&rootfs, &m.target).and_then(|target| {
+            mount_with(m, || open_target(&rootfs, &target, true).map(fd_path))?;
+            // a remount changes an existing mount, there's nothing more to undo
+            if MountOptions::parse(&m.options).flags & libc::MS_REMOUNT == 0 {
+                mounted.push(target);
+            }
+            record(bundle, &mounted)
+        });
+        if let Err(err) = res {
+            if let Err(err) = unmount_rootfs(bundle) {
+                log::warn!("failed to undo the rootfs mounts: {err}");
+            }
+            return Err(err);
+        }
+    }
+    Ok(rootfs)
+}
+
+/// Unmounts the rootfs of a container in `<bundle>/rootfs`, in the reverse order of
+/// the mounts recorded by `mount_rootfs`.
+pub fn unmount_rootfs(bundle: impl AsRef<Path>) -> Result<()> {
+    let bundle = bundle.as_ref();
+    let record = bundle.join(MOUNTS_FILE);
+    let mounted: Vec<PathBuf> = match std::fs::read(&record) {
+        Ok(content) => serde_json::from_slice(&content)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+        Err(err) => return Err(err),
+    };
+
+    let rootfs = bundle.join("rootfs");
+    for target in mounted.iter().rev() {
+        let target = match open_target(&rootfs, target, false) {
+            Ok(fd) => fd_path(fd),
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                // the mount is detached with the rootfs anyway
+                log::warn!("not unmounting {target:?}: {err}");
+                continue;
+            }
+        };
+        unmount(target)?;
+    }
+    // the rootfs could have been mounted by someone else
+    unmount(rootfs)?;
+
+    match std::fs::remove_file(record) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn record(bundle: &Path, mounted: &[PathBuf]) -> Result<()> {
+    let content = serde_json::to_vec(mounted)?;
+    std::fs::write(bundle.join(MOUNTS_FILE), content)
+}
+
+// lexically resolves the target of a mount inside the rootfs, rejecting `..` components,
+// see `open_target` for its symlinks
+fn target(rootfs: &Path, target: &str) -> Result<PathBuf> {
+    let mut path = rootfs.to_owned();
+    for component in Path::new(target).components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => path.push(name),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid mount target {target:?}"),
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+// opens a target resolved by `target` one component at a time, without following symlinks,
+// and creating the missing directories if `create` is set
+fn open_target(rootfs: &Path, target: &Path, create: bool) -> Result<OwnedFd> {
+    let mut fd = OwnedFd::from(std::fs::File::open(rootfs)?);
+    let components = target.strip_prefix(rootfs).unwrap_or(target).components();
+    for component in components {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        let name = CString::new(name.as_bytes())?;
+        let mut res = open_dir(&fd, &name);
+        let missing = matches!(&res, Err(err) if err.kind() == ErrorKind::NotFound);
+        if create && missing {
+            if unsafe { libc::mkdirat(fd.as_raw_fd(), name.as_ptr(), 0o755) } < 0 {
+                let err = Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EEXIST) {
+                    return Err(err);
+                }
+            }
+            res = open_dir(&fd, &name);
+        }
+        fd = res.map_err(|err| match err.raw_os_error() {
+            Some(libc::ENOTDIR | libc::ELOOP) => Error::new(
+                ErrorKind::InvalidInput,
+                format!("mount target {target:?} is not a directory, or goes through a symlink"),
+            ),
+            _ => err,
+        })?;
+    }
+    Ok(fd)
+}
+
+fn open_dir(dir: &OwnedFd, name: &CString) -> Result<OwnedFd> {
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // safe, since openat returned a new fd
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // with `O_PATH`, a symlink is opened itself rather than failing
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd.as_raw_fd(), stat.as_mut_ptr()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    // safe, since fstat succeeded
+    if unsafe { stat.assume_init() }.st_mode & libc::S_IFMT != libc::S_IFDIR {
+        return Err(Error::from_raw_os_error(libc::ENOTDIR));
+    }
+    Ok(fd)
+}
+
+// an opened mount target, see `open_target`
+struct Target {
+    _fd: OwnedFd,
+    path: PathBuf,
+}
+
+impl AsRef<Path> for Target {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+// the path of an opened file, which mount(2) resolves to the file itself
+fn fd_path(fd: OwnedFd) -> Target {
+    let path = PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+    Target { _fd: fd, path }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::{current_exe, var};
+    use std::os::unix::process::CommandExt as _;
+    use std::process::Command;
+
+    use super::*;
+    use crate::process::monitor;
+
+    const USERNS_VAR: &str = "SHIMKIT_TEST_USERNS";
+
+    // Runs the test `name` again in a new user and mount namespace, where it can mount
+    // without privileges, and returns whether the caller is the re-run test.
+    async fn in_userns(name: &str) -> bool {
+        if var(USERNS_VAR).is_ok() {
+            // don't propagate anything back to the host
+            mount_raw("", Path::new("/"), "", libc::MS_REC | libc::MS_PRIVATE, "").unwrap();
+            return true;
+        }
+
+        // the maps are written before exec, which drops the capabilities of unmapped users
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let maps = [
+            ("/proc/self/uid_map", format!("0 {uid} 1")),
+            ("/proc/self/setgroups", "deny".to_string()),
+            ("/proc/self/gid_map", format!("0 {gid} 1")),
+        ]
+        .map(|(path, content)| (CString::new(path).unwrap(), content));
+
+        let mut cmd = Command::new(current_exe().unwrap());
+        cmd.args(["--exact", name, "--test-threads=1"])
+            .env(USERNS_VAR, "1");
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) < 0 {
+                    return Err(Error::last_os_error());
+                }
+                for (path, content) in &maps {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+                    if fd < 0 {
+                        return Err(Error::last_os_error());
+                    }
+                    let res = libc::write(fd, content.as_ptr().cast(), content.len());
+                    libc::close(fd);
+                    if res < 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+
+        let child = match monitor().spawn(&mut cmd) {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("skipping {name}, user namespaces are not available: {err}");
+                return false;
+            }
+        };
+        let exit = monitor().wait(child.id()).await;
+        monitor().forget(child.id());
+        assert_eq!(exit.status, 0, "{name} failed in a user namespace");
+        false
+    }
+
+    fn tmpfs(target: &str) -> Mount {
+        Mount {
+            r#type: "tmpfs".into(),
+            source: "tmpfs".into(),
+            target: target.into(),
+            options: vec!["size=1m".into()],
+        }
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = [
+            "rbind",
+            "ro",
+            "nosuid",
+            "rprivate",
+            "lowerdir=/a:/b",
+            "upperdir=/c",
+        ];
+        let parsed = MountOptions::parse(&options);
+        assert_eq!(
+            parsed.flags,
+            libc::MS_BIND | libc::MS_REC | libc::MS_RDONLY | libc::MS_NOSUID
+        );
+        assert_eq!(parsed.propagation, libc::MS_PRIVATE | libc::MS_REC);
+        assert_eq!(parsed.data, "lowerdir=/a:/b,upperdir=/c");
+
+        let parsed = MountOptions::parse(&["ro", "nodev", "rw", "dev"]);
+        assert_eq!(parsed.flags, 0);
+    }
+
+    #[test]
+    fn compact_lowerdirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let parent = dir.path().join("snapshots");
+        std::fs::create_dir(&parent).unwrap();
+        let parent = parent.to_str().unwrap();
+
+        let data = format!("lowerdir={parent}/12/fs:{parent}/1/fs,upperdir=/upper");
+        let (fd, compacted) = compact_lowerdir(&data).unwrap().unwrap();
+        let fd = fd.as_raw_fd();
+        assert_eq!(
+            compacted,
+            format!("lowerdir=/proc/self/fd/{fd}/12/fs:/proc/self/fd/{fd}/1/fs,upperdir=/upper")
+        );
+
+        // nothing in common but the root
+        let data = format!("lowerdir={parent}/1:/other/2");
+        assert!(compact_lowerdir(&data).unwrap().is_none());
+        let data = format!("lowerdir={parent}/:{parent}/1");
+        assert!(compact_lowerdir(&data).unwrap().is_none());
+        assert!(compact_lowerdir("upperdir=/upper").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn mount_many_layers() {
+        if !in_userns("mount::tests::mount_many_layers").await {
+            return;
+        }
+
+        // the layers of an image with many of them, whose paths exceed the page size
+        let snapshots = tempfile::tempdir().unwrap();
+        let snapshots = snapshots
+            .path()
+            .join("io.containerd.snapshotter.v1.overlayfs/snapshots");
+        let layers: Vec<String> = (1..=100)
+            .map(|layer| {
+                let dir = snapshots.join(layer.to_string()).join("fs");
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(dir.join(format!("layer{layer}")), "").unwrap();
+                dir.to_str().unwrap().into()
+            })
+            .collect();
+        let lowerdir = format!("lowerdir={}", layers.join(":"));
+        assert!(lowerdir.len() > 4096);
+
+        let bundle = tempfile::tempdir().unwrap();
+        let overlay = Mount {
+            r#type: "overlay".into(),
+            source: "overlay".into(),
+            target: "".into(),
+            options: vec![lowerdir],
+        };
+        let rootfs = mount_rootfs(bundle.path(), &[overlay]).unwrap();
+        assert!(rootfs.join("layer1").exists());
+        assert!(rootfs.join("layer100").exists());
+        unmount_rootfs(bundle.path()).unwrap();
+        assert!(!rootfs.join("layer1").exists());
+    }
+
+    #[test]
+    fn mount_target() {
+        let rootfs = Path::new("/bundle/rootfs");
+        assert_eq!(target(rootfs, "").unwrap(), rootfs);
+        assert_eq!(target(rootfs, "/data/x").unwrap(), rootfs.join("data/x"));
+        assert!(target(rootfs, "/../etc").is_err());
+    }
+
+    #[tokio::test]
+    async fn mount_and_unmount_rootfs() {
+        if !in_userns("mount::tests::mount_and_unmount_rootfs").await {
+            return;
+        }
+
+        let bundle = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        std::fs::write(data.path().join("file"), "hello").unwrap();
+
+        let bind = Mount {
+            r#type: "bind".into(),
+            source: data.path().to_str().unwrap().into(),
+            target: "/data".into(),
+            options: vec!["rbind".into(), "ro".into()],
+        };
+        let rootfs = mount_rootfs(bundle.path(), &[tmpfs(""), bind]).unwrap();
+
+        // the rootfs is a tmpfs, with a read only view of `data`
+        std::fs::write(rootfs.join("marker"), "").unwrap();
+        let content = std::fs::read_to_string(rootfs.join("data/file")).unwrap();
+        assert_eq!(content, "hello");
+        let err = std::fs::write(rootfs.join("data/file"), "bye").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+
+        unmount_rootfs(bundle.path()).unwrap();
+        assert!(!rootfs.join("marker").exists());
+        assert!(!bundle.path().join(MOUNTS_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn remount_rootfs() {
+        if !in_userns("mount::tests::remount_rootfs").await {
+            return;
+        }
+
+        let bundle = tempfile::tempdir().unwrap();
+        let remount = Mount {
+            target: "".into(),
+            options: vec!["remount".into(), "ro".into()],
+            ..Default::default()
+        };
+        let rootfs = mount_rootfs(bundle.path(), &[tmpfs(""), remount]).unwrap();
+        let err = std::fs::write(rootfs.join("marker"), "").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+
+        // only the tmpfs is recorded, as the remount didn't add a mount
+        let content = std::fs::read_to_string(bundle.path().join(MOUNTS_FILE)).unwrap();
+        let mounted: Vec<PathBuf> = serde_json::from_str(&content).unwrap();
+        assert_eq!(mounted, [rootfs.clone()]);
+        unmount_rootfs(bundle.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mount_through_symlink() {
+        if !in_userns("mount::tests::mount_through_symlink").await {
+            return;
+        }
+
+        let bundle = tempfile::tempdir().unwrap();
+        let host = tempfile::tempdir().unwrap();
+        std::fs::write(host.path().join("file"), "hello").unwrap();
+        let rootfs = bundle.path().join("rootfs");
+        std::fs::create_dir_all(rootfs.join("dir")).unwrap();
+        std::os::unix::fs::symlink(host.path(), rootfs.join("data")).unwrap();
+        std::os::unix::fs::symlink(host.path(), rootfs.join("dir/link")).unwrap();
+
+        // the symlinks in the rootfs are not followed to the host
+        for target in ["/data", "/data/sub", "/dir/link"] {
+            let err = mount_rootfs(bundle.path(), &[tmpfs(target)]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{target}");
+            assert!(host.path().join("file").exists());
+        }
+        assert!(!host.path().join("sub").exists());
+    }
+
+    #[tokio::test]
+    async fn mount_rootfs_rollback() {
+        if !in_userns("mount::tests::mount_rootfs_rollback").await {
+            return;
+        }
+
+        let bundle = tempfile::tempdir().unwrap();
+        let missing = Mount {
+            r#type: "bind".into(),
+            source: "/does/not/exist".into(),
+            target: "/data".into(),
+            options: vec!["rbind".into()],
+        };
+
+        // the tmpfs is unmounted after the bind mount fails
+        let rootfs = bundle.path().join("rootfs");
+        std::fs::create_dir(&rootfs).unwrap();
+        std::fs::write(rootfs.join("marker"), "").unwrap();
+        mount_rootfs(bundle.path(), &[tmpfs(""), missing]).unwrap_err();
+        assert!(rootfs.join("marker").exists());
+        assert!(!bundle.path().join(MOUNTS_FILE).exists());
+    }
+}
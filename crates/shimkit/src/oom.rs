@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use tokio::io::unix::AsyncFd;
+use tokio::task::AbortHandle;
+
+use crate::cgroups::{Cgroup, Hierarchy};
+use crate::event::EventPublisher;
+use crate::types::events::TaskOom;
+
+/// Watches the cgroups of containers, and publishes a `TaskOom` event
+/// every time a process in them is killed by the OOM killer.
+pub struct OomWatcher {
+    publisher: EventPublisher,
+    watches: Mutex<HashMap<String, AbortHandle>>,
+}
+
+impl OomWatcher {
+    pub fn new(publisher: EventPublisher) -> Self {
+        Self {
+            publisher,
+            watches: Default::default(),
+        }
+    }
+
+    fn watches(&self) -> MutexGuard<'_, HashMap<String, AbortHandle>> {
+        self.watches.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Starts watching the cgroup of container `id`.
+    /// The watch stops when the cgroup is removed, or with `unwatch`.
+    pub fn watch(&self, id: impl Into<String>, cgroup: &Cgroup) -> Result<()> {
+        let id = id.into();
+        let publisher = self.publisher.clone();
+        let handle = match cgroup.hierarchy() {
+            Hierarchy::V1 => {
+                let events = OomEventFd::register(&cgroup.dir("memory"))?;
+                tokio::spawn(events.run(id.clone(), publisher))
+            }
+            Hierarchy::V2 => {
+                let events = MemoryEvents::watch(cgroup.dir("").join("memory.events"))?;
+                tokio::spawn(events.run(id.clone(), publisher))
+            }
+        };
+        if let Some(previous) = self.watches().insert(id, handle.abort_handle()) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    /// Stops watching the cgroup of container `id`.
+    pub fn unwatch(&self, id: &str) {
+        if let Some(handle) = self.watches().remove(id) {
+            handle.abort();
+        }
+    }
+}
+
+impl Drop for OomWatcher {
+    fn drop(&mut self) {
+        for (_, handle) in self.watches().drain() {
+            handle.abort();
+        }
+    }
+}
+
+async fn publish(publisher: &EventPublisher, id: &str) {
+    let event = TaskOom {
+        container_id: id.into(),
+    };
+    if let Err(err) = publisher.publish(event).await {
+        log::warn!("failed to publish oom event for {id:?}: {err}");
+    }
+}
+
+fn nonblocking_fd(fd: i32) -> Result<AsyncFd<OwnedFd>> {
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // safe, since the fd was just created and we own it
+    AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// reads from the fd until it would block, returning whether anything was read
+fn drain(fd: &OwnedFd) -> Result<bool> {
+    let mut buf = [0u8; 4096];
+    let mut read = false;
+    loop {
+        let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n > 0 {
+            read = true;
+            continue;
+        }
+        if n == 0 {
+            return Ok(read);
+        }
+        let err = Error::last_os_error();
+        return match err.kind() {
+            ErrorKind::WouldBlock => Ok(read),
+            ErrorKind::Interrupted => continue,
+            _ => Err(err),
+        };
+    }
+}
+
+// cgroup v2: inotify on `memory.events`, checking the `oom_kill` counter on every change
+struct MemoryEvents {
+    path: PathBuf,
+    inotify: AsyncFd<OwnedFd>,
+    oom_kill: u64,
+}
+
+impl MemoryEvents {
+    fn watch(path: PathBuf) -> Result<Self> {
+        let inotify = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        let inotify = nonblocking_fd(inotify)?;
+
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let mask = libc::IN_MODIFY | libc::IN_DELETE_SELF;
+        let res = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), cpath.as_ptr(), mask) };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let oom_kill = oom_kill_count(&path)?.unwrap_or_default();
+        Ok(Self {
+            path,
+            inotify,
+            oom_kill,
+        })
+    }
+
+    async fn run(mut self, id: String, publisher: EventPublisher) {
+        loop {
+            let mut guard = match self.inotify.readable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    log::warn!("failed to watch oom events for {id:?}: {err}");
+                    return;
+                }
+            };
+            if let Err(err) = drain(guard.get_inner()) {
+                log::warn!("failed to watch oom events for {id:?}: {err}");
+                return;
+            }
+            guard.clear_ready();
+
+            let oom_kill = match oom_kill_count(&self.path) {
+                Ok(Some(oom_kill)) => oom_kill,
+                // the cgroup was removed
+                Ok(None) => return,
+                Err(err) => {
+                    log::warn!("failed to read {:?}: {err}", self.path);
+                    continue;
+                }
+            };
+            if oom_kill > self.oom_kill {
+                self.oom_kill = oom_kill;
+                publish(&publisher, &id).await;
+            }
+        }
+    }
+}
+
+fn oom_kill_count(path: &Path) -> Result<Option<u64>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let count = content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(key, _)| *key == "oom_kill")
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or_default();
+    Ok(Some(count))
+}
+
+// cgroup v1: an eventfd registered for `memory.oom_control` in `cgroup.event_control`
+struct OomEventFd {
+    dir: PathBuf,
+    eventfd: AsyncFd<OwnedFd>,
+    // kept open, as the notification is tied to this file
+    _oom_control: File,
+}
+
+impl OomEventFd {
+    fn register(dir: &Path) -> Result<Self> {
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        let eventfd = nonblocking_fd(eventfd)?;
+        let oom_control = File::open(dir.join("memory.oom_control"))?;
+
+        let registration = format!("{} {}", eventfd.as_raw_fd(), oom_control.as_raw_fd());
+        std::fs::write(dir.join("cgroup.event_control"), registration)?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            eventfd,
+            _oom_control: oom_control,
+        })
+    }
+
+    async fn run(self, id: String, publisher: EventPublisher) {
+        loop {
+            let mut guard = match self.eventfd.readable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    log::warn!("failed to watch oom events for {id:?}: {err}");
+                    return;
+                }
+            };
+            if let Err(err) = drain(guard.get_inner()) {
+                log::warn!("failed to watch oom events for {id:?}: {err}");
+                return;
+            }
+            guard.clear_ready();
+
+            // the eventfd is also signaled when the cgroup is removed
+            if !self.dir.join("memory.oom_control").exists() {
+                return;
+            }
+            publish(&publisher, &id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+    use crate::types::events::{Envelope, Events, ForwardRequest};
+
+    struct FakeEvents {
+        tx: UnboundedSender<Envelope>,
+    }
+
+    impl Events for FakeEvents {
+        async fn forward(&self, req: ForwardRequest) -> trapeze::Result<()> {
+            let _ = self.tx.send(req.envelope.unwrap_or_default());
+            Ok(())
+        }
+    }
+
+    fn watcher() -> (OomWatcher, UnboundedReceiver<Envelope>) {
+        let (tx, rx) = unbounded_channel();
+        let publisher = EventPublisher::new(FakeEvents { tx });
+        (OomWatcher::new(publisher), rx)
+    }
+
+    fn memory_events(oom_kill: u64) -> String {
+        format!("low 0\nhigh 0\nmax 0\noom {oom_kill}\noom_kill {oom_kill}\n")
+    }
+
+    async fn next_oom(events: &mut UnboundedReceiver<Envelope>) -> Option<String> {
+        let envelope = tokio::time::timeout(Duration::from_millis(500), events.recv())
+            .await
+            .ok()??;
+        assert_eq!(envelope.topic, "/tasks/oom");
+        let event: TaskOom = envelope.event.unwrap().to_msg().unwrap();
+        Some(event.container_id)
+    }
+
+    #[tokio::test]
+    async fn watch_v2() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("ctr");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(root.path().join("cgroup.controllers"), "memory\n").unwrap();
+        std::fs::write(dir.join("memory.events"), memory_events(1)).unwrap();
+
+        let (watcher, mut events) = watcher();
+        let cgroup = Cgroup::with_root(root.path(), "ctr");
+        watcher.watch("c1", &cgroup).unwrap();
+
+        // changes without new kills are ignored, including kills before the watch started
+        std::fs::write(dir.join("memory.events"), memory_events(1)).unwrap();
+        assert_eq!(next_oom(&mut events).await, None);
+
+        std::fs::write(dir.join("memory.events"), memory_events(2)).unwrap();
+        assert_eq!(next_oom(&mut events).await.as_deref(), Some("c1"));
+
+        watcher.unwatch("c1");
+        std::fs::write(dir.join("memory.events"), memory_events(3)).unwrap();
+        assert_eq!(next_oom(&mut events).await, None);
+    }
+
+    #[tokio::test]
+    async fn watch_v2_removed() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("ctr");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(root.path().join("cgroup.controllers"), "memory\n").unwrap();
+        std::fs::write(dir.join("memory.events"), memory_events(0)).unwrap();
+
+        let (watcher, mut events) = watcher();
+        watcher
+            .watch("c1", &Cgroup::with_root(root.path(), "ctr"))
+            .unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(next_oom(&mut events).await, None);
+    }
+
+    #[tokio::test]
+    async fn register_v1() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("memory").join("ctr");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("memory.oom_control"), "under_oom 0\n").unwrap();
+        std::fs::write(dir.join("cgroup.event_control"), "").unwrap();
+
+        let (watcher, _events) = watcher();
+        watcher
+            .watch("c1", &Cgroup::with_root(root.path(), "ctr"))
+            .unwrap();
+
+        let registration = std::fs::read_to_string(dir.join("cgroup.event_control")).unwrap();
+        let fds: Vec<i32> = registration
+            .split(' ')
+            .map(|fd| fd.parse().unwrap())
+            .collect();
+        assert_eq!(fds.len(), 2);
+        assert!(fds.iter().all(|fd| *fd > 2));
+    }
+}
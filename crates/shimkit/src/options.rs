@@ -0,0 +1,205 @@
+use prost::{Message as _, Name};
+use serde::de::DeserializeOwned;
+
+use crate::types::prost::Any;
+use crate::types::runc::Options as RuncOptions;
+use crate::types::runtimeoptions::Options as ConfigOptions;
+use crate::types::task::CreateTaskRequest;
+use crate::types::{Result, Status};
+
+// the name of `runtimeoptions.v1.Options` in containerd 1.x, where it was part of the CRI plugin
+const CRI_RUNTIME_OPTIONS: &str = "cri.runtimeoptions.v1.Options";
+
+/// Returns the full name of the type held by `any`, e.g., `runtime.v1.PodSandboxConfig`.
+///
+/// Some clients don't add the required slash to the type url, so anything
+/// up to the last slash is dropped, if there is one.
+pub fn type_name(any: &Any) -> &str {
+    any.type_url.rsplit('/').next().unwrap_or_default()
+}
+
+/// Decodes `any` as a `T`, or returns `None` if it holds another type.
+pub fn unpack<T: Name + Default>(any: &Any) -> Result<Option<T>> {
+    if type_name(any) != T::full_name() {
+        return Ok(None);
+    }
+    let msg = T::decode(any.value.as_slice())
+        .map_err(|err| Status::invalid_argument(format!("invalid {}: {err}", T::full_name())))?;
+    Ok(Some(msg))
+}
+
+/// The runtime options of a container, set by containerd in `CreateTaskRequest.options`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeOptions {
+    /// The runc shim options, from the `options` of the runtime in the containerd config.
+    Runc(RuncOptions),
+    /// The CRI runtime options, pointing to a runtime specific TOML config.
+    Config(ConfigOptions),
+}
+
+impl RuntimeOptions {
+    /// Decodes the runtime options in `any`, which can be either form.
+    pub fn from_any(any: &Any) -> Result<Self> {
+        if let Some(options) = unpack(any)? {
+            return Ok(Self::Runc(options));
+        }
+        let name = type_name(any);
+        if name == ConfigOptions::full_name() || name == CRI_RUNTIME_OPTIONS {
+            let options = ConfigOptions::decode(any.value.as_slice()).map_err(|err| {
+                Status::invalid_argument(format!("invalid runtime options: {err}"))
+            })?;
+            return Ok(Self::Config(options));
+        }
+        Err(Status::invalid_argument(format!(
+            "unsupported runtime options type {:?}",
+            any.type_url
+        )))
+    }
+
+    /// Decodes the runtime options of the container created with `req`, if it has any.
+    pub fn from_request(req: &CreateTaskRequest) -> Result<Option<Self>> {
+        req.options.as_ref().map(Self::from_any).transpose()
+    }
+
+    /// Returns the runc shim options, if these are.
+    pub fn runc(&self) -> Option<&RuncOptions> {
+        match self {
+            Self::Runc(options) => Some(options),
+            Self::Config(_) => None,
+        }
+    }
+
+    /// Deserializes the TOML config of the runtime, read from `config_path`
+    /// or, if it's not set, from `config_body`.
+    /// Returns `None` for runc shim options, or if neither is set.
+    pub fn config<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        let Self::Config(options) = self else {
+            return Ok(None);
+        };
+
+        let body = if !options.config_path.is_empty() {
+            let path = &options.config_path;
+            std::fs::read_to_string(path).map_err(|err| {
+                Status::invalid_argument(format!("failed to read runtime config {path:?}: {err}"))
+            })?
+        } else if !options.config_body.is_empty() {
+            String::from_utf8(options.config_body.clone())
+                .map_err(|err| Status::invalid_argument(format!("invalid runtime config: {err}")))?
+        } else {
+            return Ok(None);
+        };
+
+        let config = toml::from_str(&body)
+            .map_err(|err| Status::invalid_argument(format!("invalid runtime config: {err}")))?;
+        Ok(Some(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::types::Code;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        debug: bool,
+        kernel: String,
+    }
+
+    fn any(type_url: &str, msg: &impl prost::Message) -> Any {
+        Any {
+            type_url: type_url.into(),
+            value: msg.encode_to_vec(),
+        }
+    }
+
+    #[test]
+    fn unpack_without_slash() {
+        let options = RuncOptions {
+            binary_name: "crun".into(),
+            ..Default::default()
+        };
+        for type_url in [
+            "containerd.runc.v1.Options",
+            "/containerd.runc.v1.Options",
+            "type.googleapis.com/containerd.runc.v1.Options",
+        ] {
+            let unpacked: RuncOptions = unpack(&any(type_url, &options)).unwrap().unwrap();
+            assert_eq!(unpacked, options);
+        }
+
+        let other = any("/runtimeoptions.v1.Options", &options);
+        assert_eq!(unpack::<RuncOptions>(&other).unwrap(), None);
+    }
+
+    #[test]
+    fn runc_options() {
+        let options = RuncOptions {
+            systemd_cgroup: true,
+            ..Default::default()
+        };
+        let req = CreateTaskRequest {
+            options: Some(any("/containerd.runc.v1.Options", &options)),
+            ..Default::default()
+        };
+        let decoded = RuntimeOptions::from_request(&req).unwrap().unwrap();
+        assert!(decoded.runc().unwrap().systemd_cgroup);
+        assert_eq!(decoded.config::<Config>().unwrap(), None);
+
+        let req = CreateTaskRequest::default();
+        assert_eq!(RuntimeOptions::from_request(&req).unwrap(), None);
+    }
+
+    #[test]
+    fn config_options() {
+        let expected = Config {
+            debug: true,
+            kernel: "vmlinux".into(),
+        };
+        let body = "debug = true\nkernel = \"vmlinux\"\n";
+
+        let options = ConfigOptions {
+            config_body: body.into(),
+            ..Default::default()
+        };
+        let decoded = RuntimeOptions::from_any(&any(CRI_RUNTIME_OPTIONS, &options)).unwrap();
+        assert_eq!(decoded.runc(), None);
+        assert_eq!(decoded.config::<Config>().unwrap().unwrap(), expected);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, body).unwrap();
+        let options = ConfigOptions {
+            config_path: path.to_str().unwrap().into(),
+            config_body: b"invalid".to_vec(),
+            ..Default::default()
+        };
+        let decoded = RuntimeOptions::from_any(&any("/runtimeoptions.v1.Options", &options));
+        assert_eq!(
+            decoded.unwrap().config::<Config>().unwrap().unwrap(),
+            expected
+        );
+
+        let decoded = RuntimeOptions::Config(ConfigOptions::default());
+        assert_eq!(decoded.config::<Config>().unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_options() {
+        let other = Any {
+            type_url: "/some.other.Type".into(),
+            value: vec![],
+        };
+        let err = RuntimeOptions::from_any(&other).unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let options = RuntimeOptions::Config(ConfigOptions {
+            config_body: b"debug = ".to_vec(),
+            ..Default::default()
+        });
+        let err = options.config::<Config>().unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+}
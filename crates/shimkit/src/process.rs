@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::process::{Child, Command};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock, RwLock};
+use std::thread;
+use std::time::SystemTime;
+
+use libc::{c_int, id_t, idtype_t, siginfo_t, CLD_EXITED, P_ALL, P_PID, WEXITED, WNOHANG, WNOWAIT};
+use serde::{Deserialize, Serialize};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::{mpsc, oneshot};
+
+/// Exit information of a reaped process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exit {
+    // the pid of the process
+    pub pid: u32,
+
+    // the exit code of the process, or 128 + the signal number if it was killed by a signal
+    pub status: u32,
+
+    // the time when the process was reaped
+    pub timestamp: SystemTime,
+}
+
+/// Marks the current process as a child subreaper.
+/// Orphaned descendants will be re-parented to this process instead of init,
+/// so that the `Monitor` can collect their exit status.
+pub fn set_subreaper() -> Result<()> {
+    let res = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Waits for a process that is not a child of the shim to exit, e.g., one re-adopted
+/// after the shim restarted, see `task::TaskService::restore`.
+/// Unlike `Monitor::wait`, this can't collect the exit status of the process.
+pub async fn wait_pidfd(pid: u32) -> Result<()> {
+    match Pidfd::open(pid)? {
+        Some(pidfd) => pidfd.exited().await,
+        // the process is already gone
+        None => Ok(()),
+    }
+}
+
+/// A file descriptor referring to a process, see `pidfd_open(2)`.
+///
+/// Unlike its pid, it keeps referring to the same process once it has exited,
+/// so signaling through it can't hit another process that reused the pid.
+pub struct Pidfd(OwnedFd);
+
+impl Pidfd {
+    /// Opens a pidfd for the process `pid`, or returns `None` if there is no such process.
+    /// Pids that don't fit a `pid_t`, or that would designate a process group, are invalid.
+    pub fn open(pid: u32) -> Result<Option<Self>> {
+        let pid = match libc::pid_t::try_from(pid) {
+            Ok(pid) if pid > 0 => pid,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid pid {pid}"),
+                ))
+            }
+        };
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            let err = Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ESRCH) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        // safe, since pidfd_open returned a new fd
+        Ok(Some(Self(unsafe { OwnedFd::from_raw_fd(fd as c_int) })))
+    }
+
+    /// Sends `signal` to the process, returning `false` if it has already exited.
+    pub fn signal(&self, signal: c_int) -> Result<bool> {
+        let fd = self.0.as_raw_fd();
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                fd,
+                signal,
+                std::ptr::null::<siginfo_t>(),
+                0,
+            )
+        };
+        if res < 0 {
+            let err = Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ESRCH) => Ok(false),
+                _ => Err(err),
+            };
+        }
+        Ok(true)
+    }
+
+    /// Waits for the process to exit, without reaping it.
+    pub async fn exited(self) -> Result<()> {
+        // the pidfd becomes readable once the process exits
+        let fd = AsyncFd::with_interest(self.0, Interest::READABLE)?;
+        let _ready = fd.readable().await?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // incremented every time a child is spawned, used to wake up the reaper
+    generation: u64,
+    exits: HashMap<u32, Exit>,
+    waiters: HashMap<u32, Vec<oneshot::Sender<Exit>>>,
+    subscribers: Vec<mpsc::UnboundedSender<Exit>>,
+}
+
+/// Process-wide supervisor that reaps every child of the shim and fans out their exit status.
+///
+/// The monitor reaps *all* children of the process, so children must not be waited on
+/// through other means (e.g., `Child::wait` or `tokio::process`). Use `Monitor::spawn`
+/// to start processes, and `Monitor::wait` or `Monitor::subscribe` to observe their exit.
+pub struct Monitor {
+    state: Mutex<State>,
+    children: Condvar,
+    // held for reading while spawning, and for writing while reaping
+    spawning: RwLock<()>,
+}
+
+static MONITOR: OnceLock<Monitor> = OnceLock::new();
+
+/// Returns the process-wide `Monitor`, starting the reaper thread on first use.
+pub fn monitor() -> &'static Monitor {
+    let mut init = false;
+    let monitor = MONITOR.get_or_init(|| {
+        init = true;
+        Monitor {
+            state: Default::default(),
+            children: Condvar::new(),
+            spawning: RwLock::new(()),
+        }
+    });
+    if init {
+        thread::Builder::new()
+            .name("shimkit-reaper".into())
+            .spawn(|| monitor.reap())
+            .expect("Failed to start the reaper thread");
+    }
+    monitor
+}
+
+/// Returns the process-wide `Monitor` if its reaper thread has already been started,
+/// without starting it.
+pub fn started_monitor() -> Option<&'static Monitor> {
+    MONITOR.get()
+}
+
+/// A stream of the exit status of every process reaped by the `Monitor`.
+pub struct Subscription {
+    rx: mpsc::UnboundedReceiver<Exit>,
+}
+
+impl Subscription {
+    /// Receives the next exit. Exits that happened before subscribing are not received.
+    pub async fn recv(&mut self) -> Option<Exit> {
+        self.rx.recv().await
+    }
+}
+
+impl Monitor {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Spawns a new child process.
+    /// The returned `Child` must not be waited on, use `Monitor::wait` instead.
+    pub fn spawn(&self, cmd: &mut Command) -> Result<Child> {
+        let child = {
+            let _guard = self.spawning.read().unwrap_or_else(|err| err.into_inner());
+            cmd.spawn()?
+        };
+        self.notify();
+        Ok(child)
+    }
+
+    /// Wakes up the reaper after a child was started outside of `Monitor::spawn`.
+    pub fn notify(&self) {
+        let mut state = self.lock();
+        state.generation = state.generation.wrapping_add(1);
+        self.children.notify_all();
+    }
+
+    /// Subscribes to the exit status of every process reaped from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.lock().subscribers.push(tx);
+        Subscription { rx }
+    }
+
+    /// Waits for the process with the given pid to exit.
+    /// If the process has already been reaped, its recorded exit is returned immediately.
+    pub async fn wait(&self, pid: u32) -> Exit {
+        let rx = {
+            let mut state = self.lock();
+            if let Some(exit) = state.exits.get(&pid) {
+                return *exit;
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters.entry(pid).or_default().push(tx);
+            rx
+        };
+        // the reaper only drops a waiter after sending the exit through it
+        rx.await.expect("Reaper dropped an exit waiter")
+    }
+
+    /// Returns the recorded exit of the process with the given pid, if it has been reaped.
+    pub fn try_wait(&self, pid: u32) -> Option<Exit> {
+        self.lock().exits.get(&pid).copied()
+    }
+
+    /// Forgets the recorded exit of the process with the given pid.
+    /// This should be called once the process has been deleted, as pids can be reused.
+    pub fn forget(&self, pid: u32) {
+        self.lock().exits.remove(&pid);
+    }
+
+    fn record(&self, exit: Exit) {
+        let mut state = self.lock();
+        state.exits.insert(exit.pid, exit);
+        for waiter in state.waiters.remove(&exit.pid).unwrap_or_default() {
+            let _ = waiter.send(exit);
+        }
+        state.subscribers.retain(|tx| tx.send(exit).is_ok());
+    }
+
+    fn reap(&self) {
+        loop {
+            let generation = self.lock().generation;
+
+            // Peek at the next zombie without reaping it, so that a concurrent
+            // `Command::spawn` can still reap its own child if `exec` fails.
+            let pid = match waitid(P_ALL, 0, WEXITED | WNOWAIT) {
+                Ok(Some(exit)) => exit.pid,
+                Ok(None) => continue,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    // no children left, wait until a new one is spawned
+                    let state = self.lock();
+                    let _state = self
+                        .children
+                        .wait_while(state, |state| state.generation == generation)
+                        .unwrap_or_else(|err| err.into_inner());
+                    continue;
+                }
+            };
+
+            let _guard = self.spawning.write().unwrap_or_else(|err| err.into_inner());
+            if let Ok(Some(exit)) = waitid(P_PID, pid as id_t, WEXITED | WNOHANG) {
+                self.record(exit);
+            }
+        }
+    }
+}
+
+fn waitid(idtype: idtype_t, id: id_t, options: c_int) -> Result<Option<Exit>> {
+    let mut info = MaybeUninit::<siginfo_t>::zeroed();
+    let res = unsafe { libc::waitid(idtype, id, info.as_mut_ptr(), options) };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // safe, since waitid succeeded and we zero initialized the struct
+    let info = unsafe { info.assume_init() };
+    let pid = unsafe { info.si_pid() };
+    if pid == 0 {
+        // WNOHANG was specified and the process hasn't exited yet
+        return Ok(None);
+    }
+
+    let status = unsafe { info.si_status() } as u32;
+    let status = match info.si_code {
+        CLD_EXITED => status,
+        // CLD_KILLED or CLD_DUMPED, follow the shell convention for signals
+        _ => 128 + status,
+    };
+
+    Ok(Some(Exit {
+        pid: pid as u32,
+        status,
+        timestamp: SystemTime::now(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+    use std::process::Stdio;
+
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    #[tokio::test]
+    async fn wait_exit_code() {
+        let child = monitor().spawn(&mut sh("exit 3")).unwrap();
+        let exit = monitor().wait(child.id()).await;
+        assert_eq!(exit.pid, child.id());
+        assert_eq!(exit.status, 3);
+    }
+
+    #[tokio::test]
+    async fn wait_signal() {
+        let child = monitor().spawn(&mut sh("kill -9 $$")).unwrap();
+        let exit = monitor().wait(child.id()).await;
+        assert_eq!(exit.status, 128 + 9);
+    }
+
+    #[tokio::test]
+    async fn wait_after_exit() {
+        let child = monitor().spawn(&mut sh("exit 0")).unwrap();
+        let first = monitor().wait(child.id()).await;
+        let second = monitor().wait(child.id()).await;
+        assert_eq!(first, second);
+        assert_eq!(monitor().try_wait(child.id()), Some(first));
+
+        monitor().forget(child.id());
+        assert_eq!(monitor().try_wait(child.id()), None);
+    }
+
+    #[tokio::test]
+    async fn wait_multiple_waiters() {
+        let child = monitor().spawn(&mut sh("sleep 0.1; exit 5")).unwrap();
+        let pid = child.id();
+        let (a, b) = tokio::join!(monitor().wait(pid), monitor().wait(pid));
+        assert_eq!(a, b);
+        assert_eq!(a.status, 5);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let mut subscription = monitor().subscribe();
+        let child = monitor().spawn(&mut sh("exit 7")).unwrap();
+        loop {
+            let exit = subscription.recv().await.unwrap();
+            if exit.pid == child.id() {
+                assert_eq!(exit.status, 7);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn_failure() {
+        let mut cmd = Command::new("/path/to/non/existing/binary");
+        assert!(monitor().spawn(&mut cmd).is_err());
+
+        // force the fork/exec code path, where std reaps the failed child itself
+        let mut cmd = Command::new("/path/to/non/existing/binary");
+        unsafe { std::os::unix::process::CommandExt::pre_exec(&mut cmd, || Ok(())) };
+        assert!(monitor().spawn(&mut cmd).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_non_child() {
+        let child = monitor().spawn(&mut sh("sleep 0.1")).unwrap();
+        let pid = child.id();
+        wait_pidfd(pid).await.unwrap();
+        assert_eq!(monitor().wait(pid).await.status, 0);
+        monitor().forget(pid);
+
+        // the process is gone already
+        wait_pidfd(pid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn signal_pidfd() {
+        for pid in [0, u32::MAX, i32::MAX as u32 + 1] {
+            let err = Pidfd::open(pid).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        let child = monitor().spawn(&mut sh("sleep 10")).unwrap();
+        let pid = child.id();
+        let pidfd = Pidfd::open(pid).unwrap().unwrap();
+        assert!(pidfd.signal(libc::SIGKILL).unwrap());
+        assert_eq!(monitor().wait(pid).await.status, 137);
+        monitor().forget(pid);
+
+        // the pidfd still refers to the reaped process
+        assert!(!pidfd.signal(libc::SIGKILL).unwrap());
+    }
+
+    #[tokio::test]
+    async fn reap_orphans() {
+        set_subreaper().unwrap();
+
+        let mut child = monitor()
+            .spawn(sh("sleep 0.1 & echo $!").stdout(Stdio::piped()))
+            .unwrap();
+
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        let orphan: u32 = output.trim().parse().unwrap();
+
+        let exit = monitor().wait(orphan).await;
+        assert_eq!(exit.pid, orphan);
+        assert_eq!(exit.status, 0);
+    }
+}
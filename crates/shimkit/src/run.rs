@@ -1,15 +1,47 @@
 use std::env::{current_dir, current_exe};
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{copy, stderr, stdout, IsTerminal as _, Result as IoResult, Write as _};
+use std::io::{
+    stderr, stdout, BufRead as _, BufReader, IsTerminal as _, Read, Result as IoResult, Write,
+};
+#[cfg(unix)]
+use std::io::{stdin, Error as IoError, ErrorKind};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd as _, RawFd};
 use std::process::{exit, Command, Stdio, Termination};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+#[cfg(unix)]
+use prost::Message as _;
 
 use crate::args::Arguments;
 use crate::fs::{dev_null, FileEx as _};
+#[cfg(target_os = "linux")]
+use crate::options::unpack;
 use crate::stdio::Duplicate as _;
+#[cfg(unix)]
+use crate::types::prost::Any;
+#[cfg(target_os = "linux")]
+use crate::types::runc::Options as RuncOptions;
 
-fn open_log() -> IoResult<File> {
+/// How long the `start` launcher waits for the daemon to report its address.
+pub const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+// set by the launcher in the environment of the daemon, which then keeps reporting
+// its errors through the stderr read by the launcher until it has bootstrapped
+pub(crate) const LAUNCHER_ENV: &str = "SHIMKIT_LAUNCHER";
+
+// how long to wait for the stderr of the daemon to be drained, once it has bootstrapped or failed
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// how long the daemon waits for containerd to close its stdin, once it has written the options
+#[cfg(unix)]
+const OPTIONS_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) fn open_log() -> IoResult<File> {
     // try with the log file
     if let Ok(file) = File::append("log") {
         return Ok(file);
@@ -24,41 +56,682 @@ fn open_log() -> IoResult<File> {
     dev_null()
 }
 
+/// How the daemon detaches from containerd before running user code, like containerd's
+/// own shims do. Every step is enabled by default, and only applies to a daemon spawned
+/// by the `start` launcher, not to one running interactively.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct Hardening {
+    // whether to start a new session, detaching from the process group of containerd
+    setsid: bool,
+
+    // whether to move into the `shim_cgroup` of the runc options, if it's set
+    shim_cgroup: bool,
+
+    // the adjustment to the oom_score_adj inherited from containerd
+    oom_score_adj: Option<i32>,
+
+    // the file mode creation mask
+    umask: Option<u32>,
+
+    // whether to close the file descriptors inherited from containerd, besides stdio
+    close_fds: bool,
+}
+
+impl Default for Hardening {
+    fn default() -> Self {
+        Self {
+            setsid: true,
+            shim_cgroup: true,
+            oom_score_adj: Some(1),
+            umask: Some(0o022),
+            close_fds: true,
+        }
+    }
+}
+
+impl Hardening {
+    /// Returns a configuration where every step is disabled.
+    pub fn none() -> Self {
+        Self {
+            setsid: false,
+            shim_cgroup: false,
+            oom_score_adj: None,
+            umask: None,
+            close_fds: false,
+        }
+    }
+
+    pub fn with_setsid(mut self, setsid: bool) -> Self {
+        self.setsid = setsid;
+        self
+    }
+
+    /// Sets whether to move into `Options.shim_cgroup`, from the runc options
+    /// containerd passes to the `start` action, see `Arguments::runtime_options`.
+    pub fn with_shim_cgroup(mut self, shim_cgroup: bool) -> Self {
+        self.shim_cgroup = shim_cgroup;
+        self
+    }
+
+    /// Sets the value added to the `oom_score_adj` inherited from containerd.
+    /// Defaults to 1, so that the shim is killed before containerd, but after its containers,
+    /// which usually have a higher score. Lowering the score requires `CAP_SYS_RESOURCE`.
+    pub fn with_oom_score_adj(mut self, adj: Option<i32>) -> Self {
+        self.oom_score_adj = adj;
+        self
+    }
+
+    pub fn with_umask(mut self, umask: Option<u32>) -> Self {
+        self.umask = umask;
+        self
+    }
+
+    pub fn with_close_fds(mut self, close_fds: bool) -> Self {
+        self.close_fds = close_fds;
+        self
+    }
+
+    // applies the steps to the current process, keeping the `keep` file descriptors open
+    #[cfg(unix)]
+    fn apply(&self, runtime_options: Option<&Any>, keep: &[RawFd]) -> anyhow::Result<()> {
+        if self.setsid && unsafe { libc::setsid() } < 0 {
+            let err = IoError::last_os_error();
+            // the process is already a group leader, so it's already detached
+            if err.raw_os_error() != Some(libc::EPERM) {
+                return Err(err).context("failed to start a new session");
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.shim_cgroup {
+            let options = runtime_options.and_then(|any| unpack::<RuncOptions>(any).ok()?);
+            if let Some(path) = options.map(|o| o.shim_cgroup).filter(|p| !p.is_empty()) {
+                crate::cgroups::Cgroup::new(&path)
+                    .add_process(std::process::id())
+                    .with_context(|| format!("failed to move into the shim cgroup {path:?}"))?;
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(adj) = self.oom_score_adj {
+            adjust_oom_score(adj).context("failed to adjust the oom score")?;
+        }
+
+        if let Some(umask) = self.umask {
+            unsafe { libc::umask(umask as libc::mode_t) };
+        }
+
+        if self.close_fds {
+            close_fds(keep).context("failed to close the inherited file descriptors")?;
+        }
+
+        Ok(())
+    }
+}
+
+// the file to adjust the oom score of the current process, in [-1000, 1000]
+#[cfg(target_os = "linux")]
+const OOM_SCORE_ADJ: &str = "/proc/self/oom_score_adj";
+
+#[cfg(target_os = "linux")]
+fn adjust_oom_score(adj: i32) -> IoResult<()> {
+    let current = std::fs::read_to_string(OOM_SCORE_ADJ)?;
+    let current: i32 = current
+        .trim()
+        .parse()
+        .map_err(|err| IoError::new(ErrorKind::InvalidData, format!("invalid oom score: {err}")))?;
+    let score = current.saturating_add(adj).clamp(-1000, 1000);
+    std::fs::write(OOM_SCORE_ADJ, score.to_string())
+}
+
+// closes every file descriptor but stdio and `keep`
+#[cfg(unix)]
+fn close_fds(keep: &[RawFd]) -> IoResult<()> {
+    let dir = match cfg!(target_os = "linux") {
+        true => "/proc/self/fd",
+        false => "/dev/fd",
+    };
+    // collect them first, as listing the directory opens a file descriptor too
+    let fds: Vec<RawFd> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    for fd in fds {
+        if fd > 2 && !keep.contains(&fd) {
+            unsafe { libc::close(fd) };
+        }
+    }
+    Ok(())
+}
+
+// reads the runtime options containerd writes on the stdin of the `start` action,
+// for up to `OPTIONS_TIMEOUT` if the stdin isn't closed, as when the launcher is started by hand
+#[cfg(unix)]
+fn read_runtime_options() -> IoResult<Option<Any>> {
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd as _;
+    use std::time::Instant;
+
+    if stdin().is_terminal() {
+        return Ok(None);
+    }
+    // read the stdin fd directly, as the buffered `Stdin` could hold data `poll` doesn't see
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+    let deadline = Instant::now() + OPTIONS_TIMEOUT;
+    let mut options = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut fds = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) } {
+            -1 if IoError::last_os_error().kind() == ErrorKind::Interrupted => continue,
+            -1 => return Err(IoError::last_os_error()),
+            0 => break,
+            _ => {}
+        }
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => options.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    // don't keep the pipe from containerd open
+    unsafe { dev_null()?.duplicate_to_fd(libc::STDIN_FILENO)? };
+    if options.is_empty() {
+        return Ok(None);
+    }
+    let options =
+        Any::decode(options.as_slice()).map_err(|err| IoError::new(ErrorKind::InvalidData, err))?;
+    Ok(Some(options))
+}
+
 /// Shim entry point that must be invoked from `main`.
+/// The daemon is hardened with the default `Hardening`.
 pub fn run<T: Termination>(f: impl FnOnce(Arguments) -> T) -> anyhow::Result<T> {
+    run_with(Hardening::default(), f)
+}
+
+/// Shim entry point that must be invoked from `main`, like `run`,
+/// hardening the daemon with `hardening`.
+pub fn run_with<T: Termination>(
+    hardening: Hardening,
+    f: impl FnOnce(Arguments) -> T,
+) -> anyhow::Result<T> {
     let arguments = Arguments::parse_env()?;
 
     match arguments.action.as_str() {
         "start" => {
             // This is the daemon launcher, re-spawn itself as a daemon
-            let cmd = current_exe()?;
-            let cwd = current_dir()?;
-
-            let mut child = Command::new(cmd)
-                .current_dir(cwd)
-                .stdin(Stdio::null())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::null())
-                .args(arguments.to_args_vec("daemon".as_ref()))
-                .spawn()?;
+            let cmd = daemon_command(&arguments, current_exe()?)?;
 
-            // safe, since we piped stdout
-            let mut output = child.stdout.take().unwrap();
+            let log = open_log().context("failed to allocate a sink for the daemon stderr")?;
+            let address = launch(cmd, log, START_TIMEOUT)?;
 
-            copy(&mut output, &mut stdout())?;
+            stdout().write_all(address.as_bytes())?;
             stdout().flush()?;
 
             exit(0);
         }
-        _ => {
-            // This is the daemon
+        _ => daemon(arguments, hardening, f),
+    }
+}
+
+// the command the `start` launcher re-spawns the shim `program` with, as a daemon
+fn daemon_command(arguments: &Arguments, program: impl AsRef<OsStr>) -> IoResult<Command> {
+    let mut cmd = arguments.to_command(program, "daemon");
+    cmd.current_dir(current_dir()?).env(LAUNCHER_ENV, "1");
+    Ok(cmd)
+}
+
+// runs `f` in the daemon, or for any other action than `start`
+fn daemon<T: Termination>(
+    #[allow(unused_mut)] mut arguments: Arguments,
+    hardening: Hardening,
+    f: impl FnOnce(Arguments) -> T,
+) -> anyhow::Result<T> {
+    // Before handing over control to user code, redirect stdout/stderr.
+    // When spawned by the launcher, stderr is redirected once the daemon has bootstrapped.
+    let log = open_log().context("failed to allocate a sink for stdout")?;
+    log.duplicate_to_stdout()?;
+    if !arguments.launched {
+        log.duplicate_to_stderr()?;
+    }
+    drop(log);
+    // don't leak the launcher variable to the processes spawned by the daemon
+    std::env::remove_var(LAUNCHER_ENV);
+
+    #[cfg(unix)]
+    if arguments.launched {
+        arguments.runtime_options =
+            read_runtime_options().context("failed to read the runtime options")?;
+        let keep = [arguments.stdout.as_raw_fd()];
+        hardening.apply(arguments.runtime_options.as_ref(), &keep)?;
+    }
+    #[cfg(not(unix))]
+    let _ = hardening;
+
+    // Become a subreaper so that orphaned container processes are re-parented to us
+    #[cfg(target_os = "linux")]
+    if arguments.action == "daemon" {
+        crate::process::set_subreaper().context("failed to become a child subreaper")?;
+    }
+
+    Ok(f(arguments))
+}
+
+/// Spawns the daemon with `cmd`, and waits up to `timeout` for it to report its address.
+/// The stderr of the daemon is forwarded to `log`, and is part of the returned error
+/// if the daemon fails or doesn't report its address in time.
+fn launch(
+    mut cmd: Command,
+    mut log: impl Write + Send + 'static,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    // the daemon reads the runtime options containerd writes on stdin
+    let mut child = cmd
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to spawn the daemon")?;
+
+    // safe, since we piped stdout and stderr
+    let stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let (drained, forwarded) = mpsc::channel();
+    {
+        let captured = Arc::clone(&captured);
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = stderr.read(&mut buf) {
+                let _ = log.write_all(&buf[..n]);
+                let mut captured = captured.lock().unwrap_or_else(|err| err.into_inner());
+                captured.extend_from_slice(&buf[..n]);
+            }
+            let _ = drained.send(());
+        });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let res = BufReader::new(stdout).read_line(&mut line).map(|_| line);
+        let _ = tx.send(res);
+    });
+
+    let failure = match rx.recv_timeout(timeout) {
+        Ok(Ok(line)) if !line.trim().is_empty() => {
+            let _ = forwarded.recv_timeout(DRAIN_TIMEOUT);
+            return Ok(line);
+        }
+        Ok(Ok(_)) => "exited before reporting its address".to_string(),
+        Ok(Err(err)) => format!("failed to report its address: {err}"),
+        Err(_) => {
+            let _ = child.kill();
+            format!("did not report its address within {timeout:?}")
+        }
+    };
+
+    // the daemon may have been reaped by someone else, e.g., a `process::Monitor`
+    let status = match child.wait() {
+        Ok(status) => format!(" ({status})"),
+        Err(_) => String::new(),
+    };
+
+    let _ = forwarded.recv_timeout(DRAIN_TIMEOUT);
+    let captured = captured.lock().unwrap_or_else(|err| err.into_inner());
+    let output = String::from_utf8_lossy(&captured);
+    match output.trim() {
+        "" => bail!("shim daemon {failure}{status}"),
+        output => bail!("shim daemon {failure}{status}: {output}"),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Seek as _, SeekFrom};
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::path::Path;
+
+    use super::*;
+    use crate::types::sandbox::Sandbox;
+    use crate::types::task::Task;
+
+    // set when the hardening test runs again in a child process
+    #[cfg(target_os = "linux")]
+    const HARDENED_VAR: &str = "SHIMKIT_TEST_HARDENED";
+
+    // set when the test reading the options from an open stdin runs again in a child process
+    #[cfg(target_os = "linux")]
+    const OPEN_STDIN_VAR: &str = "SHIMKIT_TEST_OPEN_STDIN";
+
+    // the arguments of the shim, set when a launcher test runs again as the daemon
+    const SHIM_ARGS_VAR: &str = "SHIMKIT_TEST_SHIM_ARGS";
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    fn launch_sh(script: &str, timeout: Duration) -> (anyhow::Result<String>, String) {
+        launch_cmd(sh(script), timeout)
+    }
+
+    fn launch_cmd(cmd: Command, timeout: Duration) -> (anyhow::Result<String>, String) {
+        let mut log = tempfile::tempfile().unwrap();
+        let res = launch(cmd, log.try_clone().unwrap(), timeout);
+
+        let mut logged = String::new();
+        log.seek(SeekFrom::Start(0)).unwrap();
+        log.read_to_string(&mut logged).unwrap();
+        (res, logged)
+    }
+
+    #[test]
+    fn launch_daemon() {
+        let script = "echo starting >&2; echo unix:///run/shim.sock; exec 2>/dev/null";
+        let (res, logged) = launch_sh(script, START_TIMEOUT);
+        assert_eq!(res.unwrap(), "unix:///run/shim.sock\n");
+        assert_eq!(logged, "starting\n");
+    }
+
+    #[test]
+    fn launch_failing_daemon() {
+        let script = "echo 'Error: failed to bind' >&2; exit 3";
+        let (res, logged) = launch_sh(script, START_TIMEOUT);
+        let err = res.unwrap_err().to_string();
+        assert!(err.starts_with("shim daemon exited before reporting its address"));
+        assert!(err.ends_with(": Error: failed to bind"), "{err}");
+        assert_eq!(logged, "Error: failed to bind\n");
+    }
+
+    #[test]
+    fn launch_stuck_daemon() {
+        let script = "echo waiting >&2; exec sleep 10";
+        let (res, logged) = launch_sh(script, Duration::from_millis(200));
+        let err = res.unwrap_err().to_string();
+        assert!(err.starts_with("shim daemon did not report its address within 200ms"));
+        assert!(err.ends_with(": waiting"), "{err}");
+        assert_eq!(logged, "waiting\n");
+    }
+
+    struct Server;
+
+    impl Task for Server {}
+
+    impl Sandbox for Server {}
+
+    // Launches this test binary as a shim, which runs the test `name` again as the daemon,
+    // serving on `socket` (see `shim_daemon`), and returns the launch result, the stderr
+    // forwarded by the launcher, and the log of the daemon.
+    fn launch_shim(name: &str, socket: &Path) -> (anyhow::Result<String>, String, String) {
+        let dir = socket.parent().unwrap().parent().unwrap();
+
+        // the shim arguments are passed in the environment, as the test harness rejects them,
+        // and the stdout read by the launcher is moved out of the way of the harness output
+        let shim = dir.join("shim");
+        let exe = std::env::current_exe().unwrap();
+        let script = format!(
+            "#!/bin/sh\n\
+             export {SHIM_ARGS_VAR}=\"$(printf '%s\\n' \"$@\")\"\n\
+             exec 3>&1 >/dev/null </dev/null\n\
+             exec '{exe}' --exact {name} --nocapture --test-threads=1\n",
+            exe = exe.display(),
+        );
+        std::fs::write(&shim, script).unwrap();
+        std::fs::set_permissions(&shim, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // the log is created by containerd, usually as a fifo
+        let log = dir.join("log");
+        std::fs::write(&log, "").unwrap();
+
+        let arguments = Arguments {
+            id: "c1".into(),
+            namespace: "ns".into(),
+            action: "start".into(),
+            rest: vec![socket.display().to_string()],
+            ..Default::default()
+        };
+        let mut cmd = daemon_command(&arguments, &shim).unwrap();
+        cmd.current_dir(dir);
+        let (res, logged) = launch_cmd(cmd, START_TIMEOUT);
+
+        // a launched daemon logs until it exits
+        let deadline = std::time::Instant::now() + START_TIMEOUT;
+        let mut daemon_log = String::new();
+        while res.is_ok() && std::time::Instant::now() < deadline {
+            daemon_log = std::fs::read_to_string(&log).unwrap_or_default();
+            if daemon_log.contains("exiting") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        (res, logged, daemon_log)
+    }
+
+    // runs in the daemon launched by `launch_shim`, like the main of a shim
+    fn shim_daemon(args: String) -> ! {
+        // restore the stdout read by the launcher
+        unsafe {
+            libc::dup2(3, libc::STDOUT_FILENO);
+            libc::close(3);
+        }
+        let arguments = Arguments::parse_from(args.lines(), std::env::vars()).unwrap();
+        let res = daemon(arguments, Hardening::default(), |arguments| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                eprintln!("starting");
+                let socket = arguments.rest[0].clone();
+                let handle = arguments.serve(socket, Server).await?;
+                eprintln!("serving");
+                handle.shutdown();
+                handle.await?;
+                eprintln!("exiting");
+                anyhow::Ok(())
+            })
+        });
+        match res.and_then(|res| res) {
+            Ok(()) => exit(0),
+            Err(err) => {
+                eprintln!("Error: {err:?}");
+                exit(1)
+            }
+        }
+    }
+
+    #[test]
+    fn launch_shim_daemon() {
+        if let Ok(args) = std::env::var(SHIM_ARGS_VAR) {
+            shim_daemon(args);
+        }
 
-            // Before handing over control to user code, redirect stdout/stderr
-            let log = open_log().context("failed to allocate a sink for stdout")?;
-            log.duplicate_to_stdout()?;
-            log.duplicate_to_stderr()?;
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("run/shim.sock");
+        let (res, logged, daemon_log) = launch_shim("run::tests::launch_shim_daemon", &socket);
+        assert_eq!(res.unwrap(), format!("unix://{}\n", socket.display()));
 
-            Ok(f(arguments))
+        // stderr is handed over from the launcher to the log once the daemon has bootstrapped
+        assert_eq!(logged, "starting\n");
+        assert_eq!(daemon_log, "serving\nexiting\n");
+    }
+
+    #[test]
+    fn launch_shim_failing_daemon() {
+        if let Ok(args) = std::env::var(SHIM_ARGS_VAR) {
+            shim_daemon(args);
         }
+
+        // the socket can't be bound, as its directory is a file
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("run"), "").unwrap();
+        let socket = dir.path().join("run/shim.sock");
+        let name = "run::tests::launch_shim_failing_daemon";
+        let (res, logged, _) = launch_shim(name, &socket);
+
+        let err = res.unwrap_err().to_string();
+        assert!(err.starts_with("shim daemon exited before reporting its address"));
+        assert!(err.contains("Error: Error binding listener"), "{err}");
+        assert!(logged.starts_with("starting\nError: Error binding listener"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn harden_daemon() {
+        use crate::cgroups::Cgroup;
+        use crate::process::monitor;
+
+        let options = |shim_cgroup: String| {
+            let options = RuncOptions {
+                binary_name: "crun".into(),
+                shim_cgroup,
+                ..Default::default()
+            };
+            Any::from_msg(&options).unwrap()
+        };
+
+        if let Ok(shim_cgroup) = std::env::var(HARDENED_VAR) {
+            return hardened(options(shim_cgroup));
+        }
+
+        // the daemon is moved into a new cgroup, if the cgroup filesystem is writable
+        let shim_cgroup = format!("/shimkit-test-{}", std::process::id());
+        let cgroup_dir = Cgroup::new(&shim_cgroup).dir("pids");
+        let shim_cgroup = match std::fs::create_dir(&cgroup_dir) {
+            Ok(()) => shim_cgroup,
+            Err(err) => {
+                eprintln!("not moving into a shim cgroup: {err}");
+                String::new()
+            }
+        };
+        let options = options(shim_cgroup.clone());
+
+        // the steps change the attributes of the whole process, so run them in a child,
+        // feeding it the runtime options like containerd does
+        let mut cmd = Command::new(std::env::current_exe().unwrap());
+        cmd.args(["--exact", "run::tests::harden_daemon", "--test-threads=1"])
+            .env(HARDENED_VAR, &shim_cgroup)
+            .stdin(Stdio::piped());
+        let mut child = monitor().spawn(&mut cmd).unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&options.encode_to_vec()).unwrap();
+        drop(stdin);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let exit = runtime.block_on(monitor().wait(child.id()));
+        monitor().forget(child.id());
+        if !shim_cgroup.is_empty() {
+            std::fs::remove_dir(&cgroup_dir).unwrap();
+        }
+        assert_eq!(exit.status, 0, "the hardened daemon failed");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn read_options_from_open_stdin() {
+        use crate::process::monitor;
+
+        let options = Any::from_msg(&RuncOptions::default()).unwrap();
+
+        if std::env::var(OPEN_STDIN_VAR).is_ok() {
+            // the options are read without waiting for the launcher to exit
+            let start = std::time::Instant::now();
+            assert_eq!(read_runtime_options().unwrap(), Some(options));
+            assert!(start.elapsed() < START_TIMEOUT);
+            return;
+        }
+
+        // the stdin of the child is kept open until it exits
+        let mut cmd = Command::new(std::env::current_exe().unwrap());
+        cmd.args(["--exact", "run::tests::read_options_from_open_stdin"])
+            .env(OPEN_STDIN_VAR, "1")
+            .stdin(Stdio::piped());
+        let mut child = monitor().spawn(&mut cmd).unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&options.encode_to_vec()).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let exit = runtime.block_on(monitor().wait(child.id()));
+        monitor().forget(child.id());
+        drop(stdin);
+        assert_eq!(exit.status, 0, "reading the options failed");
+    }
+
+    #[cfg(target_os = "linux")]
+    fn hardened(options: Any) {
+        use std::os::fd::IntoRawFd as _;
+
+        let read = |path: &str| std::fs::read_to_string(path).unwrap();
+
+        assert_eq!(read_runtime_options().unwrap(), Some(options.clone()));
+        let shim_cgroup = unpack::<RuncOptions>(&options)
+            .unwrap()
+            .unwrap()
+            .shim_cgroup;
+        let stdin = std::fs::read_link("/proc/self/fd/0").unwrap();
+        assert_eq!(stdin, Path::new("/dev/null"));
+
+        let leaked = File::open("/dev/null").unwrap().into_raw_fd();
+        let kept = File::open("/dev/null").unwrap().into_raw_fd();
+        let score: i32 = read(OOM_SCORE_ADJ).trim().parse().unwrap();
+
+        Hardening::default().apply(Some(&options), &[kept]).unwrap();
+
+        // the session id follows the state, the parent pid and the process group
+        let stat = read("/proc/self/stat");
+        let (_, stat) = stat.rsplit_once(')').unwrap();
+        let session = stat.split_whitespace().nth(3).unwrap();
+        assert_eq!(session, std::process::id().to_string());
+
+        let status = read("/proc/self/status");
+        assert!(
+            status.lines().any(|line| line == "Umask:\t0022"),
+            "{status}"
+        );
+
+        if !shim_cgroup.is_empty() {
+            let cgroups = read("/proc/self/cgroup");
+            let suffix = format!(":{shim_cgroup}");
+            assert!(
+                cgroups.lines().any(|line| line.ends_with(&suffix)),
+                "{cgroups}"
+            );
+        }
+
+        let expected = (score + 1).min(1000);
+        assert_eq!(read(OOM_SCORE_ADJ).trim(), expected.to_string());
+
+        assert_eq!(unsafe { libc::fcntl(leaked, libc::F_GETFD) }, -1);
+        assert!(unsafe { libc::fcntl(kept, libc::F_GETFD) } >= 0);
+        let mut fds: Vec<RawFd> = std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| {
+                let entry = entry.unwrap();
+                // skip the directory being listed
+                let target = std::fs::read_link(entry.path()).ok()?;
+                if target.ends_with("fd") {
+                    return None;
+                }
+                entry.file_name().to_str()?.parse().ok()
+            })
+            .collect();
+        fds.sort();
+        assert_eq!(fds, [0, 1, 2, kept]);
     }
 }
@@ -0,0 +1,1415 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result, Write as _};
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use oci_spec::runtime::{LinuxResources, Process};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::unix::pipe;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+
+use crate::bundle::Bundle;
+use crate::cgroups::Cgroup;
+use crate::cleanup::INIT_PID_FILE;
+use crate::console::{Console, ConsoleSocket};
+use crate::io::ProcessIo;
+use crate::mount;
+use crate::options::RuntimeOptions;
+use crate::process::{monitor, Exit, Pidfd};
+use crate::state::ContainerState;
+use crate::task::ContainerBackend;
+use crate::types::prost::Any;
+use crate::types::runc::{CheckpointOptions, Options as RuncOptions};
+use crate::types::task::{CreateTaskRequest, ExecProcessRequest};
+use crate::types::Status;
+
+/// The runtime binary used when none is configured.
+pub const DEFAULT_BINARY: &str = "runc";
+
+/// The file in the bundle where the runtime writes its logs, like containerd's runc shim does.
+pub const RUNTIME_LOG_FILE: &str = "log.json";
+
+/// Client for the command line of an OCI runtime, such as `runc` or `crun`.
+///
+/// Every command runs the runtime binary to completion through the process `Monitor`.
+/// Failures are reported with the error logged by the runtime, as an `ErrorKind::NotFound`
+/// error if the container doesn't exist, or an `ErrorKind::AlreadyExists` error if it does.
+#[derive(Clone, Debug)]
+pub struct Runc {
+    // the runtime binary, looked up in `PATH` if it's not a path
+    binary: PathBuf,
+
+    // the `--root` directory where the runtime keeps the state of its containers
+    root: Option<PathBuf>,
+
+    // the `--log` file of the runtime, stderr by default
+    log: Option<PathBuf>,
+
+    // whether to pass `--systemd-cgroup`
+    systemd_cgroup: bool,
+
+    // the `--criu` binary used to checkpoint containers
+    criu: Option<PathBuf>,
+}
+
+impl Default for Runc {
+    fn default() -> Self {
+        Self::new(DEFAULT_BINARY)
+    }
+}
+
+impl Runc {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            root: None,
+            log: None,
+            systemd_cgroup: false,
+            criu: None,
+        }
+    }
+
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Sets the file where the runtime writes its JSON logs.
+    /// The logs of `create` and `exec` are appended to it once they are done, as they
+    /// are first written to a file of their own to report their errors.
+    pub fn with_log(mut self, log: impl Into<PathBuf>) -> Self {
+        self.log = Some(log.into());
+        self
+    }
+
+    pub fn with_systemd_cgroup(mut self, systemd_cgroup: bool) -> Self {
+        self.systemd_cgroup = systemd_cgroup;
+        self
+    }
+
+    pub fn with_criu(mut self, criu: impl Into<PathBuf>) -> Self {
+        self.criu = Some(criu.into());
+        self
+    }
+
+    /// Applies the runc shim options, as set by containerd in `CreateTaskRequest.options`.
+    /// Options that aren't set keep their current value.
+    pub fn with_options(mut self, options: &RuncOptions) -> Self {
+        if !options.binary_name.is_empty() {
+            self.binary = options.binary_name.clone().into();
+        }
+        if !options.root.is_empty() {
+            self.root = Some(options.root.clone().into());
+        }
+        #[allow(deprecated)]
+        if !options.criu_path.is_empty() {
+            self.criu = Some(options.criu_path.clone().into());
+        }
+        self.systemd_cgroup |= options.systemd_cgroup;
+        self
+    }
+
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    /// Creates the container `id` from the OCI bundle in `bundle`, and returns the pid
+    /// of its init process, which waits to be started with `start`.
+    pub async fn create(
+        &self,
+        id: &str,
+        bundle: impl AsRef<Path>,
+        opts: &CreateOpts,
+        stdio: ProcessStdio,
+    ) -> Result<u32> {
+        let mut args = vec!["create".into(), "--bundle".into(), bundle.as_ref().into()];
+        args.extend(opts.args());
+        args.push(id.into());
+        self.spawn(args, stdio).await?;
+        read_pid(&opts.pid_file)
+    }
+
+    /// Starts the init process of a created container.
+    pub async fn start(&self, id: &str) -> Result<()> {
+        self.run(["start", id], None).await?;
+        Ok(())
+    }
+
+    /// Starts a new `process` in the running container `id`, and returns its pid.
+    /// The runtime doesn't wait for the process to exit.
+    pub async fn exec(
+        &self,
+        id: &str,
+        process: &Process,
+        opts: &ExecOpts,
+        stdio: ProcessStdio,
+    ) -> Result<u32> {
+        // the runtime reads the process spec from a file, which is only needed while it runs
+        let spec = TempFile::create("process.json", &serde_json::to_vec(process)?)?;
+
+        let mut args = vec!["exec".into(), "--detach".into()];
+        args.extend(["--process".into(), spec.0.clone().into_os_string()]);
+        args.extend(opts.args());
+        args.push(id.into());
+        self.spawn(args, stdio).await?;
+        read_pid(&opts.pid_file)
+    }
+
+    /// Sends `signal` to the init process of the container, or to all its processes if `all` is set.
+    pub async fn kill(&self, id: &str, signal: u32, all: bool) -> Result<()> {
+        let signal = signal.to_string();
+        let args = match all {
+            true => vec!["kill", "--all", id, &signal],
+            false => vec!["kill", id, &signal],
+        };
+        self.run(args, None).await?;
+        Ok(())
+    }
+
+    /// Deletes the container and the state kept by the runtime.
+    /// With `force`, a running container is killed first.
+    pub async fn delete(&self, id: &str, force: bool) -> Result<()> {
+        let args = match force {
+            true => vec!["delete", "--force", id],
+            false => vec!["delete", id],
+        };
+        self.run(args, None).await?;
+        Ok(())
+    }
+
+    /// Returns the pids of the processes running in the container.
+    pub async fn ps(&self, id: &str) -> Result<Vec<u32>> {
+        let output = self.run(["ps", "--format", "json", id], None).await?;
+        parse_output(&output)
+    }
+
+    /// Freezes all the processes in the container.
+    pub async fn pause(&self, id: &str) -> Result<()> {
+        self.run(["pause", id], None).await?;
+        Ok(())
+    }
+
+    /// Thaws all the processes in a paused container.
+    pub async fn resume(&self, id: &str) -> Result<()> {
+        self.run(["resume", id], None).await?;
+        Ok(())
+    }
+
+    /// Updates the resource limits of the container.
+    pub async fn update(&self, id: &str, resources: &LinuxResources) -> Result<()> {
+        let resources = serde_json::to_vec(resources)?;
+        self.run(["update", "--resources", "-", id], Some(resources))
+            .await?;
+        Ok(())
+    }
+
+    /// Checkpoints the container with CRIU into `opts.image_path`.
+    pub async fn checkpoint(&self, id: &str, opts: &CheckpointOpts) -> Result<()> {
+        let mut args = vec!["checkpoint".into()];
+        args.extend(opts.args());
+        args.push(id.into());
+        self.run(args, None).await?;
+        Ok(())
+    }
+
+    /// Returns the state of the container, as reported by the runtime.
+    pub async fn state(&self, id: &str) -> Result<State> {
+        let output = self.run(["state", id], None).await?;
+        parse_output(&output)
+    }
+
+    // the command running the runtime, which logs to `log` or to the log of the client
+    fn command(&self, log: Option<&Path>) -> Command {
+        let mut cmd = Command::new(&self.binary);
+        if let Some(root) = &self.root {
+            cmd.arg("--root").arg(root);
+        }
+        if let Some(log) = log.or(self.log.as_deref()) {
+            cmd.arg("--log").arg(log);
+        }
+        cmd.args(["--log-format", "json"]);
+        if self.systemd_cgroup {
+            cmd.arg("--systemd-cgroup");
+        }
+        if let Some(criu) = &self.criu {
+            cmd.arg("--criu").arg(criu);
+        }
+        cmd
+    }
+
+    // runs a command that starts a container process, which inherits the stdio of the runtime
+    async fn spawn(&self, args: Vec<OsString>, stdio: ProcessStdio) -> Result<()> {
+        // the errors are only found in the log, as stderr belongs to the container process,
+        // and each command gets a log of its own so that concurrent ones don't mix theirs
+        let log = TempFile::create("log.json", b"")?;
+        let mut cmd = self.command(Some(&log.0));
+        cmd.args(&args)
+            .stdin(stdio.stdin.map_or_else(Stdio::null, Stdio::from))
+            .stdout(stdio.stdout.map_or_else(Stdio::null, Stdio::from))
+            .stderr(stdio.stderr.map_or_else(Stdio::null, Stdio::from));
+
+        let pid = monitor().spawn(&mut cmd)?.id();
+        // close our copies of the process stdio
+        drop(cmd);
+
+        let exit = monitor().wait(pid).await;
+        monitor().forget(pid);
+
+        let logged = std::fs::read(&log.0)?;
+        if let Some(path) = &self.log {
+            let appended = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(&logged));
+            if let Err(err) = appended {
+                log::warn!("failed to append the runtime log to {path:?}: {err}");
+            }
+        }
+        self.check(&args, &exit, &logged)
+    }
+
+    // runs a command to completion, feeding it `input`, and returns its output
+    async fn run<I, S>(&self, args: I, input: Option<Vec<u8>>) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let mut cmd = self.command(None);
+        cmd.args(&args)
+            .stdin(match input {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = monitor().spawn(&mut cmd)?;
+        let pid = child.id();
+        let stdout = read_to_end(child.stdout.take())?;
+        let stderr = read_to_end(child.stderr.take())?;
+
+        if let (Some(input), Some(stdin)) = (input, child.stdin.take()) {
+            let mut stdin = pipe::Sender::from_owned_fd(stdin.into())?;
+            // the runtime may fail before reading its input, which is reported below
+            let _ = stdin.write_all(&input).await;
+        }
+
+        let exit = monitor().wait(pid).await;
+        monitor().forget(pid);
+
+        let stdout = stdout.await.map_err(Error::other)??;
+        let stderr = stderr.await.map_err(Error::other)??;
+        self.check(&args, &exit, &stderr)?;
+        Ok(stdout)
+    }
+
+    // turns a failed command into an error, with the message logged by the runtime
+    // in `logged`, its stderr or its own log
+    fn check(&self, args: &[OsString], exit: &Exit, logged: &[u8]) -> Result<()> {
+        if exit.status == 0 {
+            return Ok(());
+        }
+
+        let message = std::str::from_utf8(logged).ok().and_then(error_message);
+        let message = message.unwrap_or_else(|| format!("exit status {}", exit.status));
+
+        let kind = if message.contains("does not exist") {
+            ErrorKind::NotFound
+        } else if message.contains("already exists") || message.contains("container with id exists")
+        {
+            ErrorKind::AlreadyExists
+        } else {
+            ErrorKind::Other
+        };
+
+        let command = args.first().map(|arg| arg.to_string_lossy());
+        let command = command.unwrap_or_default();
+        let binary = self.binary.display();
+        Err(Error::new(
+            kind,
+            format!("{binary} {command} failed: {message}"),
+        ))
+    }
+}
+
+/// The options of `Runc::create`.
+#[derive(Clone, Debug, Default)]
+pub struct CreateOpts {
+    // the file where the runtime writes the pid of the init process
+    pub pid_file: PathBuf,
+
+    // the socket where the runtime sends the pty master, for containers with a terminal
+    pub console_socket: Option<PathBuf>,
+
+    // whether to use `MS_MOVE` instead of `pivot_root` to change the root
+    pub no_pivot: bool,
+
+    // whether to keep the session keyring of the caller
+    pub no_new_keyring: bool,
+}
+
+impl CreateOpts {
+    pub fn new(pid_file: impl Into<PathBuf>) -> Self {
+        Self {
+            pid_file: pid_file.into(),
+            ..Default::default()
+        }
+    }
+
+    fn args(&self) -> Vec<OsString> {
+        let mut args = vec!["--pid-file".into(), self.pid_file.clone().into()];
+        if let Some(socket) = &self.console_socket {
+            args.extend(["--console-socket".into(), socket.clone().into()]);
+        }
+        if self.no_pivot {
+            args.push("--no-pivot".into());
+        }
+        if self.no_new_keyring {
+            args.push("--no-new-keyring".into());
+        }
+        args
+    }
+}
+
+/// The options of `Runc::exec`.
+#[derive(Clone, Debug, Default)]
+pub struct ExecOpts {
+    // the file where the runtime writes the pid of the process
+    pub pid_file: PathBuf,
+
+    // the socket where the runtime sends the pty master, for processes with a terminal
+    pub console_socket: Option<PathBuf>,
+}
+
+impl ExecOpts {
+    pub fn new(pid_file: impl Into<PathBuf>) -> Self {
+        Self {
+            pid_file: pid_file.into(),
+            ..Default::default()
+        }
+    }
+
+    fn args(&self) -> Vec<OsString> {
+        let mut args = vec!["--pid-file".into(), self.pid_file.clone().into()];
+        if let Some(socket) = &self.console_socket {
+            args.extend(["--console-socket".into(), socket.clone().into()]);
+        }
+        args
+    }
+}
+
+/// The options of `Runc::checkpoint`.
+#[derive(Clone, Debug, Default)]
+pub struct CheckpointOpts {
+    // the directory where CRIU writes the checkpoint images
+    pub image_path: PathBuf,
+
+    // the directory where CRIU writes its logs, the image path by default
+    pub work_path: Option<PathBuf>,
+
+    // the images of a previous checkpoint, for incremental checkpoints
+    pub parent_path: Option<PathBuf>,
+
+    // whether to leave the container running once it's been checkpointed
+    pub leave_running: bool,
+
+    // whether to checkpoint established TCP connections
+    pub tcp_established: bool,
+
+    // whether to checkpoint external unix sockets
+    pub ext_unix_sk: bool,
+
+    // whether to checkpoint a container with a terminal
+    pub shell_job: bool,
+
+    // whether to checkpoint file locks
+    pub file_locks: bool,
+
+    // the namespaces to create empty on restore, e.g., `network`
+    pub empty_ns: Vec<String>,
+
+    // the CRIU cgroups mode, e.g., `soft`
+    pub manage_cgroups_mode: Option<String>,
+}
+
+impl CheckpointOpts {
+    /// Builds the options from the runc checkpoint options set by containerd
+    /// in `CheckpointTaskRequest.options`.
+    pub fn from_options(options: &CheckpointOptions) -> Self {
+        let path = |path: &str| (!path.is_empty()).then(|| PathBuf::from(path));
+        Self {
+            image_path: options.image_path.clone().into(),
+            work_path: path(&options.work_path),
+            parent_path: None,
+            leave_running: !options.exit,
+            tcp_established: options.open_tcp,
+            ext_unix_sk: options.external_unix_sockets,
+            shell_job: options.terminal,
+            file_locks: options.file_locks,
+            empty_ns: options.empty_namespaces.clone(),
+            manage_cgroups_mode: (!options.cgroups_mode.is_empty())
+                .then(|| options.cgroups_mode.clone()),
+        }
+    }
+
+    fn args(&self) -> Vec<OsString> {
+        let mut args = vec!["--image-path".into(), self.image_path.clone().into()];
+        let paths = [
+            ("--work-path", &self.work_path),
+            ("--parent-path", &self.parent_path),
+        ];
+        for (flag, path) in paths {
+            if let Some(path) = path {
+                args.extend([flag.into(), path.clone().into()]);
+            }
+        }
+        let flags = [
+            ("--leave-running", self.leave_running),
+            ("--tcp-established", self.tcp_established),
+            ("--ext-unix-sk", self.ext_unix_sk),
+            ("--shell-job", self.shell_job),
+            ("--file-locks", self.file_locks),
+        ];
+        args.extend(flags.iter().filter(|(_, set)| *set).map(|(f, _)| f.into()));
+        for ns in &self.empty_ns {
+            args.extend(["--empty-ns".into(), ns.into()]);
+        }
+        if let Some(mode) = &self.manage_cgroups_mode {
+            args.extend(["--manage-cgroups-mode".into(), mode.into()]);
+        }
+        args
+    }
+}
+
+/// The stdio handed over to a container process by `Runc::create` and `Runc::exec`.
+/// Streams that aren't set are connected to `/dev/null`.
+#[derive(Debug, Default)]
+pub struct ProcessStdio {
+    pub stdin: Option<OwnedFd>,
+    pub stdout: Option<OwnedFd>,
+    pub stderr: Option<OwnedFd>,
+}
+
+impl ProcessStdio {
+    /// Takes the process side of the streams of `io`.
+    pub fn take(io: &mut ProcessIo) -> Self {
+        Self {
+            stdin: io.take_stdin(),
+            stdout: io.take_stdout(),
+            stderr: io.take_stderr(),
+        }
+    }
+}
+
+/// The status of a container, as reported by the runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerStatus {
+    Creating,
+    Created,
+    Running,
+    Paused,
+    Stopped,
+    #[serde(other)]
+    Unknown,
+}
+
+/// The state of a container, as printed by the `state` command.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    pub oci_version: String,
+    pub id: String,
+    // the pid of the init process, 0 once the container has stopped
+    pub pid: u32,
+    pub status: ContainerStatus,
+    pub bundle: PathBuf,
+    pub rootfs: PathBuf,
+    // the creation time, in RFC 3339 format
+    pub created: String,
+    #[serde(default)]
+    pub owner: String,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+// an entry of the JSON logs of the runtime
+#[derive(Deserialize)]
+struct LogEntry {
+    level: String,
+    msg: String,
+}
+
+// returns the last error in the output or logs of the runtime
+fn error_message(output: &str) -> Option<String> {
+    output
+        .lines()
+        .rev()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .find_map(|line| match serde_json::from_str::<LogEntry>(line) {
+            Ok(entry) if matches!(entry.level.as_str(), "error" | "fatal") => Some(entry.msg),
+            Ok(_) => None,
+            // plain messages, e.g., from runtimes that don't support JSON logs
+            Err(_) => Some(line.to_string()),
+        })
+}
+
+fn parse_output<T: serde::de::DeserializeOwned>(output: &[u8]) -> Result<T> {
+    serde_json::from_slice(output).map_err(|err| {
+        let output = String::from_utf8_lossy(output);
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid runtime output {output:?}: {err}"),
+        )
+    })
+}
+
+fn read_pid(path: &Path) -> Result<u32> {
+    let content = std::fs::read_to_string(path)?;
+    content.trim().parse().map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid pid file {path:?}: {err}"),
+        )
+    })
+}
+
+fn read_to_end(pipe: Option<impl Into<OwnedFd>>) -> Result<JoinHandle<Result<Vec<u8>>>> {
+    let pipe = pipe.map(|pipe| pipe::Receiver::from_owned_fd(pipe.into()));
+    let mut pipe = pipe.transpose()?;
+    Ok(tokio::spawn(async move {
+        let mut output = vec![];
+        if let Some(pipe) = &mut pipe {
+            pipe.read_to_end(&mut output).await?;
+        }
+        Ok(output)
+    }))
+}
+
+// a uniquely named file in the temporary directory, removed when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn create(name: &str, content: &[u8]) -> Result<Self> {
+        let file = TempFile(temp_path(name));
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file.0)?;
+        f.write_all(content)?;
+        Ok(file)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// a unique path in the temporary directory, which is short enough for a unix socket,
+// unlike the paths of the bundles
+fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let pid = std::process::id();
+    std::env::temp_dir().join(format!("runc-{pid}-{n}-{name}"))
+}
+
+/// A `ContainerBackend` that runs the containers of a `TaskService` with an OCI runtime.
+///
+/// The rootfs mounts of the containers are mounted with `mount::mount_rootfs`, and their
+/// stdio is wired with `io::ProcessIo`, or with a `console::Console` for a terminal.
+/// The runc shim options of a container, if any, are applied on top of the `Runc` client.
+///
+/// The init processes are children of the runtime, so the shim must be a child subreaper
+/// for their exit to be observed, see `process::set_subreaper`.
+///
+/// A restored container gets no stdio, which went away with the previous shim,
+/// and its exec processes that were not started can't be started anymore.
+pub struct RuncBackend {
+    runc: Runc,
+    namespace: String,
+    containers: Mutex<HashMap<String, Container>>,
+}
+
+struct Container {
+    runc: Runc,
+    bundle: PathBuf,
+    cgroup: Option<Cgroup>,
+    processes: HashMap<String, RuncProcess>,
+}
+
+#[derive(Default)]
+struct RuncProcess {
+    pid: u32,
+    // the pidfd of an exec process, to signal it, until it has been reaped
+    pidfd: Option<Pidfd>,
+    io: Option<ProcessIo>,
+    // shared, so that it's not locked out of the process while its stdin is closed
+    console: Option<Arc<AsyncMutex<Console>>>,
+    // the request of an exec process that hasn't been started yet
+    exec: Option<(ExecProcessRequest, Process)>,
+}
+
+impl RuncBackend {
+    /// Creates a backend running `runc` for the containers of `namespace`.
+    pub fn new(runc: Runc, namespace: impl Into<String>) -> Self {
+        Self {
+            runc,
+            namespace: namespace.into(),
+            containers: Default::default(),
+        }
+    }
+
+    /// Returns the runtime client of the container `id`, e.g., to pause it.
+    pub fn runc(&self, id: &str) -> Option<Runc> {
+        self.lock().get(id).map(|c| c.runc.clone())
+    }
+
+    // the runtime client of a container, logging into its bundle
+    fn container_runc(&self, bundle: &Bundle, options: Option<&RuncOptions>) -> Runc {
+        let mut runc = self.runc.clone();
+        if let Some(options) = options {
+            runc = runc.with_options(options);
+        }
+        runc.with_log(bundle.path().join(RUNTIME_LOG_FILE))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Container>> {
+        self.containers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn with_process<T>(
+        &self,
+        id: &str,
+        exec_id: &str,
+        f: impl FnOnce(&mut Container, &mut RuncProcess) -> T,
+    ) -> crate::types::Result<T> {
+        let mut containers = self.lock();
+        let container = containers
+            .get_mut(id)
+            .ok_or_else(|| Status::not_found(format!("container {id:?} not found")))?;
+        let mut process = container
+            .processes
+            .remove(exec_id)
+            .ok_or_else(|| Status::not_found(format!("process {exec_id:?} not found in {id:?}")))?;
+        let res = f(container, &mut process);
+        container.processes.insert(exec_id.into(), process);
+        Ok(res)
+    }
+
+    // opens the stdio of a process, or a socket to receive its terminal from the runtime
+    async fn open_stdio(
+        &self,
+        id: &str,
+        terminal: bool,
+        stdin: &str,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<(ProcessStdio, Option<ProcessIo>, Option<ConsoleSocket>)> {
+        if terminal {
+            let socket = ConsoleSocket::bind(temp_path("pty.sock"))?;
+            return Ok((ProcessStdio::default(), None, Some(socket)));
+        }
+        let mut io = ProcessIo::open(id, &self.namespace, stdin, stdout, stderr).await?;
+        Ok((ProcessStdio::take(&mut io), Some(io), None))
+    }
+
+    async fn create_container(
+        &self,
+        req: &CreateTaskRequest,
+        bundle: &Bundle,
+        runc: &Runc,
+        options: Option<&RuncOptions>,
+    ) -> Result<RuncProcess> {
+        if !req.rootfs.is_empty() {
+            mount::mount_rootfs(bundle.path(), &req.rootfs)?;
+        }
+
+        let (stdio, io, socket) = self
+            .open_stdio(&req.id, req.terminal, &req.stdin, &req.stdout, &req.stderr)
+            .await?;
+        let opts = CreateOpts {
+            pid_file: bundle.path().join(INIT_PID_FILE),
+            console_socket: socket.as_ref().map(|s| s.path().to_owned()),
+            no_pivot: options.is_some_and(|o| o.no_pivot_root),
+            no_new_keyring: options.is_some_and(|o| o.no_new_keyring),
+        };
+        let pid = runc.create(&req.id, bundle.path(), &opts, stdio).await?;
+
+        let console = match socket {
+            Some(socket) => {
+                let console = async {
+                    let master = socket.receive().await?;
+                    Console::new(master, &req.stdin, &req.stdout).await
+                };
+                match console.await {
+                    Ok(console) => Some(Arc::new(AsyncMutex::new(console))),
+                    Err(err) => {
+                        let _ = runc.delete(&req.id, true).await;
+                        return Err(err);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        Ok(RuncProcess {
+            pid,
+            io,
+            console,
+            ..Default::default()
+        })
+    }
+
+    async fn start_exec(
+        &self,
+        id: &str,
+        exec_id: &str,
+        runc: &Runc,
+        bundle: &Path,
+        (req, spec): &(ExecProcessRequest, Process),
+    ) -> Result<RuncProcess> {
+        let (stdio, mut io, socket) = self
+            .open_stdio(id, req.terminal, &req.stdin, &req.stdout, &req.stderr)
+            .await?;
+        let opts = ExecOpts {
+            pid_file: bundle.join(format!("{exec_id}.pid")),
+            console_socket: socket.as_ref().map(|s| s.path().to_owned()),
+        };
+        let started = runc.exec(id, spec, &opts, stdio).await;
+        let (pid, pidfd) = match started.and_then(|pid| Ok((pid, Pidfd::open(pid)?))) {
+            Ok(started) => started,
+            Err(err) => {
+                // stop copying stdin, the output copies stop with `io`
+                if let Some(io) = &mut io {
+                    io.close_stdin();
+                }
+                return Err(err);
+            }
+        };
+
+        let console = match socket {
+            Some(socket) => {
+                let console = async {
+                    let master = socket.receive().await?;
+                    Console::new(master, &req.stdin, &req.stdout).await
+                };
+                match console.await {
+                    Ok(console) => Some(Arc::new(AsyncMutex::new(console))),
+                    Err(err) => {
+                        // the process is of no use without its terminal
+                        if let Some(pidfd) = &pidfd {
+                            let _ = pidfd.signal(libc::SIGKILL);
+                        }
+                        monitor().wait(pid).await;
+                        monitor().forget(pid);
+                        return Err(err);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        Ok(RuncProcess {
+            pid,
+            pidfd,
+            io,
+            console,
+            exec: None,
+        })
+    }
+}
+
+// keeps the kind of the runtime errors that containerd acts upon
+fn status(err: Error) -> Status {
+    match err.kind() {
+        ErrorKind::NotFound => Status::not_found(err.to_string()),
+        ErrorKind::AlreadyExists => Status::already_exists(err.to_string()),
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => {
+            Status::invalid_argument(err.to_string())
+        }
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+impl ContainerBackend for RuncBackend {
+    async fn create(&self, req: &CreateTaskRequest) -> crate::types::Result<u32> {
+        let bundle = Bundle::from_request(req).map_err(status)?;
+        let options = RuntimeOptions::from_request(req)?;
+        let options = options.as_ref().and_then(RuntimeOptions::runc);
+        let runc = self.container_runc(&bundle, options);
+
+        let init = match self.create_container(req, &bundle, &runc, options).await {
+            Ok(init) => init,
+            Err(err) => {
+                if let Err(err) = mount::unmount_rootfs(bundle.path()) {
+                    log::warn!("failed to unmount the rootfs of {:?}: {err}", req.id);
+                }
+                return Err(status(err));
+            }
+        };
+
+        let pid = init.pid;
+        let container = Container {
+            runc,
+            cgroup: Cgroup::from_spec(bundle.spec()),
+            bundle: bundle.path().to_owned(),
+            processes: HashMap::from([(String::new(), init)]),
+        };
+        self.lock().insert(req.id.clone(), container);
+        Ok(pid)
+    }
+
+    async fn start(&self, id: &str, exec_id: &str) -> crate::types::Result<u32> {
+        let (runc, bundle, exec) = self.with_process(id, exec_id, |container, process| {
+            let exec = process.exec.take();
+            (container.runc.clone(), container.bundle.clone(), exec)
+        })?;
+
+        let Some(exec) = exec else {
+            runc.start(id).await.map_err(status)?;
+            return self.with_process(id, exec_id, |_, process| process.pid);
+        };
+
+        let res = self.start_exec(id, exec_id, &runc, &bundle, &exec).await;
+        self.with_process(id, exec_id, |_, process| match res {
+            Ok(started) => {
+                *process = started;
+                Ok(process.pid)
+            }
+            Err(err) => {
+                // the exec can be started again
+                process.exec = Some(exec);
+                Err(status(err))
+            }
+        })?
+    }
+
+    async fn kill(
+        &self,
+        id: &str,
+        exec_id: &str,
+        signal: u32,
+        all: bool,
+    ) -> crate::types::Result<()> {
+        if exec_id.is_empty() {
+            let runc = self.with_process(id, exec_id, |container, _| container.runc.clone())?;
+            return runc.kill(id, signal, all).await.map_err(status);
+        }
+
+        // the pidfd, unlike the pid, can't refer to another process once the exec has exited
+        self.with_process(id, exec_id, |_, process| {
+            let signaled = match &process.pidfd {
+                Some(pidfd) => pidfd.signal(signal as i32)?,
+                None if process.pid == 0 => {
+                    return Err(Status::failed_precondition("process has not been started"))
+                }
+                None => false,
+            };
+            match signaled {
+                true => Ok(()),
+                false => Err(Status::not_found("process already finished")),
+            }
+        })?
+    }
+
+    async fn exec(&self, req: &ExecProcessRequest) -> crate::types::Result<()> {
+        let spec = req
+            .spec
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("missing process spec"))?;
+        // containerd encodes the process spec as JSON
+        let spec: Process = serde_json::from_slice(&spec.value)
+            .map_err(|err| Status::invalid_argument(format!("invalid process spec: {err}")))?;
+
+        let mut containers = self.lock();
+        let container = containers
+            .get_mut(&req.id)
+            .ok_or_else(|| Status::not_found(format!("container {:?} not found", req.id)))?;
+        let process = RuncProcess {
+            exec: Some((req.clone(), spec)),
+            ..Default::default()
+        };
+        container.processes.insert(req.exec_id.clone(), process);
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str, exec_id: &str) -> crate::types::Result<()> {
+        if !exec_id.is_empty() {
+            let mut containers = self.lock();
+            if let Some(container) = containers.get_mut(id) {
+                container.processes.remove(exec_id);
+                let _ = std::fs::remove_file(container.bundle.join(format!("{exec_id}.pid")));
+            }
+            return Ok(());
+        }
+
+        let Some(runc) = self.runc(id) else {
+            return Ok(());
+        };
+        match runc.delete(id, false).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(status(err)),
+            _ => {}
+        }
+        self.lock().remove(id);
+        Ok(())
+    }
+
+    async fn resize_pty(
+        &self,
+        id: &str,
+        exec_id: &str,
+        width: u32,
+        height: u32,
+    ) -> crate::types::Result<()> {
+        let console = self.with_process(id, exec_id, |_, process| process.console.clone())?;
+        let console =
+            console.ok_or_else(|| Status::failed_precondition("process has no terminal"))?;
+        let console = console.lock().await;
+        Ok(console.resize(width, height)?)
+    }
+
+    async fn close_stdin(&self, id: &str, exec_id: &str) -> crate::types::Result<()> {
+        let console = self.with_process(id, exec_id, |_, process| {
+            if let Some(io) = &mut process.io {
+                io.close_stdin();
+            }
+            process.console.clone()
+        })?;
+        let Some(console) = console else {
+            return Ok(());
+        };
+        let mut console = console.lock().await;
+        Ok(console.close_stdin().await?)
+    }
+
+    async fn stats(&self, id: &str) -> crate::types::Result<Any> {
+        let cgroup = self.with_process(id, "", |container, _| container.cgroup.clone())?;
+        let cgroup = cgroup.ok_or_else(|| Status::not_found("container has no cgroup"))?;
+        cgroup.stats().map_err(status)
+    }
+
+    async fn restore(&self, state: &ContainerState) -> crate::types::Result<()> {
+        let bundle = Bundle::open(&state.bundle).map_err(status)?;
+        let options = state.options.as_ref().map(RuntimeOptions::from_any);
+        let options = options.transpose()?;
+        let runc = self.container_runc(&bundle, options.as_ref().and_then(RuntimeOptions::runc));
+
+        let execs = state.execs.iter().filter(|(_, process)| process.pid != 0);
+        let processes = std::iter::once(("", &state.init))
+            .chain(execs.map(|(exec_id, process)| (exec_id.as_str(), process)))
+            .map(|(exec_id, state)| {
+                // the exec processes are signaled through their pidfd, if they still run
+                let pidfd = match exec_id {
+                    "" => None,
+                    _ => Pidfd::open(state.pid).ok().flatten(),
+                };
+                let process = RuncProcess {
+                    pid: state.pid,
+                    pidfd: pidfd.filter(|_| state.is_running()),
+                    ..Default::default()
+                };
+                (exec_id.to_string(), process)
+            })
+            .collect();
+
+        let container = Container {
+            runc,
+            cgroup: Cgroup::from_spec(bundle.spec()),
+            bundle: bundle.path().to_owned(),
+            processes,
+        };
+        self.lock().insert(state.id.clone(), container);
+        Ok(())
+    }
+
+    async fn wait(&self, pid: u32) -> Exit {
+        let exit = monitor().wait(pid).await;
+        monitor().forget(pid);
+
+        // wait for the output of the process to be copied, before its exit is published
+        let output = self.lock().values_mut().find_map(|container| {
+            let process = container.processes.values_mut().find(|p| p.pid == pid)?;
+            process.pidfd = None;
+            Some((process.io.take(), process.console.take()))
+        });
+        if let Some((io, console)) = output {
+            if let Some(mut io) = io {
+                if let Err(err) = io.wait().await {
+                    log::warn!("failed to copy the output of process {pid}: {err}");
+                }
+            }
+            if let Some(console) = console {
+                if let Err(err) = console.lock().await.wait().await {
+                    log::warn!("failed to copy the terminal of process {pid}: {err}");
+                }
+            }
+        }
+        exit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use oci_spec::runtime::{LinuxPidsBuilder, LinuxResourcesBuilder, ProcessBuilder, Spec};
+    use prost::Name as _;
+
+    use super::*;
+    use crate::process::set_subreaper;
+
+    // a fake runtime, which records its arguments in `calls`, and emulates
+    // an init process that prints `hello` and exits with 3 once started
+    const FAKE_RUNTIME: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "$@" >> "$dir/calls"
+cmd=""
+id=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --log) log=$2; shift ;;
+        --pid-file) pid_file=$2; shift ;;
+        --process) process=$2; shift ;;
+        --root|--log-format|--criu|--bundle|--console-socket|--format|--image-path|--work-path|--resources) shift ;;
+        -*) ;;
+        *) if [ -z "$cmd" ]; then cmd=$1; elif [ -z "$id" ]; then id=$1; fi ;;
+    esac
+    shift
+done
+case "$cmd" in
+    create)
+        if [ "$id" = silent ]; then
+            exit 1
+        fi
+        if [ "$id" = exists ]; then
+            echo '{"level":"info","msg":"creating"}' >> "$log"
+            echo '{"level":"error","msg":"container with id exists: exists","time":"2024-01-01T00:00:00Z"}' >> "$log"
+            echo '{"level":"debug","msg":"exiting"}' >> "$log"
+            exit 1
+        fi
+        (while [ ! -e "$dir/$id.started" ]; do sleep 0.01; done; echo hello; exit 3) &
+        echo $! > "$pid_file" ;;
+    start)
+        if [ -z "$id" ]; then
+            echo "container id cannot be empty" >&2
+            exit 1
+        fi
+        touch "$dir/$id.started" ;;
+    exec)
+        if [ -e "$dir/exec.fail" ]; then
+            echo '{"level":"error","msg":"exec failed","time":"2024-01-01T00:00:00Z"}' >> "$log"
+            exit 1
+        fi
+        cp "$process" "$dir/process.json"
+        sleep 0.1 &
+        echo $! > "$pid_file" ;;
+    state)
+        if [ "$id" = missing ]; then
+            echo '{"level":"error","msg":"container does not exist","time":"2024-01-01T00:00:00Z"}' >&2
+            exit 1
+        fi
+        echo '{"ociVersion":"1.0.2","id":"c1","pid":42,"status":"running","bundle":"/b","rootfs":"/b/rootfs","created":"2024-01-01T00:00:00Z","owner":""}' ;;
+    ps) echo '[42,43]' ;;
+    update) cat > "$dir/resources.json" ;;
+    kill|delete|pause|resume|checkpoint) ;;
+    *) echo "unknown command $cmd" >&2; exit 1 ;;
+esac
+"#;
+
+    fn fake_runtime() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("runc");
+        std::fs::write(&path, FAKE_RUNTIME).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (dir, path)
+    }
+
+    fn calls(dir: &Path) -> Vec<String> {
+        let calls = std::fs::read_to_string(dir.join("calls")).unwrap();
+        calls.lines().map(str::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn runtime_commands() {
+        let (dir, binary) = fake_runtime();
+        let options = RuncOptions {
+            root: "/run/fake".into(),
+            systemd_cgroup: true,
+            ..Default::default()
+        };
+        let runc = Runc::new(&binary).with_options(&options);
+        assert_eq!(runc.binary(), binary);
+
+        let state = runc.state("c1").await.unwrap();
+        assert_eq!(state.id, "c1");
+        assert_eq!(state.pid, 42);
+        assert_eq!(state.status, ContainerStatus::Running);
+        assert_eq!(state.rootfs, Path::new("/b/rootfs"));
+
+        assert_eq!(runc.ps("c1").await.unwrap(), [42, 43]);
+        runc.kill("c1", 9, true).await.unwrap();
+        runc.pause("c1").await.unwrap();
+        runc.resume("c1").await.unwrap();
+
+        let resources = LinuxResourcesBuilder::default()
+            .pids(LinuxPidsBuilder::default().limit(10).build().unwrap())
+            .build()
+            .unwrap();
+        runc.update("c1", &resources).await.unwrap();
+        let updated = std::fs::read(dir.path().join("resources.json")).unwrap();
+        let updated: LinuxResources = serde_json::from_slice(&updated).unwrap();
+        assert_eq!(updated, resources);
+
+        let options = CheckpointOptions {
+            image_path: "/images".into(),
+            open_tcp: true,
+            empty_namespaces: vec!["network".into()],
+            ..Default::default()
+        };
+        let opts = CheckpointOpts::from_options(&options);
+        runc.checkpoint("c1", &opts).await.unwrap();
+        runc.delete("c1", true).await.unwrap();
+
+        let global = "--root /run/fake --log-format json --systemd-cgroup";
+        let expected = [
+            "state c1",
+            "ps --format json c1",
+            "kill --all c1 9",
+            "pause c1",
+            "resume c1",
+            "update --resources - c1",
+            "checkpoint --image-path /images --leave-running --tcp-established --empty-ns network c1",
+            "delete --force c1",
+        ]
+        .map(|call| format!("{global} {call}"));
+        assert_eq!(calls(dir.path()), expected);
+    }
+
+    #[tokio::test]
+    async fn runtime_errors() {
+        let (dir, binary) = fake_runtime();
+        let runc = Runc::new(&binary);
+
+        let err = runc.state("missing").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .ends_with("state failed: container does not exist"));
+
+        let err = runc.start("").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(err
+            .to_string()
+            .ends_with("start failed: container id cannot be empty"));
+
+        // the error of create is only found in the log, as stderr belongs to the container
+        let log = dir.path().join("log.json");
+        let runc = runc.with_log(&log);
+        let opts = CreateOpts::new(dir.path().join("pid"));
+        let stdio = ProcessStdio::default();
+        let err = runc.create("exists", "/b", &opts, stdio).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(err
+            .to_string()
+            .ends_with("container with id exists: exists"));
+        // and then appended to the log of the client
+        let logged = std::fs::read_to_string(&log).unwrap();
+        assert!(logged.starts_with(r#"{"level":"info","msg":"creating"}"#));
+        assert!(logged.contains("container with id exists"));
+
+        // the errors logged by the previous commands are not reported again
+        let stdio = ProcessStdio::default();
+        let err = runc.create("silent", "/b", &opts, stdio).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(err.to_string().ends_with("create failed: exit status 1"));
+
+        let err = Runc::new(dir.path().join("missing"))
+            .ps("c1")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let log = concat!(
+            r#"{"level":"error","msg":"first","time":"2024-01-01T00:00:00Z"}"#,
+            "\n",
+            r#"{"level":"warning","msg":"ignored","time":"2024-01-01T00:00:00Z"}"#,
+            "\n\n",
+        );
+        assert_eq!(error_message(log).unwrap(), "first");
+        assert_eq!(error_message("plain error\n").unwrap(), "plain error");
+        assert_eq!(error_message(""), None);
+    }
+
+    #[tokio::test]
+    async fn run_container() {
+        set_subreaper().unwrap();
+        let (dir, binary) = fake_runtime();
+
+        let bundle = tempfile::tempdir().unwrap();
+        Spec::default()
+            .save(bundle.path().join(crate::bundle::CONFIG_FILE))
+            .unwrap();
+        let stdout = bundle.path().join("stdout");
+
+        let backend = RuncBackend::new(Runc::new(&binary), "ns");
+        let options = RuncOptions {
+            no_pivot_root: true,
+            ..Default::default()
+        };
+        let req = CreateTaskRequest {
+            id: "c1".into(),
+            bundle: bundle.path().to_str().unwrap().into(),
+            stdout: format!("file://{}", stdout.display()),
+            options: Some(Any {
+                type_url: format!("/{}", RuncOptions::full_name()),
+                value: prost::Message::encode_to_vec(&options),
+            }),
+            ..Default::default()
+        };
+        let pid = backend.create(&req).await.unwrap();
+        assert_eq!(read_pid(&bundle.path().join(INIT_PID_FILE)).unwrap(), pid);
+
+        assert_eq!(backend.start("c1", "").await.unwrap(), pid);
+        let exit = backend.wait(pid).await;
+        assert_eq!(exit.status, 3);
+        assert_eq!(std::fs::read_to_string(&stdout).unwrap(), "hello\n");
+
+        let process = ProcessBuilder::default()
+            .args(vec!["ls".to_string()])
+            .build()
+            .unwrap();
+        // exec loggers are told the id of the container, not of the exec
+        let logger = bundle.path().join("logger.sh");
+        std::fs::write(
+            &logger,
+            "#!/bin/sh\necho \"$CONTAINER_ID\" > \"$1/logger.env\"\nexec 5>&-\ncat <&3 >/dev/null\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&logger, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let req = ExecProcessRequest {
+            id: "c1".into(),
+            exec_id: "e1".into(),
+            stdout: format!("binary://{}?{}", logger.display(), bundle.path().display()),
+            spec: Some(Any {
+                type_url: "types.containerd.io/opencontainers/runtime-spec/1/Process".into(),
+                value: serde_json::to_vec(&process).unwrap(),
+            }),
+            ..Default::default()
+        };
+        backend.exec(&req).await.unwrap();
+        let exec_pid = backend.start("c1", "e1").await.unwrap();
+        let env = std::fs::read_to_string(bundle.path().join("logger.env")).unwrap();
+        assert_eq!(env, "c1\n");
+        let executed = std::fs::read(dir.path().join("process.json")).unwrap();
+        let executed: Process = serde_json::from_slice(&executed).unwrap();
+        assert_eq!(executed, process);
+        assert_eq!(backend.wait(exec_pid).await.status, 0);
+
+        let err = backend.resize_pty("c1", "e1", 80, 24).await.unwrap_err();
+        assert_eq!(err.code(), crate::types::Code::FailedPrecondition);
+        // the exited exec is not signaled, even if its pid was reused
+        let err = backend.kill("c1", "e1", 9, false).await.unwrap_err();
+        assert_eq!(err.code(), crate::types::Code::NotFound);
+        backend.delete("c1", "e1").await.unwrap();
+
+        // an exec that failed to start can be started again
+        let req = ExecProcessRequest {
+            exec_id: "e2".into(),
+            ..req
+        };
+        backend.exec(&req).await.unwrap();
+        let err = backend.kill("c1", "e2", 9, false).await.unwrap_err();
+        assert_eq!(err.code(), crate::types::Code::FailedPrecondition);
+        std::fs::write(dir.path().join("exec.fail"), "").unwrap();
+        let err = backend.start("c1", "e2").await.unwrap_err();
+        assert!(err.message.ends_with("exec failed"), "{err:?}");
+        std::fs::remove_file(dir.path().join("exec.fail")).unwrap();
+        let exec_pid = backend.start("c1", "e2").await.unwrap();
+        backend.kill("c1", "e2", 9, false).await.unwrap();
+        assert_eq!(backend.wait(exec_pid).await.status, 137);
+        backend.delete("c1", "e2").await.unwrap();
+        backend.delete("c1", "").await.unwrap();
+        assert!(backend.runc("c1").is_none());
+
+        let calls = calls(dir.path());
+        let bundle = bundle.path().display();
+        let log = format!("--log {bundle}/log.json --log-format json");
+        // create and exec log to a file of their own
+        let own_log = |call: &str| {
+            let (log, _) = call.strip_prefix("--log ")?.split_once(' ')?;
+            Some(log.ends_with("-log.json") && !log.starts_with(&bundle.to_string()))
+        };
+        assert!(calls[0].ends_with(&format!(
+            "--log-format json create --bundle {bundle} --pid-file {bundle}/init.pid --no-pivot c1"
+        )));
+        assert_eq!(own_log(&calls[0]), Some(true), "{}", calls[0]);
+        assert_eq!(calls[1], format!("{log} start c1"));
+        assert!(calls[2].contains(" --log-format json exec --detach --process "));
+        assert_eq!(own_log(&calls[2]), Some(true), "{}", calls[2]);
+        assert!(calls[2].ends_with(&format!("--pid-file {bundle}/e1.pid c1")));
+        assert!(calls[3].ends_with(&format!("--pid-file {bundle}/e2.pid c1")));
+        assert!(calls[4].ends_with(&format!("--pid-file {bundle}/e2.pid c1")));
+        assert_eq!(calls[5], format!("{log} delete c1"));
+    }
+
+    #[tokio::test]
+    async fn restore_container() {
+        let (dir, binary) = fake_runtime();
+        let bundle = tempfile::tempdir().unwrap();
+        Spec::default()
+            .save(bundle.path().join(crate::bundle::CONFIG_FILE))
+            .unwrap();
+
+        let options = RuncOptions {
+            root: "/run/fake".into(),
+            ..Default::default()
+        };
+        let running = |pid| crate::state::ProcessState {
+            pid,
+            status: crate::types::task::Status::Running,
+            ..Default::default()
+        };
+        let state = ContainerState {
+            id: "c1".into(),
+            bundle: bundle.path().to_str().unwrap().into(),
+            options: Some(Any {
+                type_url: format!("/{}", RuncOptions::full_name()),
+                value: prost::Message::encode_to_vec(&options),
+            }),
+            init: running(42),
+            execs: [("e1".to_string(), running(0))].into(),
+        };
+
+        let backend = RuncBackend::new(Runc::new(&binary), "ns");
+        backend.restore(&state).await.unwrap();
+        assert!(backend.runc("c1").is_some());
+
+        backend.kill("c1", "", 9, false).await.unwrap();
+        let err = backend.start("c1", "e1").await.unwrap_err();
+        assert_eq!(err.code(), crate::types::Code::NotFound);
+        backend.delete("c1", "").await.unwrap();
+
+        let bundle = bundle.path().display();
+        let global = format!("--root /run/fake --log {bundle}/log.json --log-format json");
+        let expected = ["kill c1 9", "delete c1"].map(|call| format!("{global} {call}"));
+        assert_eq!(calls(dir.path()), expected);
+    }
+}
@@ -0,0 +1,775 @@
+use std::collections::HashMap;
+use std::future::{pending, Future};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::{watch, Mutex as AsyncMutex};
+use tokio::task::AbortHandle;
+use tokio::time::{timeout_at, Instant};
+
+use crate::event::{Event, EventPublisher};
+use crate::options;
+use crate::process::{monitor, Exit, Pidfd};
+use crate::types::cri::PodSandboxConfig;
+use crate::types::events::{SandboxCreate, SandboxExit, SandboxStart};
+use crate::types::prost::Timestamp;
+use crate::types::sandbox::{
+    CreateSandboxRequest, CreateSandboxResponse, PingRequest, PingResponse, Platform,
+    PlatformRequest, PlatformResponse, Sandbox, SandboxStatusRequest, SandboxStatusResponse,
+    ShutdownSandboxRequest, ShutdownSandboxResponse, StartSandboxRequest, StartSandboxResponse,
+    StopSandboxRequest, StopSandboxResponse, WaitSandboxRequest, WaitSandboxResponse,
+};
+use crate::types::task::Task;
+use crate::types::{Result, Status};
+
+/// Backend that performs the actual sandbox operations on behalf of a `SandboxService`.
+pub trait SandboxBackend: Send + Sync + 'static {
+    /// Prepares a new sandbox.
+    /// `config` is the CRI pod configuration, if containerd provided one.
+    #[allow(unused_variables)]
+    fn create(
+        &self,
+        req: &CreateSandboxRequest,
+        config: Option<&PodSandboxConfig>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Starts a created sandbox, and returns the pid of its main process,
+    /// or 0 if the sandbox is not backed by a process.
+    fn start(&self, sandbox_id: &str) -> impl Future<Output = Result<u32>> + Send;
+
+    /// Stops a running sandbox, waiting at most `timeout` for it to stop gracefully.
+    fn stop(
+        &self,
+        sandbox_id: &str,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Returns the platform the sandbox runs containers on.
+    /// Defaults to the platform of the shim.
+    #[allow(unused_variables)]
+    fn platform(&self, sandbox_id: &str) -> impl Future<Output = Result<Platform>> + Send {
+        async { Ok(host_platform()) }
+    }
+
+    /// Waits for the sandbox main process to exit.
+    /// By default, this waits for the exit to be reaped by the process `Monitor`,
+    /// or never returns if the sandbox is not backed by a process.
+    #[allow(unused_variables)]
+    fn wait(&self, sandbox_id: &str, pid: u32) -> impl Future<Output = Exit> + Send {
+        async move {
+            if pid == 0 {
+                return pending().await;
+            }
+            let exit = monitor().wait(pid).await;
+            monitor().forget(pid);
+            exit
+        }
+    }
+
+    /// Releases any resource associated with a stopped sandbox.
+    #[allow(unused_variables)]
+    fn shutdown(&self, sandbox_id: &str) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Returns the platform of the running shim, using the OCI naming conventions.
+pub fn host_platform() -> Platform {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    let architecture = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "loongarch64" => "loong64",
+        arch => arch,
+    };
+    Platform {
+        os: os.into(),
+        architecture: architecture.into(),
+        variant: "".into(),
+    }
+}
+
+/// Lifecycle state of a sandbox.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SandboxState {
+    Created,
+    Ready,
+    Stopped,
+}
+
+impl SandboxState {
+    /// Returns the state name as reported to containerd.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SandboxState::Ready => "SANDBOX_READY",
+            SandboxState::Created | SandboxState::Stopped => "SANDBOX_NOTREADY",
+        }
+    }
+}
+
+struct Instance {
+    pid: u32,
+    state: SandboxState,
+    created_at: SystemTime,
+    exit: watch::Sender<Option<Exit>>,
+    waiter: Option<AbortHandle>,
+    // serializes starting the sandbox with stopping it, so that it's started only once
+    start: Arc<AsyncMutex<()>>,
+}
+
+#[derive(Default)]
+struct Sandboxes(HashMap<String, Instance>);
+
+impl Sandboxes {
+    fn get(&mut self, id: &str) -> Result<&mut Instance> {
+        self.0
+            .get_mut(id)
+            .ok_or_else(|| Status::not_found(format!("sandbox {id:?} not found")))
+    }
+}
+
+struct Inner<B> {
+    backend: B,
+    publisher: EventPublisher,
+    sandboxes: Mutex<Sandboxes>,
+}
+
+/// A `Sandbox` implementation that keeps track of the sandboxes lifecycle and
+/// publishes the sandbox events.
+/// The actual sandbox management is delegated to a `SandboxBackend`.
+pub struct SandboxService<B> {
+    inner: Arc<Inner<B>>,
+}
+
+impl<B> Clone for SandboxService<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: SandboxBackend> SandboxService<B> {
+    pub fn new(backend: B, publisher: EventPublisher) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                backend,
+                publisher,
+                sandboxes: Default::default(),
+            }),
+        }
+    }
+
+    /// Returns the backend used by this service.
+    pub fn backend(&self) -> &B {
+        &self.inner.backend
+    }
+
+    fn sandboxes(&self) -> MutexGuard<'_, Sandboxes> {
+        self.inner
+            .sandboxes
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    async fn publish(&self, event: impl Event) {
+        if let Err(err) = self.inner.publisher.publish(event).await {
+            log::warn!("failed to publish event: {err}");
+        }
+    }
+
+    fn spawn_waiter(&self, id: &str, pid: u32) -> AbortHandle {
+        let this = self.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            let exit = this.inner.backend.wait(&id, pid).await;
+            this.handle_exit(&id, exit).await;
+        })
+        .abort_handle()
+    }
+
+    async fn handle_exit(&self, id: &str, exit: Exit) {
+        {
+            let mut sandboxes = self.sandboxes();
+            let Ok(sandbox) = sandboxes.get(id) else {
+                return;
+            };
+            if sandbox.exit.borrow().is_some() {
+                return;
+            }
+            sandbox.state = SandboxState::Stopped;
+            sandbox.exit.send_replace(Some(exit));
+        }
+
+        self.publish(SandboxExit {
+            sandbox_id: id.into(),
+            exit_status: exit.status,
+            exited_at: Some(exit.timestamp.into()),
+        })
+        .await;
+    }
+}
+
+impl<B: SandboxBackend> Sandbox for SandboxService<B> {
+    async fn create_sandbox(&self, req: CreateSandboxRequest) -> Result<CreateSandboxResponse> {
+        let id = req.sandbox_id.clone();
+        if id.is_empty() {
+            return Err(Status::invalid_argument("sandbox id must not be empty"));
+        }
+
+        let config = match &req.options {
+            Some(options) => options::unpack::<PodSandboxConfig>(options)?,
+            None => None,
+        };
+
+        // reserve the id while the backend creates the sandbox
+        {
+            let mut sandboxes = self.sandboxes();
+            if sandboxes.0.contains_key(&id) {
+                return Err(Status::already_exists(format!(
+                    "sandbox {id:?} already exists"
+                )));
+            }
+            let instance = Instance {
+                pid: 0,
+                state: SandboxState::Created,
+                created_at: SystemTime::now(),
+                exit: watch::Sender::new(None),
+                waiter: None,
+                start: Default::default(),
+            };
+            sandboxes.0.insert(id.clone(), instance);
+        }
+
+        if let Err(err) = self.inner.backend.create(&req, config.as_ref()).await {
+            self.sandboxes().0.remove(&id);
+            return Err(err);
+        }
+
+        self.publish(SandboxCreate { sandbox_id: id }).await;
+
+        Ok(CreateSandboxResponse {})
+    }
+
+    async fn start_sandbox(&self, req: StartSandboxRequest) -> Result<StartSandboxResponse> {
+        let id = req.sandbox_id;
+
+        let start = self.sandboxes().get(&id)?.start.clone();
+        let _start = start.lock().await;
+
+        {
+            let mut sandboxes = self.sandboxes();
+            let sandbox = sandboxes.get(&id)?;
+            if sandbox.state != SandboxState::Created {
+                return Err(Status::failed_precondition(format!(
+                    "cannot start a sandbox in {:?} state",
+                    sandbox.state
+                )));
+            }
+        }
+
+        let pid = self.inner.backend.start(&id).await?;
+
+        let created_at = {
+            let mut sandboxes = self.sandboxes();
+            let sandbox = sandboxes.get(&id)?;
+            sandbox.pid = pid;
+            sandbox.state = SandboxState::Ready;
+            sandbox.created_at
+        };
+
+        self.publish(SandboxStart {
+            sandbox_id: id.clone(),
+        })
+        .await;
+
+        let waiter = self.spawn_waiter(&id, pid);
+        if let Ok(sandbox) = self.sandboxes().get(&id) {
+            sandbox.waiter = Some(waiter);
+        }
+
+        Ok(StartSandboxResponse {
+            pid,
+            created_at: Some(created_at.into()),
+        })
+    }
+
+    async fn platform(&self, req: PlatformRequest) -> Result<PlatformResponse> {
+        self.sandboxes().get(&req.sandbox_id)?;
+        let platform = self.inner.backend.platform(&req.sandbox_id).await?;
+        Ok(PlatformResponse {
+            platform: Some(platform),
+        })
+    }
+
+    async fn stop_sandbox(&self, req: StopSandboxRequest) -> Result<StopSandboxResponse> {
+        let id = req.sandbox_id;
+
+        // wait for any start in progress to complete
+        let start = self.sandboxes().get(&id)?.start.clone();
+        let _start = start.lock().await;
+
+        let (state, pid, mut exit) = {
+            let mut sandboxes = self.sandboxes();
+            let sandbox = sandboxes.get(&id)?;
+            (sandbox.state, sandbox.pid, sandbox.exit.subscribe())
+        };
+
+        match state {
+            SandboxState::Ready => {}
+            SandboxState::Created => {
+                // the sandbox never started, it exits without having run
+                let exit = Exit {
+                    pid,
+                    status: 0,
+                    timestamp: SystemTime::now(),
+                };
+                self.handle_exit(&id, exit).await;
+                return Ok(StopSandboxResponse {});
+            }
+            // stopping a sandbox that has already stopped is a no-op
+            SandboxState::Stopped => return Ok(StopSandboxResponse {}),
+        }
+
+        let timeout = match req.timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs.into())),
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        // the process is killed if it's still running once the timeout expires
+        let pidfd = match pid {
+            0 => None,
+            pid => Pidfd::open(pid)?,
+        };
+        self.inner.backend.stop(&id, timeout).await?;
+
+        // the next requests don't wait for the sandbox to exit
+        drop(_start);
+
+        if pid == 0 {
+            // there is no process to wait for, the sandbox exits when stopped
+            let exit = Exit {
+                pid,
+                status: 0,
+                timestamp: SystemTime::now(),
+            };
+            self.handle_exit(&id, exit).await;
+            return Ok(StopSandboxResponse {});
+        }
+
+        if let Some(deadline) = deadline {
+            let exited = timeout_at(deadline, exit.wait_for(Option::is_some)).await;
+            if let (Err(_), Some(pidfd)) = (exited, &pidfd) {
+                log::warn!("sandbox {id:?} didn't stop in time, killing it");
+                pidfd.signal(libc::SIGKILL)?;
+            }
+        }
+        let _ = exit.wait_for(Option::is_some).await;
+
+        Ok(StopSandboxResponse {})
+    }
+
+    async fn wait_sandbox(&self, req: WaitSandboxRequest) -> Result<WaitSandboxResponse> {
+        let id = req.sandbox_id;
+        let mut exit = self.sandboxes().get(&id)?.exit.subscribe();
+        let exit = exit
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Status::not_found(format!("sandbox {id:?} was shut down")))?
+            .expect("wait_for returned None");
+        Ok(WaitSandboxResponse {
+            exit_status: exit.status,
+            exited_at: Some(exit.timestamp.into()),
+        })
+    }
+
+    async fn sandbox_status(&self, req: SandboxStatusRequest) -> Result<SandboxStatusResponse> {
+        let id = req.sandbox_id;
+        let mut sandboxes = self.sandboxes();
+        let sandbox = sandboxes.get(&id)?;
+        let exited_at = sandbox
+            .exit
+            .borrow()
+            .map(|exit| Timestamp::from(exit.timestamp));
+        Ok(SandboxStatusResponse {
+            sandbox_id: id,
+            pid: sandbox.pid,
+            state: sandbox.state.as_str().into(),
+            created_at: Some(sandbox.created_at.into()),
+            exited_at,
+            ..Default::default()
+        })
+    }
+
+    async fn ping_sandbox(&self, req: PingRequest) -> Result<PingResponse> {
+        self.sandboxes().get(&req.sandbox_id)?;
+        Ok(PingResponse {})
+    }
+
+    async fn shutdown_sandbox(
+        &self,
+        req: ShutdownSandboxRequest,
+    ) -> Result<ShutdownSandboxResponse> {
+        let id = req.sandbox_id;
+
+        {
+            let mut sandboxes = self.sandboxes();
+            let sandbox = sandboxes.get(&id)?;
+            if sandbox.state == SandboxState::Ready {
+                return Err(Status::failed_precondition(
+                    "cannot shutdown a running sandbox, stop it first",
+                ));
+            }
+        }
+
+        self.inner.backend.shutdown(&id).await?;
+        if let Some(sandbox) = self.sandboxes().0.remove(&id) {
+            sandbox.waiter.inspect(AbortHandle::abort);
+        }
+
+        Ok(ShutdownSandboxResponse {})
+    }
+}
+
+impl<B: SandboxBackend> Task for SandboxService<B> {}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use prost::Message as _;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+    use crate::types::cri::PodSandboxMetadata;
+    use crate::types::events::{Envelope, Events, ForwardRequest};
+    use crate::types::prost::Any;
+    use crate::types::Code;
+
+    struct FakeEvents {
+        tx: UnboundedSender<Envelope>,
+    }
+
+    impl Events for FakeEvents {
+        async fn forward(&self, req: ForwardRequest) -> trapeze::Result<()> {
+            let _ = self.tx.send(req.envelope.unwrap_or_default());
+            Ok(())
+        }
+    }
+
+    // runs a `sleep` process for sandboxes with a process, or nothing otherwise
+    #[derive(Default)]
+    struct SleepBackend {
+        with_process: bool,
+        // whether the process ignores the stop
+        stubborn: bool,
+        starts: AtomicUsize,
+        names: Mutex<Vec<String>>,
+        pids: Mutex<HashMap<String, u32>>,
+    }
+
+    impl SandboxBackend for SleepBackend {
+        async fn create(
+            &self,
+            _req: &CreateSandboxRequest,
+            config: Option<&PodSandboxConfig>,
+        ) -> Result<()> {
+            if let Some(metadata) = config.and_then(|c| c.metadata.as_ref()) {
+                self.names.lock().unwrap().push(metadata.name.clone());
+            }
+            Ok(())
+        }
+
+        async fn start(&self, sandbox_id: &str) -> Result<u32> {
+            self.starts.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+            if !self.with_process {
+                return Ok(0);
+            }
+            let child = monitor().spawn(Command::new("sleep").arg("10"))?;
+            let pid = child.id();
+            self.pids.lock().unwrap().insert(sandbox_id.into(), pid);
+            Ok(pid)
+        }
+
+        async fn stop(&self, sandbox_id: &str, _timeout: Option<Duration>) -> Result<()> {
+            if self.stubborn {
+                return Ok(());
+            }
+            if let Some(pid) = self.pids.lock().unwrap().get(sandbox_id) {
+                unsafe { libc::kill(*pid as _, libc::SIGKILL) };
+            }
+            Ok(())
+        }
+    }
+
+    fn service(with_process: bool) -> (SandboxService<SleepBackend>, UnboundedReceiver<Envelope>) {
+        let (tx, rx) = unbounded_channel();
+        let publisher = EventPublisher::new(FakeEvents { tx });
+        let backend = SleepBackend {
+            with_process,
+            ..Default::default()
+        };
+        (SandboxService::new(backend, publisher), rx)
+    }
+
+    fn create_request(id: &str) -> CreateSandboxRequest {
+        let config = PodSandboxConfig {
+            metadata: Some(PodSandboxMetadata {
+                name: "my-sandbox".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        CreateSandboxRequest {
+            sandbox_id: id.into(),
+            options: Some(Any {
+                type_url: "runtime.v1.PodSandboxConfig".into(),
+                value: config.encode_to_vec(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    async fn state(service: &SandboxService<SleepBackend>, id: &str) -> String {
+        let req = SandboxStatusRequest {
+            sandbox_id: id.into(),
+            verbose: false,
+        };
+        service.sandbox_status(req).await.unwrap().state
+    }
+
+    #[test]
+    fn decode_config_without_slash() {
+        let req = create_request("s1");
+        let config = options::unpack::<PodSandboxConfig>(req.options.as_ref().unwrap()).unwrap();
+        assert_eq!(config.unwrap().metadata.unwrap().name, "my-sandbox");
+
+        let other = Any {
+            type_url: "/some.other.Type".into(),
+            value: vec![],
+        };
+        assert_eq!(options::unpack::<PodSandboxConfig>(&other).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn lifecycle_with_process() {
+        let (service, mut events) = service(true);
+        let id = || "s1".to_string();
+
+        service.create_sandbox(create_request("s1")).await.unwrap();
+        assert_eq!(service.backend().names.lock().unwrap()[..], ["my-sandbox"]);
+        assert_eq!(state(&service, "s1").await, "SANDBOX_NOTREADY");
+
+        let res = service
+            .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap();
+        assert_ne!(res.pid, 0);
+        assert!(res.created_at.is_some());
+        assert_eq!(state(&service, "s1").await, "SANDBOX_READY");
+
+        let waiter = tokio::spawn({
+            let service = service.clone();
+            async move {
+                service
+                    .wait_sandbox(WaitSandboxRequest { sandbox_id: id() })
+                    .await
+            }
+        });
+
+        let req = ShutdownSandboxRequest { sandbox_id: id() };
+        let err = service.shutdown_sandbox(req).await.unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        let req = StopSandboxRequest {
+            sandbox_id: id(),
+            timeout_secs: 0,
+        };
+        service.stop_sandbox(req).await.unwrap();
+
+        let res = waiter.await.unwrap().unwrap();
+        assert_eq!(res.exit_status, 128 + libc::SIGKILL as u32);
+
+        let req = SandboxStatusRequest {
+            sandbox_id: id(),
+            verbose: false,
+        };
+        let status = service.sandbox_status(req).await.unwrap();
+        assert_eq!(status.state, "SANDBOX_NOTREADY");
+        assert!(status.exited_at.is_some());
+
+        let req = ShutdownSandboxRequest { sandbox_id: id() };
+        service.shutdown_sandbox(req).await.unwrap();
+
+        let err = service
+            .ping_sandbox(PingRequest { sandbox_id: id() })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        for topic in ["/sandboxes/create", "/sandboxes/start", "/sandboxes/exit"] {
+            assert_eq!(events.recv().await.unwrap().topic, topic);
+        }
+    }
+
+    #[tokio::test]
+    async fn lifecycle_without_process() {
+        let (service, _events) = service(false);
+        let id = || "s1".to_string();
+
+        service.create_sandbox(create_request("s1")).await.unwrap();
+
+        let err = service
+            .create_sandbox(create_request("s1"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+
+        let res = service
+            .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap();
+        assert_eq!(res.pid, 0);
+
+        let err = service
+            .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        let req = StopSandboxRequest {
+            sandbox_id: id(),
+            timeout_secs: 1,
+        };
+        service.stop_sandbox(req).await.unwrap();
+
+        let res = service
+            .wait_sandbox(WaitSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap();
+        assert_eq!(res.exit_status, 0);
+
+        let res = service
+            .platform(PlatformRequest { sandbox_id: id() })
+            .await
+            .unwrap();
+        assert_eq!(res.platform, Some(host_platform()));
+    }
+
+    #[tokio::test]
+    async fn concurrent_starts() {
+        let (service, _events) = service(false);
+        let id = || "s1".to_string();
+        service.create_sandbox(create_request("s1")).await.unwrap();
+
+        let start = || {
+            let service = service.clone();
+            tokio::spawn(async move {
+                service
+                    .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+                    .await
+            })
+        };
+        let (a, b) = tokio::join!(start(), start());
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_ok() != b.is_ok(), "{a:?} {b:?}");
+        assert_eq!(service.backend().starts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn stop_timeout() {
+        let (tx, _events) = unbounded_channel();
+        let publisher = EventPublisher::new(FakeEvents { tx });
+        let backend = SleepBackend {
+            with_process: true,
+            stubborn: true,
+            ..Default::default()
+        };
+        let service = SandboxService::new(backend, publisher);
+        let id = || "s1".to_string();
+        service.create_sandbox(create_request("s1")).await.unwrap();
+        service
+            .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap();
+
+        let stop = tokio::spawn({
+            let service = service.clone();
+            async move {
+                let req = StopSandboxRequest {
+                    sandbox_id: id(),
+                    timeout_secs: 1,
+                };
+                service.stop_sandbox(req).await
+            }
+        });
+
+        // the other requests don't wait for the sandbox to stop
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let err = service
+            .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+        assert!(!stop.is_finished());
+
+        // the sandbox is killed once the timeout expires
+        tokio::time::timeout(Duration::from_secs(5), stop)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let res = service
+            .wait_sandbox(WaitSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap();
+        assert_eq!(res.exit_status, 128 + libc::SIGKILL as u32);
+    }
+
+    #[tokio::test]
+    async fn stop_created() {
+        let (service, mut events) = service(true);
+        let id = || "s1".to_string();
+        service.create_sandbox(create_request("s1")).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let service = service.clone();
+            async move {
+                service
+                    .wait_sandbox(WaitSandboxRequest { sandbox_id: id() })
+                    .await
+            }
+        });
+
+        let req = StopSandboxRequest {
+            sandbox_id: id(),
+            timeout_secs: 0,
+        };
+        service.stop_sandbox(req.clone()).await.unwrap();
+        service.stop_sandbox(req).await.unwrap();
+
+        let res = waiter.await.unwrap().unwrap();
+        assert_eq!(res.exit_status, 0);
+        assert_eq!(state(&service, "s1").await, "SANDBOX_NOTREADY");
+
+        let err = service
+            .start_sandbox(StartSandboxRequest { sandbox_id: id() })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+        assert_eq!(service.backend().starts.load(Ordering::Relaxed), 0);
+
+        for topic in ["/sandboxes/create", "/sandboxes/exit"] {
+            assert_eq!(events.recv().await.unwrap().topic, topic);
+        }
+    }
+}
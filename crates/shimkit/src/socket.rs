@@ -0,0 +1,210 @@
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _, OpenOptionsExt as _};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tokio::net::UnixListener;
+use trapeze::transport::{Connection, Listener};
+
+/// The maximum length of a socket path, `sun_path` is 108 bytes including the nul terminator.
+pub(crate) const SUN_PATH_MAX: usize = 107;
+
+/// The listener of a shim socket.
+///
+/// The socket is guarded by an exclusive lock on `<socket>.lock`, held for as long as
+/// the listener lives. This tells a socket served by a live daemon apart from a stale one
+/// left behind by a daemon that died.
+/// The socket and the lock file are removed when the listener is dropped,
+/// i.e., when the server stops.
+pub(crate) struct ShimListener {
+    inner: UnixListener,
+    path: PathBuf,
+    lock: PathBuf,
+    // released when the listener is dropped, after the files are removed
+    _lock_file: File,
+}
+
+impl ShimListener {
+    /// Binds the socket at `path`, replacing any stale socket.
+    /// Returns `None` if a live daemon is already serving on `path`.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref().to_owned();
+        if path.as_os_str().len() > SUN_PATH_MAX {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("socket path {path:?} exceeds {SUN_PATH_MAX} bytes"),
+            ));
+        }
+
+        if let Some(dir) = path.parent() {
+            private_dir(dir)?;
+        }
+
+        let mut lock = path.clone().into_os_string();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        let Some(lock_file) = try_lock(&lock)? else {
+            return Ok(None);
+        };
+
+        // we hold the lock, so any existing socket is stale
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        let inner = bind_private(&path)?;
+        inner.set_nonblocking(true)?;
+        let inner = UnixListener::from_std(inner)?;
+
+        Ok(Some(Self {
+            inner,
+            path,
+            lock,
+            _lock_file: lock_file,
+        }))
+    }
+}
+
+// creates the missing directories of the socket only accessible to their owner,
+// an existing one is left alone as it may be shared, e.g., the state directory of containerd
+fn private_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+// binds the socket with its permissions already restricted to the owner, as the socket
+// takes the permissions of the umask, and can be connected to as soon as it's bound
+fn bind_private(path: &Path) -> Result<StdUnixListener> {
+    // the umask is process wide, don't let concurrent binds restore each other's
+    static UMASK: Mutex<()> = Mutex::new(());
+    let _guard = UMASK.lock().unwrap_or_else(|err| err.into_inner());
+
+    let umask = unsafe { libc::umask(0o177) };
+    let res = StdUnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    res
+}
+
+// takes an exclusive lock on the file at `path`, creating it if needed,
+// or returns `None` if someone else holds the lock
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res < 0 {
+            let err = Error::last_os_error();
+            return match err.kind() {
+                ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        // the previous owner might have removed the file between our open and lock,
+        // in which case we locked an orphan file and need to try again
+        let locked = file.metadata()?;
+        match std::fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(Some(file));
+            }
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for ShimListener {
+    async fn accept(&mut self) -> Result<Box<dyn Connection>> {
+        let (conn, _) = self.inner.accept().await?;
+        Ok(Box::new(conn))
+    }
+}
+
+// the incoming connections of the gRPC server
+#[cfg(feature = "grpc")]
+impl futures_core::Stream for ShimListener {
+    type Item = Result<tokio::net::UnixStream>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inner
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(conn, _)| conn)))
+    }
+}
+
+impl Drop for ShimListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(&self.lock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("s").join("shim.sock");
+
+        let listener = ShimListener::bind(&socket).unwrap().unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o777;
+        assert_eq!(mode(&socket), 0o600);
+        assert_eq!(mode(socket.parent().unwrap()), 0o700);
+
+        drop(listener);
+        assert!(!socket.exists());
+        assert!(!dir.path().join("s").join("shim.sock.lock").exists());
+
+        // an existing directory is left alone, the socket itself is private
+        std::fs::set_permissions(socket.parent().unwrap(), Permissions::from_mode(0o711)).unwrap();
+        drop(ShimListener::bind(&socket).unwrap().unwrap());
+        assert_eq!(mode(socket.parent().unwrap()), 0o711);
+    }
+
+    #[tokio::test]
+    async fn bind_stale_and_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("shim.sock");
+
+        // a socket left behind by a daemon that died
+        drop(StdUnixListener::bind(&socket).unwrap());
+        assert!(socket.exists());
+
+        let listener = ShimListener::bind(&socket).unwrap();
+        assert!(listener.is_some());
+
+        // the socket is now served by a live daemon
+        assert!(ShimListener::bind(&socket).unwrap().is_none());
+        assert!(socket.exists());
+
+        drop(listener);
+        assert!(ShimListener::bind(&socket).unwrap().is_some());
+    }
+
+    #[test]
+    fn bind_too_long() {
+        let socket = PathBuf::from("/").join("a".repeat(SUN_PATH_MAX));
+        let err = ShimListener::bind(socket).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ErrorKind, Result, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::process::Exit;
+use crate::types::prost::Any;
+use crate::types::task::Status as TaskStatus;
+
+/// The file in the state directory of a container where its state is recorded,
+/// see `bundle::Bundle::state_dir`.
+pub const STATE_FILE: &str = "state.json";
+
+/// The recorded state of a container, enough for a restarted shim to take it over,
+/// see `task::TaskService::restore`, or for the `delete` action to clean up after it.
+///
+/// The rootfs mounts are recorded separately, see `mount::mount_rootfs`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ContainerState {
+    // the id of the container
+    pub id: String,
+
+    // the path of the bundle of the container
+    pub bundle: String,
+
+    // the runtime options of the container, from `CreateTaskRequest.options`
+    #[serde(default, with = "any")]
+    pub options: Option<Any>,
+
+    // the init process of the container
+    pub init: ProcessState,
+
+    // the exec processes of the container, by exec id
+    #[serde(default)]
+    pub execs: BTreeMap<String, ProcessState>,
+}
+
+impl ContainerState {
+    /// Returns the process `exec_id` of the container, the init process for an empty exec id.
+    pub fn process(&self, exec_id: &str) -> Option<&ProcessState> {
+        match exec_id {
+            "" => Some(&self.init),
+            exec_id => self.execs.get(exec_id),
+        }
+    }
+}
+
+/// The recorded state of a process of a container.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessState {
+    // the pid of the process, or 0 if it hasn't been started
+    pub pid: u32,
+
+    // the start time of the process in clock ticks since boot, see `start_time`
+    #[serde(default)]
+    pub start_time: Option<u64>,
+
+    // the lifecycle status of the process
+    #[serde(with = "status")]
+    pub status: TaskStatus,
+
+    // the exit of the process, once it has been reaped
+    #[serde(default)]
+    pub exit: Option<Exit>,
+
+    // the stdio URIs of the process, as given by containerd
+    #[serde(default)]
+    pub stdin: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    #[serde(default)]
+    pub terminal: bool,
+}
+
+impl ProcessState {
+    /// Returns whether the recorded process is still running.
+    ///
+    /// The start time of the process is compared with the recorded one, if any,
+    /// so that another process that reused its pid is not mistaken for it.
+    pub fn is_running(&self) -> bool {
+        if self.pid == 0 || self.exit.is_some() {
+            return false;
+        }
+        match (start_time(self.pid), self.start_time) {
+            (Some(current), Some(recorded)) => current == recorded,
+            (current, None) => current.is_some(),
+            (None, _) => false,
+        }
+    }
+}
+
+/// Persists the `ContainerState` of a container in the `state.json` file of a directory,
+/// usually its bundle.
+///
+/// The file is replaced atomically, so that a shim that crashes while saving
+/// leaves either the previous or the new state behind.
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    /// Creates a store for the state recorded in `dir`.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            path: dir.as_ref().join(STATE_FILE),
+        }
+    }
+
+    /// Returns the path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the recorded state, or returns `None` if there is none.
+    pub fn load(&self) -> Result<Option<ContainerState>> {
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(Some(serde_json::from_slice(&content)?))
+    }
+
+    /// Records `state`, replacing the previous one.
+    ///
+    /// Concurrent saves don't corrupt the file, but the last one to finish wins,
+    /// so they should be serialized to keep the latest state.
+    pub fn save(&self, state: &ContainerState) -> Result<()> {
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+
+        let content = serde_json::to_vec(state)?;
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let tmp = self
+            .path
+            .with_extension(format!("json.{}.{count}.tmp", std::process::id()));
+        let res = File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(&content)?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        res
+    }
+
+    /// Removes the recorded state, if any.
+    pub fn remove(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the start time of a running process in clock ticks since boot,
+/// or `None` if it doesn't exist or is a zombie.
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the fields follow the command name, which is in parenthesis,
+    // starting with the state, the 3rd field, up to the start time, the 22nd
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    if matches!(fields.next(), Some("Z" | "X") | None) {
+        return None;
+    }
+    fields.nth(18)?.parse().ok()
+}
+
+// records the status by name, e.g., `RUNNING`
+mod status {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::TaskStatus;
+
+    pub fn serialize<S: Serializer>(status: &TaskStatus, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(status.as_str_name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TaskStatus, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        TaskStatus::from_str_name(&name)
+            .ok_or_else(|| D::Error::custom(format!("unknown status {name:?}")))
+    }
+}
+
+// records an `Any` with its type url and encoded value
+mod any {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Any;
+
+    #[derive(Serialize, Deserialize)]
+    struct AnyState {
+        type_url: String,
+        value: Vec<u8>,
+    }
+
+    pub fn serialize<S: Serializer>(any: &Option<Any>, serializer: S) -> Result<S::Ok, S::Error> {
+        let any = any.as_ref().map(|any| AnyState {
+            type_url: any.type_url.clone(),
+            value: any.value.clone(),
+        });
+        any.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Any>, D::Error> {
+        let any = Option::<AnyState>::deserialize(deserializer)?;
+        Ok(any.map(|any| Any {
+            type_url: any.type_url,
+            value: any.value,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::process::monitor;
+
+    fn state() -> ContainerState {
+        let exec = ProcessState {
+            pid: 43,
+            status: TaskStatus::Stopped,
+            exit: Some(Exit {
+                pid: 43,
+                status: 3,
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(42),
+            }),
+            ..Default::default()
+        };
+        ContainerState {
+            id: "c1".into(),
+            bundle: "/path/to/bundle".into(),
+            options: Some(Any {
+                type_url: "/containerd.runc.v1.Options".into(),
+                value: vec![1, 2, 3],
+            }),
+            init: ProcessState {
+                pid: 42,
+                start_time: Some(1234),
+                status: TaskStatus::Running,
+                stdout: "/run/stdout".into(),
+                ..Default::default()
+            },
+            execs: BTreeMap::from([("e1".into(), exec)]),
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path());
+        assert_eq!(store.path(), dir.path().join(STATE_FILE));
+        assert_eq!(store.load().unwrap(), None);
+
+        let state = state();
+        store.save(&state).unwrap();
+        assert_eq!(store.load().unwrap().unwrap(), state);
+        assert_eq!(state.process("e1").unwrap().exit.unwrap().status, 3);
+        assert_eq!(state.process("").unwrap().pid, 42);
+        assert_eq!(state.process("e2"), None);
+
+        let content = std::fs::read_to_string(store.path()).unwrap();
+        assert!(content.contains(r#""status":"RUNNING""#), "{content}");
+
+        // the state is replaced, without leaving the temporary file behind
+        let mut state = state;
+        state.init.status = TaskStatus::Stopped;
+        store.save(&state).unwrap();
+        assert_eq!(
+            store.load().unwrap().unwrap().init.status,
+            TaskStatus::Stopped
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        store.remove().unwrap();
+        store.remove().unwrap();
+        assert_eq!(store.load().unwrap(), None);
+    }
+
+    #[test]
+    fn concurrent_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path());
+        std::thread::scope(|scope| {
+            for pid in 1..=8 {
+                let store = &store;
+                scope.spawn(move || {
+                    let mut state = state();
+                    state.init.pid = pid;
+                    for _ in 0..20 {
+                        store.save(&state).unwrap();
+                    }
+                });
+            }
+        });
+
+        // one of the states is recorded whole
+        let pid = store.load().unwrap().unwrap().init.pid;
+        assert!((1..=8).contains(&pid));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn load_invalid_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path());
+        std::fs::write(
+            store.path(),
+            r#"{"id":"c1","bundle":"","init":{"pid":1,"status":"GONE"}}"#,
+        )
+        .unwrap();
+        let err = store.load().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn running_process() {
+        let child = monitor().spawn(Command::new("sleep").arg("10")).unwrap();
+        let pid = child.id();
+        let mut process = ProcessState {
+            pid,
+            start_time: start_time(pid),
+            status: TaskStatus::Running,
+            ..Default::default()
+        };
+        assert!(process.start_time.is_some());
+        assert!(process.is_running());
+
+        // another process with the same pid
+        process.start_time = process.start_time.map(|t| t + 1);
+        assert!(!process.is_running());
+
+        unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+        monitor().wait(pid).await;
+        monitor().forget(pid);
+        process.start_time = None;
+        assert!(!process.is_running());
+        assert_eq!(start_time(pid), None);
+    }
+}
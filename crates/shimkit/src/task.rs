@@ -0,0 +1,1277 @@
+use std::collections::HashMap;
+use std::env::current_exe;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::{watch, Mutex as AsyncMutex};
+
+use crate::cleanup;
+use crate::event::{Event, EventPublisher};
+use crate::mount;
+use crate::process::{monitor, wait_pidfd, Exit};
+use crate::state::{self, ContainerState, ProcessState, StateStore};
+use crate::types::events::{
+    TaskCreate, TaskDelete, TaskExecAdded, TaskExecStarted, TaskExit, TaskIo, TaskStart,
+};
+use crate::types::prost::{Any, Timestamp};
+use crate::types::sandbox::Sandbox;
+use crate::types::task::{
+    CleanupRequest, CloseIoRequest, ConnectRequest, ConnectResponse, CreateTaskRequest,
+    CreateTaskResponse, DeleteRequest, DeleteResponse, ExecProcessRequest, KillRequest,
+    PidsRequest, PidsResponse, ProcessInfo, ResizePtyRequest, StartRequest, StartResponse,
+    StateRequest, StateResponse, StatsRequest, StatsResponse, Status as TaskStatus, Task,
+    VersionResponse, WaitRequest, WaitResponse,
+};
+use crate::types::{Result, Status};
+
+/// The exit status reported for the re-adopted processes of a restored container,
+/// whose actual exit status can't be collected, see `TaskService::restore`.
+pub const UNKNOWN_EXIT_STATUS: u32 = 255;
+
+/// Backend that performs the actual container operations on behalf of a `TaskService`.
+///
+/// Processes are identified by the container id and the exec id, where the init process
+/// of a container has an empty exec id.
+/// The `TaskService` takes care of validating the requests before calling the backend,
+/// e.g., `start` is only called on processes that have been created and not started yet.
+pub trait ContainerBackend: Send + Sync + 'static {
+    /// Creates a new container, and returns the pid of its init process.
+    /// The pid can be 0 if the init process doesn't exist until the container is started.
+    fn create(&self, req: &CreateTaskRequest) -> impl Future<Output = Result<u32>> + Send;
+
+    /// Starts a process created with `create` or `exec`, and returns its pid.
+    fn start(&self, id: &str, exec_id: &str) -> impl Future<Output = Result<u32>> + Send;
+
+    /// Delivers a signal to a process, or to all the processes in the container if `all` is set.
+    fn kill(
+        &self,
+        id: &str,
+        exec_id: &str,
+        signal: u32,
+        all: bool,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Adds a new process to a running container. Not supported by default.
+    #[allow(unused_variables)]
+    fn exec(&self, req: &ExecProcessRequest) -> impl Future<Output = Result<()>> + Send {
+        async { Err(Status::unimplemented("exec is not supported")) }
+    }
+
+    /// Releases any resource associated with a stopped or never started process.
+    #[allow(unused_variables)]
+    fn delete(&self, id: &str, exec_id: &str) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Resizes the terminal of a process. Not supported by default.
+    #[allow(unused_variables)]
+    fn resize_pty(
+        &self,
+        id: &str,
+        exec_id: &str,
+        width: u32,
+        height: u32,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Err(Status::unimplemented("terminals are not supported")) }
+    }
+
+    /// Closes the stdin of a process. Not supported by default.
+    #[allow(unused_variables)]
+    fn close_stdin(&self, id: &str, exec_id: &str) -> impl Future<Output = Result<()>> + Send {
+        async { Err(Status::unimplemented("closing stdin is not supported")) }
+    }
+
+    /// Collects the resource usage of a container, e.g., with `cgroups::Cgroup::stats`.
+    /// Not supported by default.
+    #[allow(unused_variables)]
+    fn stats(&self, id: &str) -> impl Future<Output = Result<Any>> + Send {
+        async { Err(Status::unimplemented("stats are not supported")) }
+    }
+
+    /// Waits for a process to exit.
+    /// By default, this waits for the exit to be reaped by the process `Monitor`.
+    fn wait(&self, pid: u32) -> impl Future<Output = Exit> + Send {
+        async move {
+            let exit = monitor().wait(pid).await;
+            monitor().forget(pid);
+            exit
+        }
+    }
+
+    /// Cleans up after a container whose shim went away, for the `delete` action.
+    /// By default, this uses the state recorded in the bundle, see `cleanup::cleanup`.
+    fn cleanup(&self, bundle: &str) -> impl Future<Output = Result<DeleteResponse>> + Send {
+        async move { Ok(cleanup::cleanup(bundle).await?) }
+    }
+
+    /// Takes over a container recorded by a previous instance of the shim, for
+    /// `TaskService::restore`. The processes in `state` that are still running
+    /// are not children of the shim anymore, and can't be waited on.
+    /// Not supported by default.
+    #[allow(unused_variables)]
+    fn restore(&self, state: &ContainerState) -> impl Future<Output = Result<()>> + Send {
+        async {
+            Err(Status::unimplemented(
+                "restoring containers is not supported",
+            ))
+        }
+    }
+
+    /// Returns the version information used by the `-v` flag.
+    fn version(&self) -> impl Future<Output = Result<VersionResponse>> + Send {
+        async {
+            let executable = current_exe()?
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            Ok(VersionResponse {
+                executable,
+                info: vec![],
+            })
+        }
+    }
+}
+
+struct Process {
+    pid: u32,
+    // tells the process apart from a later one with the same pid, see `state::start_time`
+    start_time: Option<u64>,
+    status: TaskStatus,
+    io: TaskIo,
+    exit: watch::Sender<Option<Exit>>,
+    // serializes a process start with the handling of its exit, so that
+    // the start event is always published before the exit event
+    start: Arc<AsyncMutex<()>>,
+    // whether an exit waiter has been spawned for this process
+    waiting: bool,
+}
+
+impl Process {
+    fn new(pid: u32, io: TaskIo) -> Self {
+        Self {
+            pid,
+            start_time: None,
+            status: TaskStatus::Created,
+            io,
+            exit: watch::Sender::new(None),
+            start: Default::default(),
+            waiting: false,
+        }
+    }
+
+    fn restored(state: &ProcessState) -> Self {
+        let io = TaskIo {
+            stdin: state.stdin.clone(),
+            stdout: state.stdout.clone(),
+            stderr: state.stderr.clone(),
+            terminal: state.terminal,
+        };
+        let mut process = Self::new(state.pid, io);
+        process.start_time = state.start_time;
+        process.status = state.status;
+        process.exit.send_replace(state.exit);
+        // the exit of a started process is handled once it has been re-adopted
+        process.waiting = state.pid != 0;
+        process
+    }
+
+    fn set_pid(&mut self, pid: u32) {
+        self.pid = pid;
+        self.start_time = state::start_time(pid);
+    }
+
+    fn exit(&self) -> (u32, Option<Timestamp>) {
+        match *self.exit.borrow() {
+            Some(exit) => (exit.status, Some(exit.timestamp.into())),
+            None => (0, None),
+        }
+    }
+
+    fn state(&self) -> ProcessState {
+        ProcessState {
+            pid: self.pid,
+            start_time: self.start_time,
+            status: self.status,
+            exit: *self.exit.borrow(),
+            stdin: self.io.stdin.clone(),
+            stdout: self.io.stdout.clone(),
+            stderr: self.io.stderr.clone(),
+            terminal: self.io.terminal,
+        }
+    }
+}
+
+struct Container {
+    bundle: String,
+    options: Option<Any>,
+    init: Process,
+    execs: HashMap<String, Process>,
+    // serializes the saves of the container state, so that they are written
+    // in the order their snapshots were taken, see `TaskService::persist`
+    persisting: Arc<AsyncMutex<()>>,
+}
+
+impl Container {
+    fn state(&self, id: &str) -> ContainerState {
+        ContainerState {
+            id: id.into(),
+            bundle: self.bundle.clone(),
+            options: self.options.clone(),
+            init: self.init.state(),
+            execs: self
+                .execs
+                .iter()
+                .map(|(exec_id, process)| (exec_id.clone(), process.state()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Containers(HashMap<String, Container>);
+
+impl Containers {
+    fn container(&mut self, id: &str) -> Result<&mut Container> {
+        self.0
+            .get_mut(id)
+            .ok_or_else(|| Status::not_found(format!("container {id:?} not found")))
+    }
+
+    fn process(&mut self, id: &str, exec_id: &str) -> Result<&mut Process> {
+        let container = self.container(id)?;
+        if exec_id.is_empty() {
+            return Ok(&mut container.init);
+        }
+        container
+            .execs
+            .get_mut(exec_id)
+            .ok_or_else(|| Status::not_found(format!("process {exec_id:?} not found in {id:?}")))
+    }
+}
+
+struct Inner<B> {
+    backend: B,
+    publisher: EventPublisher,
+    containers: Mutex<Containers>,
+}
+
+/// A `Task` implementation that keeps track of containers and their processes,
+/// validates the lifecycle transitions, and publishes the task events.
+/// The actual process management is delegated to a `ContainerBackend`.
+pub struct TaskService<B> {
+    inner: Arc<Inner<B>>,
+}
+
+impl<B> Clone for TaskService<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: ContainerBackend> TaskService<B> {
+    pub fn new(backend: B, publisher: EventPublisher) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                backend,
+                publisher,
+                containers: Default::default(),
+            }),
+        }
+    }
+
+    /// Returns the backend used by this service.
+    pub fn backend(&self) -> &B {
+        &self.inner.backend
+    }
+
+    fn containers(&self) -> MutexGuard<'_, Containers> {
+        self.inner
+            .containers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    async fn publish(&self, event: impl Event) {
+        if let Err(err) = self.inner.publisher.publish(event).await {
+            log::warn!("failed to publish event: {err}");
+        }
+    }
+
+    // records the state of a container in its bundle, see `state::StateStore`
+    async fn persist(&self, id: &str) {
+        let Ok(persisting) = self
+            .containers()
+            .container(id)
+            .map(|c| c.persisting.clone())
+        else {
+            return;
+        };
+        let _persisting = persisting.lock().await;
+
+        let Ok((bundle, state)) = self
+            .containers()
+            .container(id)
+            .map(|c| (PathBuf::from(&c.bundle), c.state(id)))
+        else {
+            return;
+        };
+        let dir = bundle.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            if !dir.is_dir() {
+                return Ok(());
+            }
+            StateStore::new(dir).save(&state)
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+        if let Err(err) = res {
+            log::warn!("failed to record the state of {id:?} in {bundle:?}: {err}");
+        }
+    }
+
+    // updates the runtime state of a container in its bundle, for the `delete` action
+    // and for a restarted shim
+    fn record(&self, id: &str, record: impl FnOnce(&Path) -> std::io::Result<()>) {
+        let Ok(bundle) = self.containers().container(id).map(|c| c.bundle.clone()) else {
+            return;
+        };
+        let bundle = Path::new(&bundle);
+        if !bundle.is_dir() {
+            return;
+        }
+        if let Err(err) = record(bundle) {
+            log::warn!("failed to record the state of {id:?} in {bundle:?}: {err}");
+        }
+    }
+
+    fn spawn_waiter(&self, id: &str, exec_id: &str, pid: u32) {
+        let this = self.clone();
+        let id = id.to_string();
+        let exec_id = exec_id.to_string();
+        tokio::spawn(async move {
+            let exit = this.inner.backend.wait(pid).await;
+            this.handle_exit(&id, &exec_id, exit).await;
+        });
+    }
+
+    // waits for a process re-adopted by `restore`, which is not a child of the shim
+    fn spawn_adopted_waiter(&self, id: &str, exec_id: &str, pid: u32, start_time: Option<u64>) {
+        let this = self.clone();
+        let id = id.to_string();
+        let exec_id = exec_id.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = wait_pidfd(pid).await {
+                log::warn!("failed to watch process {pid}, polling it instead: {err}");
+                while state::start_time(pid).is_some_and(|t| Some(t) == start_time) {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+            let exit = Exit {
+                pid,
+                status: UNKNOWN_EXIT_STATUS,
+                timestamp: SystemTime::now(),
+            };
+            this.handle_exit(&id, &exec_id, exit).await;
+        });
+    }
+
+    /// Takes over the container recorded in `bundle` by a previous instance of the shim,
+    /// e.g., after it crashed, and returns its id, or `None` if nothing is recorded there.
+    ///
+    /// The backend takes over the container first, see `ContainerBackend::restore`.
+    /// The processes that are still running are re-adopted, but as they are not children
+    /// of the shim anymore, their exit is reported with `UNKNOWN_EXIT_STATUS`.
+    /// The exit of the processes that went away in the meantime is published right away.
+    ///
+    /// The daemon doesn't call it on its own, as only the shim knows the bundles of
+    /// its containers: a shim that takes over its containers calls it for each of them
+    /// before serving, e.g., with the bundle in the working directory of the daemon.
+    pub async fn restore(&self, bundle: impl AsRef<Path>) -> Result<Option<String>> {
+        let Some(state) = StateStore::new(bundle).load()? else {
+            return Ok(None);
+        };
+        let id = state.id.clone();
+        if self.containers().0.contains_key(&id) {
+            return Err(Status::already_exists(format!(
+                "container {id:?} already exists"
+            )));
+        }
+
+        self.inner.backend.restore(&state).await?;
+
+        let container = Container {
+            bundle: state.bundle.clone(),
+            options: state.options.clone(),
+            init: Process::restored(&state.init),
+            execs: state
+                .execs
+                .iter()
+                .map(|(exec_id, process)| (exec_id.clone(), Process::restored(process)))
+                .collect(),
+            persisting: Default::default(),
+        };
+
+        // the processes that were started and haven't been reaped yet
+        let (adopted, lost): (Vec<_>, Vec<_>) = std::iter::once(("", &state.init))
+            .chain(state.execs.iter().map(|(id, p)| (id.as_str(), p)))
+            .filter(|(_, process)| process.pid != 0 && process.exit.is_none())
+            .partition(|(_, process)| process.is_running());
+
+        {
+            let mut containers = self.containers();
+            if containers.0.contains_key(&id) {
+                return Err(Status::already_exists(format!(
+                    "container {id:?} already exists"
+                )));
+            }
+            containers.0.insert(id.clone(), container);
+        }
+
+        for (exec_id, process) in adopted {
+            self.spawn_adopted_waiter(&id, exec_id, process.pid, process.start_time);
+        }
+        for (exec_id, process) in lost {
+            let exit = Exit {
+                pid: process.pid,
+                status: UNKNOWN_EXIT_STATUS,
+                timestamp: SystemTime::now(),
+            };
+            self.handle_exit(&id, exec_id, exit).await;
+        }
+
+        Ok(Some(id))
+    }
+
+    async fn handle_exit(&self, id: &str, exec_id: &str, exit: Exit) {
+        let Ok(start) = self
+            .containers()
+            .process(id, exec_id)
+            .map(|p| p.start.clone())
+        else {
+            // the process was deleted before it exited
+            return;
+        };
+        let _start = start.lock().await;
+
+        {
+            let mut containers = self.containers();
+            let Ok(process) = containers.process(id, exec_id) else {
+                return;
+            };
+            process.status = TaskStatus::Stopped;
+            process.exit.send_replace(Some(exit));
+        }
+
+        // deleting the container waits for the exit to be handled,
+        // so the exit is always recorded before the state is removed
+        self.persist(id).await;
+
+        self.publish(TaskExit {
+            container_id: id.into(),
+            id: if exec_id.is_empty() { id } else { exec_id }.into(),
+            pid: exit.pid,
+            exit_status: exit.status,
+            exited_at: Some(exit.timestamp.into()),
+        })
+        .await;
+    }
+}
+
+impl<B: ContainerBackend> Task for TaskService<B> {
+    async fn create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+        let id = req.id.clone();
+        if id.is_empty() {
+            return Err(Status::invalid_argument("container id must not be empty"));
+        }
+
+        let io = TaskIo {
+            stdin: req.stdin.clone(),
+            stdout: req.stdout.clone(),
+            stderr: req.stderr.clone(),
+            terminal: req.terminal,
+        };
+
+        // reserve the id while the backend creates the container
+        {
+            let mut containers = self.containers();
+            if containers.0.contains_key(&id) {
+                return Err(Status::already_exists(format!(
+                    "container {id:?} already exists"
+                )));
+            }
+            let mut init = Process::new(0, io.clone());
+            init.status = TaskStatus::Unknown;
+            let container = Container {
+                bundle: req.bundle.clone(),
+                options: req.options.clone(),
+                init,
+                execs: Default::default(),
+                persisting: Default::default(),
+            };
+            containers.0.insert(id.clone(), container);
+        }
+
+        let pid = match self.inner.backend.create(&req).await {
+            Ok(pid) => pid,
+            Err(err) => {
+                self.containers().0.remove(&id);
+                return Err(err);
+            }
+        };
+
+        {
+            let mut containers = self.containers();
+            let init = &mut containers.container(&id)?.init;
+            init.set_pid(pid);
+            init.status = TaskStatus::Created;
+            init.waiting = pid != 0;
+        }
+
+        self.persist(&id).await;
+        if pid != 0 {
+            // the init process could be killed before being started
+            self.spawn_waiter(&id, "", pid);
+        }
+
+        self.publish(TaskCreate {
+            container_id: id,
+            bundle: req.bundle,
+            rootfs: req.rootfs,
+            io: Some(io),
+            checkpoint: req.checkpoint,
+            pid,
+        })
+        .await;
+
+        Ok(CreateTaskResponse { pid })
+    }
+
+    async fn start(&self, req: StartRequest) -> Result<StartResponse> {
+        let StartRequest { id, exec_id } = req;
+
+        let start = {
+            let mut containers = self.containers();
+            let process = containers.process(&id, &exec_id)?;
+            if process.status != TaskStatus::Created {
+                return Err(Status::failed_precondition(format!(
+                    "cannot start a process in {} state",
+                    process.status.as_str_name()
+                )));
+            }
+            process.start.clone()
+        };
+
+        let start = start.lock_owned().await;
+
+        let pid = self.inner.backend.start(&id, &exec_id).await?;
+
+        let spawn_waiter = {
+            let mut containers = self.containers();
+            let process = containers.process(&id, &exec_id)?;
+            process.set_pid(pid);
+            if process.status == TaskStatus::Created {
+                process.status = TaskStatus::Running;
+            }
+            let spawn_waiter = !process.waiting;
+            process.waiting = true;
+            spawn_waiter
+        };
+
+        self.persist(&id).await;
+        if exec_id.is_empty() {
+            self.publish(TaskStart {
+                container_id: id.clone(),
+                pid,
+            })
+            .await;
+        } else {
+            self.publish(TaskExecStarted {
+                container_id: id.clone(),
+                exec_id: exec_id.clone(),
+                pid,
+            })
+            .await;
+        }
+
+        drop(start);
+
+        if spawn_waiter {
+            self.spawn_waiter(&id, &exec_id, pid);
+        }
+
+        Ok(StartResponse { pid })
+    }
+
+    async fn state(&self, req: StateRequest) -> Result<StateResponse> {
+        let StateRequest { id, exec_id } = req;
+        let mut containers = self.containers();
+        let bundle = containers.container(&id)?.bundle.clone();
+        let process = containers.process(&id, &exec_id)?;
+        let (exit_status, exited_at) = process.exit();
+        Ok(StateResponse {
+            id,
+            bundle,
+            pid: process.pid,
+            status: process.status.into(),
+            stdin: process.io.stdin.clone(),
+            stdout: process.io.stdout.clone(),
+            stderr: process.io.stderr.clone(),
+            terminal: process.io.terminal,
+            exit_status,
+            exited_at,
+            exec_id,
+        })
+    }
+
+    async fn wait(&self, req: WaitRequest) -> Result<WaitResponse> {
+        let WaitRequest { id, exec_id } = req;
+        let mut exit = self.containers().process(&id, &exec_id)?.exit.subscribe();
+        let exit = exit
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Status::not_found(format!("process {id:?} was deleted")))?
+            .expect("wait_for returned None");
+        Ok(WaitResponse {
+            exit_status: exit.status,
+            exited_at: Some(exit.timestamp.into()),
+        })
+    }
+
+    async fn kill(&self, req: KillRequest) -> Result<()> {
+        let KillRequest {
+            id,
+            exec_id,
+            signal,
+            all,
+        } = req;
+        {
+            let mut containers = self.containers();
+            let process = containers.process(&id, &exec_id)?;
+            if process.status == TaskStatus::Stopped {
+                return Err(Status::not_found("process already finished"));
+            }
+        }
+        self.inner.backend.kill(&id, &exec_id, signal, all).await
+    }
+
+    async fn exec(&self, req: ExecProcessRequest) -> Result<()> {
+        let id = req.id.clone();
+        let exec_id = req.exec_id.clone();
+        if exec_id.is_empty() {
+            return Err(Status::invalid_argument("exec id must not be empty"));
+        }
+
+        let io = TaskIo {
+            stdin: req.stdin.clone(),
+            stdout: req.stdout.clone(),
+            stderr: req.stderr.clone(),
+            terminal: req.terminal,
+        };
+
+        // reserve the exec id while the backend creates the process
+        {
+            let mut containers = self.containers();
+            let container = containers.container(&id)?;
+            if container.init.status != TaskStatus::Running {
+                return Err(Status::failed_precondition("container must be running"));
+            }
+            if container.execs.contains_key(&exec_id) {
+                return Err(Status::already_exists(format!(
+                    "process {exec_id:?} already exists in {id:?}"
+                )));
+            }
+            let mut process = Process::new(0, io);
+            process.status = TaskStatus::Unknown;
+            container.execs.insert(exec_id.clone(), process);
+        }
+
+        if let Err(err) = self.inner.backend.exec(&req).await {
+            if let Ok(container) = self.containers().container(&id) {
+                container.execs.remove(&exec_id);
+            }
+            return Err(err);
+        }
+
+        self.containers().process(&id, &exec_id)?.status = TaskStatus::Created;
+        self.persist(&id).await;
+
+        self.publish(TaskExecAdded {
+            container_id: id,
+            exec_id,
+        })
+        .await;
+
+        Ok(())
+    }
+
+    async fn resize_pty(&self, req: ResizePtyRequest) -> Result<()> {
+        let ResizePtyRequest {
+            id,
+            exec_id,
+            width,
+            height,
+        } = req;
+        {
+            let mut containers = self.containers();
+            let process = containers.process(&id, &exec_id)?;
+            if !process.io.terminal {
+                return Err(Status::failed_precondition("process has no terminal"));
+            }
+        }
+        self.inner
+            .backend
+            .resize_pty(&id, &exec_id, width, height)
+            .await
+    }
+
+    async fn close_io(&self, req: CloseIoRequest) -> Result<()> {
+        let CloseIoRequest { id, exec_id, stdin } = req;
+        self.containers().process(&id, &exec_id)?;
+        if !stdin {
+            return Ok(());
+        }
+        self.inner.backend.close_stdin(&id, &exec_id).await
+    }
+
+    async fn delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
+        let DeleteRequest { id, exec_id } = req;
+
+        // wait for any pending exit event to be published before deleting
+        let start = self.containers().process(&id, &exec_id)?.start.clone();
+        let _start = start.lock().await;
+
+        {
+            let mut containers = self.containers();
+            let process = containers.process(&id, &exec_id)?;
+            match process.status {
+                TaskStatus::Created | TaskStatus::Stopped => {}
+                status => {
+                    return Err(Status::failed_precondition(format!(
+                        "cannot delete a process in {} state",
+                        status.as_str_name()
+                    )));
+                }
+            }
+            // the rootfs and state of the container are still in use by its execs
+            let container = containers.container(&id)?;
+            let running =
+                |exec: &Process| !matches!(exec.status, TaskStatus::Created | TaskStatus::Stopped);
+            if exec_id.is_empty() && container.execs.values().any(running) {
+                return Err(Status::failed_precondition(
+                    "cannot delete a container with running execs",
+                ));
+            }
+        }
+
+        self.inner.backend.delete(&id, &exec_id).await?;
+
+        // don't let a pending save record the state again once it's removed
+        let persisting = self.containers().container(&id)?.persisting.clone();
+        let _persisting = persisting.lock().await;
+
+        if exec_id.is_empty() {
+            // undo the mounts of `mount::mount_rootfs`, if the backend used it
+            self.record(&id, |bundle| {
+                mount::unmount_rootfs(bundle)?;
+                cleanup::remove_state(bundle)
+            });
+        }
+
+        let (pid, exit_status, exited_at) = {
+            let mut containers = self.containers();
+            let process = if exec_id.is_empty() {
+                containers.0.remove(&id).map(|c| c.init)
+            } else {
+                containers.container(&id)?.execs.remove(&exec_id)
+            };
+            let process = process.ok_or_else(|| Status::not_found("process already deleted"))?;
+            let (exit_status, exited_at) = process.exit();
+            (process.pid, exit_status, exited_at)
+        };
+
+        if !exec_id.is_empty() {
+            drop(_persisting);
+            self.persist(&id).await;
+        }
+
+        if exec_id.is_empty() {
+            self.publish(TaskDelete {
+                container_id: id.clone(),
+                pid,
+                exit_status,
+                exited_at,
+                id,
+            })
+            .await;
+        }
+
+        Ok(DeleteResponse {
+            pid,
+            exit_status,
+            exited_at,
+        })
+    }
+
+    async fn pids(&self, req: PidsRequest) -> Result<PidsResponse> {
+        let mut containers = self.containers();
+        let container = containers.container(&req.id)?;
+        let processes = std::iter::once(&container.init)
+            .chain(container.execs.values())
+            .filter(|p| p.pid != 0 && p.status != TaskStatus::Stopped)
+            .map(|p| ProcessInfo {
+                pid: p.pid,
+                info: None,
+            })
+            .collect();
+        Ok(PidsResponse { processes })
+    }
+
+    async fn stats(&self, req: StatsRequest) -> Result<StatsResponse> {
+        self.containers().container(&req.id)?;
+        let stats = self.inner.backend.stats(&req.id).await?;
+        Ok(StatsResponse { stats: Some(stats) })
+    }
+
+    async fn connect(&self, req: ConnectRequest) -> Result<ConnectResponse> {
+        let task_pid = self.containers().container(&req.id)?.init.pid;
+        Ok(ConnectResponse {
+            shim_pid: std::process::id(),
+            task_pid,
+            version: "".into(),
+        })
+    }
+
+    async fn cleanup(&self, req: CleanupRequest) -> Result<DeleteResponse> {
+        self.inner.backend.cleanup(&req.bundle).await
+    }
+
+    async fn version(&self, _: ()) -> Result<VersionResponse> {
+        self.inner.backend.version().await
+    }
+}
+
+impl<B: ContainerBackend> Sandbox for TaskService<B> {}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+    use crate::types::events::{Envelope, Events, ForwardRequest};
+    use crate::types::Code;
+
+    struct FakeEvents {
+        tx: UnboundedSender<Envelope>,
+    }
+
+    impl Events for FakeEvents {
+        async fn forward(&self, req: ForwardRequest) -> trapeze::Result<()> {
+            let _ = self.tx.send(req.envelope.unwrap_or_default());
+            Ok(())
+        }
+    }
+
+    // runs every process as `sh -c <script>`, where the script is the container's bundle
+    #[derive(Default)]
+    struct ShBackend {
+        scripts: Mutex<HashMap<(String, String), String>>,
+        pids: Mutex<HashMap<(String, String), u32>>,
+    }
+
+    impl ContainerBackend for ShBackend {
+        async fn create(&self, req: &CreateTaskRequest) -> Result<u32> {
+            let key = (req.id.clone(), String::new());
+            self.scripts.lock().unwrap().insert(key, req.bundle.clone());
+            Ok(0)
+        }
+
+        async fn exec(&self, req: &ExecProcessRequest) -> Result<()> {
+            let key = (req.id.clone(), req.exec_id.clone());
+            self.scripts.lock().unwrap().insert(key, "sleep 10".into());
+            Ok(())
+        }
+
+        async fn start(&self, id: &str, exec_id: &str) -> Result<u32> {
+            let key = (id.to_string(), exec_id.to_string());
+            let script = self.scripts.lock().unwrap()[&key].clone();
+            let child = monitor().spawn(Command::new("sh").arg("-c").arg(script))?;
+            self.pids.lock().unwrap().insert(key, child.id());
+            Ok(child.id())
+        }
+
+        async fn kill(&self, id: &str, exec_id: &str, signal: u32, _all: bool) -> Result<()> {
+            let key = (id.to_string(), exec_id.to_string());
+            let pid = self.pids.lock().unwrap()[&key];
+            unsafe { libc::kill(pid as _, signal as _) };
+            Ok(())
+        }
+
+        async fn restore(&self, state: &ContainerState) -> Result<()> {
+            let mut pids = self.pids.lock().unwrap();
+            pids.insert((state.id.clone(), String::new()), state.init.pid);
+            for (exec_id, process) in &state.execs {
+                pids.insert((state.id.clone(), exec_id.clone()), process.pid);
+            }
+            Ok(())
+        }
+    }
+
+    fn service() -> (TaskService<ShBackend>, UnboundedReceiver<Envelope>) {
+        let (tx, rx) = unbounded_channel();
+        let publisher = EventPublisher::new(FakeEvents { tx });
+        (TaskService::new(ShBackend::default(), publisher), rx)
+    }
+
+    fn create_request(id: &str, script: &str) -> CreateTaskRequest {
+        CreateTaskRequest {
+            id: id.into(),
+            bundle: script.into(),
+            ..Default::default()
+        }
+    }
+
+    macro_rules! request {
+        ($Request:ident, $id:expr, $exec_id:expr) => {
+            $Request {
+                id: $id.into(),
+                exec_id: $exec_id.into(),
+            }
+        };
+    }
+
+    async fn status(service: &TaskService<ShBackend>, id: &str, exec_id: &str) -> TaskStatus {
+        let state = service
+            .state(request!(StateRequest, id, exec_id))
+            .await
+            .unwrap();
+        state.status()
+    }
+
+    #[tokio::test]
+    async fn records_bundle_state() {
+        let (service, _events) = service();
+
+        // the script is the bundle directory, which `sh` fails to run
+        let bundle = tempfile::tempdir().unwrap();
+        let script = bundle.path().to_str().unwrap();
+        service.create(create_request("c1", script)).await.unwrap();
+
+        let store = StateStore::new(bundle.path());
+        let recorded = store.load().unwrap().unwrap();
+        assert_eq!(recorded.id, "c1");
+        assert_eq!(recorded.init.status, TaskStatus::Created);
+
+        let StartResponse { pid } = service
+            .start(request!(StartRequest, "c1", ""))
+            .await
+            .unwrap();
+        let WaitResponse { exit_status, .. } =
+            service.wait(request!(WaitRequest, "c1", "")).await.unwrap();
+        // the exit is recorded after it's reported, but the saves are in order
+        service.persist("c1").await;
+        let recorded = store.load().unwrap().unwrap().init;
+        assert_eq!(recorded.pid, pid);
+        assert_eq!(recorded.status, TaskStatus::Stopped);
+        assert_eq!(recorded.exit.unwrap().status, exit_status);
+
+        service
+            .delete(request!(DeleteRequest, "c1", ""))
+            .await
+            .unwrap();
+        assert_eq!(store.load().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn restore_container() {
+        let (service, mut events) = service();
+        let bundle = tempfile::tempdir().unwrap();
+        assert_eq!(service.restore(bundle.path()).await.unwrap(), None);
+
+        // a container left behind by a previous shim, with a running init process
+        // and an exec process that went away while the shim was not running
+        let child = monitor().spawn(Command::new("sleep").arg("10")).unwrap();
+        let pid = child.id();
+        let running = ProcessState {
+            pid,
+            start_time: state::start_time(pid),
+            status: TaskStatus::Running,
+            ..Default::default()
+        };
+        let gone = ProcessState {
+            pid: u32::MAX,
+            status: TaskStatus::Running,
+            ..Default::default()
+        };
+        let store = StateStore::new(bundle.path());
+        let state = ContainerState {
+            id: "c1".into(),
+            bundle: bundle.path().to_str().unwrap().into(),
+            init: running,
+            execs: [("e1".to_string(), gone)].into(),
+            ..Default::default()
+        };
+        store.save(&state).unwrap();
+
+        let id = service.restore(bundle.path()).await.unwrap();
+        assert_eq!(id.as_deref(), Some("c1"));
+        let err = service.restore(bundle.path()).await.unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+
+        assert_eq!(status(&service, "c1", "").await, TaskStatus::Running);
+        let WaitResponse { exit_status, .. } = service
+            .wait(request!(WaitRequest, "c1", "e1"))
+            .await
+            .unwrap();
+        assert_eq!(exit_status, UNKNOWN_EXIT_STATUS);
+
+        service
+            .kill(KillRequest {
+                id: "c1".into(),
+                signal: libc::SIGKILL as u32,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let WaitResponse { exit_status, .. } =
+            service.wait(request!(WaitRequest, "c1", "")).await.unwrap();
+        assert_eq!(exit_status, UNKNOWN_EXIT_STATUS);
+        monitor().wait(pid).await;
+        monitor().forget(pid);
+
+        service.persist("c1").await;
+        let recorded = store.load().unwrap().unwrap();
+        assert_eq!(recorded.init.exit.unwrap().status, UNKNOWN_EXIT_STATUS);
+        assert_eq!(recorded.execs["e1"].status, TaskStatus::Stopped);
+
+        service
+            .delete(request!(DeleteRequest, "c1", ""))
+            .await
+            .unwrap();
+        assert_eq!(store.load().unwrap(), None);
+
+        service.inner.publisher.flush().await;
+        let topics: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|env| env.topic)
+            .collect();
+        assert_eq!(topics, ["/tasks/exit", "/tasks/exit", "/tasks/delete"]);
+    }
+
+    #[tokio::test]
+    async fn lifecycle() {
+        let (service, mut events) = service();
+
+        service
+            .create(create_request("c1", "exit 3"))
+            .await
+            .unwrap();
+        assert_eq!(status(&service, "c1", "").await, TaskStatus::Created);
+
+        let StartResponse { pid } = service
+            .start(request!(StartRequest, "c1", ""))
+            .await
+            .unwrap();
+        assert_ne!(pid, 0);
+
+        let WaitResponse { exit_status, .. } =
+            service.wait(request!(WaitRequest, "c1", "")).await.unwrap();
+        assert_eq!(exit_status, 3);
+        assert_eq!(status(&service, "c1", "").await, TaskStatus::Stopped);
+
+        let res = service
+            .delete(request!(DeleteRequest, "c1", ""))
+            .await
+            .unwrap();
+        assert_eq!(res.pid, pid);
+        assert_eq!(res.exit_status, 3);
+
+        let err = service
+            .state(request!(StateRequest, "c1", ""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        service.inner.publisher.flush().await;
+        let topics: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|env| env.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "/tasks/create",
+                "/tasks/start",
+                "/tasks/exit",
+                "/tasks/delete"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_transitions() {
+        let (service, _events) = service();
+
+        let err = service
+            .start(request!(StartRequest, "c1", ""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        service
+            .create(create_request("c1", "sleep 10"))
+            .await
+            .unwrap();
+
+        let err = service
+            .create(create_request("c1", "sleep 10"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+
+        service
+            .start(request!(StartRequest, "c1", ""))
+            .await
+            .unwrap();
+
+        let err = service
+            .start(request!(StartRequest, "c1", ""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        let err = service
+            .delete(request!(DeleteRequest, "c1", ""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        let err = service
+            .resize_pty(ResizePtyRequest {
+                id: "c1".into(),
+                width: 80,
+                height: 24,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        service
+            .kill(KillRequest {
+                id: "c1".into(),
+                signal: libc::SIGKILL as u32,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let WaitResponse { exit_status, .. } =
+            service.wait(request!(WaitRequest, "c1", "")).await.unwrap();
+        assert_eq!(exit_status, 128 + libc::SIGKILL as u32);
+
+        let err = service
+            .kill(KillRequest {
+                id: "c1".into(),
+                signal: libc::SIGKILL as u32,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        service
+            .delete(request!(DeleteRequest, "c1", ""))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn exec_process() {
+        let (service, mut events) = service();
+
+        let exec = ExecProcessRequest {
+            id: "c1".into(),
+            exec_id: "e1".into(),
+            ..Default::default()
+        };
+
+        service
+            .create(create_request("c1", "sleep 10"))
+            .await
+            .unwrap();
+
+        let err = service.exec(exec.clone()).await.unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        service
+            .start(request!(StartRequest, "c1", ""))
+            .await
+            .unwrap();
+        service.exec(exec.clone()).await.unwrap();
+        assert_eq!(status(&service, "c1", "e1").await, TaskStatus::Created);
+
+        let err = service.exec(exec).await.unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+
+        service
+            .start(request!(StartRequest, "c1", "e1"))
+            .await
+            .unwrap();
+        assert_eq!(status(&service, "c1", "e1").await, TaskStatus::Running);
+
+        let pids = service.pids(PidsRequest { id: "c1".into() }).await.unwrap();
+        assert_eq!(pids.processes.len(), 2);
+
+        for exec_id in ["", "e1"] {
+            service
+                .kill(KillRequest {
+                    id: "c1".into(),
+                    exec_id: exec_id.into(),
+                    signal: libc::SIGKILL as u32,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            service
+                .wait(request!(WaitRequest, "c1", exec_id))
+                .await
+                .unwrap();
+            if exec_id.is_empty() {
+                let err = service
+                    .delete(request!(DeleteRequest, "c1", ""))
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.code(), Code::FailedPrecondition);
+                assert_eq!(status(&service, "c1", "e1").await, TaskStatus::Running);
+            }
+        }
+        for exec_id in ["e1", ""] {
+            service
+                .delete(request!(DeleteRequest, "c1", exec_id))
+                .await
+                .unwrap();
+        }
+
+        service.inner.publisher.flush().await;
+        let topics: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|env| env.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "/tasks/create",
+                "/tasks/start",
+                "/tasks/exec-added",
+                "/tasks/exec-started",
+                "/tasks/exit",
+                "/tasks/exit",
+                "/tasks/delete",
+            ]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::{Read as _, Seek as _, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use prost::Message as _;
+use tempfile::TempDir;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use trapeze::{service, Client, Server, ServerHandle};
+
+use crate::args::Arguments;
+use crate::daemon::DaemonHandle;
+use crate::event::EventPublisher;
+use crate::types::events::{Envelope, Events, ForwardRequest};
+use crate::types::sandbox::Sandbox;
+use crate::types::task::{DeleteResponse, Task};
+
+struct EventsRecorder {
+    tx: mpsc::UnboundedSender<Envelope>,
+}
+
+impl Events for EventsRecorder {
+    async fn forward(&self, req: ForwardRequest) -> trapeze::Result<()> {
+        let _ = self.tx.send(req.envelope.unwrap_or_default());
+        Ok(())
+    }
+}
+
+/// An in-process stand-in for containerd, to test shims end-to-end without root.
+///
+/// It serves containerd's TTRPC `Events` API in a temporary directory, capturing every
+/// event published by the shim, and drives the shim actions the way containerd does:
+/// it passes the same flags and environment, and reads back what the shim prints.
+pub struct FakeContainerd {
+    dir: TempDir,
+    namespace: String,
+    events: AsyncMutex<mpsc::UnboundedReceiver<Envelope>>,
+    server: ServerHandle,
+}
+
+impl FakeContainerd {
+    /// Starts a fake containerd for the given namespace.
+    pub async fn new(namespace: impl Into<String>) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let address = format!(
+            "unix://{}",
+            dir.path().join("containerd.sock.ttrpc").display()
+        );
+        let recorder = EventsRecorder { tx };
+        let server = Server::new()
+            .register(service!(recorder : Events))
+            .bind(address)
+            .await
+            .context("Error binding events listener")?;
+
+        Ok(Self {
+            dir,
+            namespace: namespace.into(),
+            events: AsyncMutex::new(rx),
+            server,
+        })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// The directory where the sockets of containerd and the shims are created.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// The path of containerd's GRPC socket, passed to the shim with `-address`.
+    pub fn grpc_address(&self) -> PathBuf {
+        self.dir.path().join("containerd.sock")
+    }
+
+    /// The path of containerd's TTRPC socket, where the events are published.
+    pub fn ttrpc_address(&self) -> PathBuf {
+        self.dir.path().join("containerd.sock.ttrpc")
+    }
+
+    /// Receives the next event published by a shim.
+    pub async fn next_event(&self) -> Option<Envelope> {
+        self.events.lock().await.recv().await
+    }
+
+    /// Parses the shim arguments containerd would use for container `id`,
+    /// followed by the action specific `args`, e.g., `["start"]` or `["-bundle", path, "delete"]`.
+    pub fn arguments(&self, id: &str, args: &[&str]) -> Result<Arguments> {
+        let grpc_address = self.grpc_address().display().to_string();
+        let ttrpc_address = self.ttrpc_address().display().to_string();
+
+        let mut argv = vec![
+            "-namespace",
+            &self.namespace,
+            "-address",
+            &grpc_address,
+            "-publish-binary",
+            "containerd",
+            "-id",
+            id,
+        ];
+        argv.extend_from_slice(args);
+
+        let vars = [
+            ("NAMESPACE", self.namespace.as_str()),
+            ("GRPC_ADDRESS", &grpc_address),
+            ("TTRPC_ADDRESS", &ttrpc_address),
+        ];
+
+        Arguments::parse_from(argv, vars)
+    }
+
+    /// Runs the `start` action of the shim for container `id`.
+    ///
+    /// The launcher step is skipped, and the server returned by `server` is served in-process
+    /// as the `daemon` action would. The address printed by the shim is used to connect a client.
+    pub async fn start<S: Sandbox + Task>(
+        &self,
+        id: &str,
+        server: impl FnOnce(EventPublisher) -> S,
+    ) -> Result<Shim> {
+        let mut args = self.arguments(id, &["start"])?;
+        args.action = "daemon".into();
+
+        let publisher = args.event_publisher().await?;
+        let socket = args.socket_address(id);
+
+        let (output, handle) = capture(args, |args| args.serve(socket, server(publisher))).await?;
+        let address = String::from_utf8(output)?.trim().to_string();
+        let client = Client::connect(&address)
+            .await
+            .with_context(|| format!("Error connecting to shim at {address:?}"))?;
+
+        Ok(Shim {
+            address,
+            client,
+            server: handle,
+        })
+    }
+
+    /// Runs the `delete` action of the shim for container `id`, and decodes its output.
+    pub async fn delete(
+        &self,
+        id: &str,
+        bundle: impl AsRef<Path>,
+        server: impl Sandbox + Task,
+    ) -> Result<DeleteResponse> {
+        let bundle = bundle.as_ref().display().to_string();
+        let args = self.arguments(id, &["-bundle", &bundle, "delete"])?;
+        let socket = args.socket_address(id);
+
+        let (output, _) = capture(args, |args| args.serve(socket, server)).await?;
+        Ok(DeleteResponse::decode(&output[..])?)
+    }
+
+    /// Runs the shim with the `-v` flag, and returns its output.
+    pub async fn version(&self, server: impl Sandbox + Task) -> Result<String> {
+        let args = self.arguments("", &["-v"])?;
+        let (output, _) = capture(args, |args| args.serve("", server)).await?;
+        Ok(String::from_utf8(output)?)
+    }
+
+    /// Stops the events server.
+    pub async fn shutdown(self) -> Result<()> {
+        self.server.shutdown();
+        self.server.await?;
+        Ok(())
+    }
+}
+
+// runs `f` with the stdout of `args` redirected to a temporary file, and returns its content
+async fn capture<F>(
+    mut args: Arguments,
+    f: impl FnOnce(Arguments) -> F,
+) -> Result<(Vec<u8>, DaemonHandle)>
+where
+    F: Future<Output = Result<DaemonHandle>>,
+{
+    let mut stdout = tempfile::tempfile()?;
+    args.stdout = stdout.try_clone()?;
+    let handle = f(args).await?;
+    Ok((read_all(&mut stdout)?, handle))
+}
+
+fn read_all(file: &mut File) -> Result<Vec<u8>> {
+    let mut output = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// A shim started by `FakeContainerd::start`.
+pub struct Shim {
+    address: String,
+    client: Client,
+    server: DaemonHandle,
+}
+
+impl Shim {
+    /// The address the shim printed on stdout.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// A client connected to the shim, which implements the `Task` and `Sandbox` traits.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Disconnects the client and stops the shim server.
+    pub async fn shutdown(self) -> Result<()> {
+        drop(self.client);
+        self.server.shutdown();
+        self.server.await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::process::Command;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::process::monitor;
+    use crate::task::{ContainerBackend, TaskService};
+    use crate::types::task::{
+        CleanupRequest, CreateTaskRequest, DeleteRequest, StartRequest, WaitRequest,
+    };
+
+    // runs the init process of each container as `sh -c <bundle>`
+    #[derive(Default)]
+    struct ShBackend {
+        scripts: Mutex<HashMap<String, String>>,
+    }
+
+    impl ContainerBackend for ShBackend {
+        async fn create(&self, req: &CreateTaskRequest) -> trapeze::Result<u32> {
+            let mut scripts = self.scripts.lock().unwrap();
+            scripts.insert(req.id.clone(), req.bundle.clone());
+            Ok(0)
+        }
+
+        async fn start(&self, id: &str, _exec_id: &str) -> trapeze::Result<u32> {
+            let script = self.scripts.lock().unwrap()[id].clone();
+            let child = monitor().spawn(Command::new("sh").arg("-c").arg(script))?;
+            Ok(child.id())
+        }
+
+        async fn kill(&self, _: &str, _: &str, _: u32, _: bool) -> trapeze::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Cleanup;
+    impl Sandbox for Cleanup {}
+    impl Task for Cleanup {
+        async fn cleanup(&self, req: CleanupRequest) -> trapeze::Result<DeleteResponse> {
+            assert_eq!(req.bundle, "/path/to/bundle");
+            Ok(DeleteResponse {
+                pid: 42,
+                exit_status: 137,
+                exited_at: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn start_shim() {
+        let containerd = FakeContainerd::new("testing").await.unwrap();
+        let shim = containerd
+            .start("c1", |publisher| {
+                TaskService::new(ShBackend::default(), publisher)
+            })
+            .await
+            .unwrap();
+        assert!(shim.address().starts_with("unix://"));
+
+        let client = shim.client();
+        let req = CreateTaskRequest {
+            id: "c1".into(),
+            bundle: "exit 3".into(),
+            ..Default::default()
+        };
+        Task::create(client, req).await.unwrap();
+
+        let req = StartRequest {
+            id: "c1".into(),
+            exec_id: "".into(),
+        };
+        Task::start(client, req).await.unwrap();
+
+        let req = WaitRequest {
+            id: "c1".into(),
+            exec_id: "".into(),
+        };
+        let res = Task::wait(client, req).await.unwrap();
+        assert_eq!(res.exit_status, 3);
+
+        let req = DeleteRequest {
+            id: "c1".into(),
+            exec_id: "".into(),
+        };
+        Task::delete(client, req).await.unwrap();
+
+        for topic in [
+            "/tasks/create",
+            "/tasks/start",
+            "/tasks/exit",
+            "/tasks/delete",
+        ] {
+            let event = containerd.next_event().await.unwrap();
+            assert_eq!(event.topic, topic);
+            assert_eq!(event.namespace, "testing");
+        }
+
+        shim.shutdown().await.unwrap();
+        containerd.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_shim() {
+        let containerd = FakeContainerd::new("testing").await.unwrap();
+        let res = containerd
+            .delete("c1", "/path/to/bundle", Cleanup)
+            .await
+            .unwrap();
+        assert_eq!(res.pid, 42);
+        assert_eq!(res.exit_status, 137);
+    }
+
+    #[tokio::test]
+    async fn version_shim() {
+        let containerd = FakeContainerd::new("testing").await.unwrap();
+        let service = TaskService::new(ShBackend::default(), EventPublisher::null());
+        let output = containerd.version(service).await.unwrap();
+        assert!(output.ends_with(":\n"), "unexpected output {output:?}");
+    }
+}
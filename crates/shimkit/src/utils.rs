@@ -1,23 +1,15 @@
 use std::ffi::OsStr;
 
-use oci_spec::runtime::Spec;
-
-const GROUP_LABELS: [&str; 2] = [
-    "io.kubernetes.cri.sandbox-id",
-    "io.containerd.runc.v2.group",
-];
+use crate::bundle::Bundle;
 
+/// Returns the id of the sandbox of the container whose bundle is the working directory.
+#[deprecated(
+    since = "0.2.4",
+    note = "use `bundle::Bundle::from_args` and `Bundle::sandbox_id`"
+)]
 pub fn cri_sandbox_id() -> Option<String> {
-    if let Ok(spec) = Spec::load("config.json") {
-        if let Some(annotations) = spec.annotations() {
-            for &label in GROUP_LABELS.iter() {
-                if let Some(value) = annotations.get(label) {
-                    return Some(value.clone());
-                }
-            }
-        }
-    }
-    None
+    let bundle = Bundle::open(std::env::current_dir().ok()?).ok()?;
+    bundle.sandbox_id().map(str::to_owned)
 }
 
 pub(crate) trait ToLossyString {